@@ -1,4 +1,12 @@
 #!/bin/sh
+# Usage: make-qvm.sh <name> [-v2]
+# The mod-* fixtures are version 1 VMs. Pass -v2 for a version 2 VM with jump
+# table targets instead, as mod-control.c needs for its switch.
+
+version=-vq3
+if [ "$2" = "-v2" ]; then
+	version=
+fi
 
 q3lcc -S $1.c
-q3asm -v -m -vq3 -o $1.qvm $1.asm syscalls.asm
+q3asm -v -m $version -o $1.qvm $1.asm syscalls.asm
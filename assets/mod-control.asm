@@ -0,0 +1,137 @@
+export vmMain
+code
+proc vmMain 4 4
+ADDRFP4 0
+INDIRI4
+CNSTI4 0
+GEI4 $2
+ADDRGP4 $4
+ARGP4
+ADDRGP4 trap_Print
+CALLI4
+pop
+CNSTI4 -1
+RETI4
+ADDRGP4 $1
+JUMPV
+LABELV $2
+ADDRLP4 0
+CNSTI4 0
+ASGNI4
+ADDRGP4 $6
+JUMPV
+LABELV $5
+ADDRLP4 0
+ADDRLP4 0
+INDIRI4
+ADDRFP4 0
+INDIRI4
+ADDI4
+ASGNI4
+LABELV $6
+ADDRLP4 0
+INDIRI4
+ADDRFP4 4
+INDIRI4
+LTI4 $5
+ADDRFP4 0
+INDIRI4
+CNSTI4 0
+LTI4 $8
+ADDRFP4 0
+INDIRI4
+CNSTI4 3
+GTI4 $8
+ADDRFP4 0
+INDIRI4
+CNSTI4 2
+LSHI4
+ADDRGP4 $18
+ADDP4
+INDIRP4
+JUMPV
+lit
+align 4
+LABELV $18
+address $10
+address $12
+address $14
+address $16
+code
+LABELV $10
+ADDRGP4 $11
+ARGP4
+ADDRGP4 trap_Print
+CALLI4
+pop
+ADDRGP4 $9
+JUMPV
+LABELV $12
+ADDRGP4 $13
+ARGP4
+ADDRGP4 trap_Print
+CALLI4
+pop
+ADDRGP4 $9
+JUMPV
+LABELV $14
+ADDRGP4 $15
+ARGP4
+ADDRGP4 trap_Print
+CALLI4
+pop
+ADDRGP4 $9
+JUMPV
+LABELV $16
+ADDRGP4 $17
+ARGP4
+ADDRGP4 trap_Print
+CALLI4
+pop
+LABELV $8
+LABELV $9
+ADDRLP4 0
+INDIRI4
+RETI4
+LABELV $1
+endproc vmMain 4 4
+import trap_Print
+lit
+align 1
+LABELV $17
+byte 1 116
+byte 1 104
+byte 1 114
+byte 1 101
+byte 1 101
+byte 1 0
+align 1
+LABELV $15
+byte 1 116
+byte 1 119
+byte 1 111
+byte 1 0
+align 1
+LABELV $13
+byte 1 111
+byte 1 110
+byte 1 101
+byte 1 0
+align 1
+LABELV $11
+byte 1 122
+byte 1 101
+byte 1 114
+byte 1 111
+byte 1 0
+align 1
+LABELV $4
+byte 1 110
+byte 1 101
+byte 1 103
+byte 1 97
+byte 1 116
+byte 1 105
+byte 1 118
+byte 1 101
+byte 1 0
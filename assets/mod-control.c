@@ -0,0 +1,27 @@
+int vmMain(int command, int arg0, int arg1, int arg2, int arg3, int arg4, int arg5, int arg6, int arg7, int arg8, int arg9, int arg10, int arg11) {
+	int i;
+
+	if (command < 0) {
+		trap_Print("negative");
+		return -1;
+	}
+	i = 0;
+	while (i < arg0) {
+		i = i + command;
+	}
+	switch (command) {
+	case 0:
+		trap_Print("zero");
+		break;
+	case 1:
+		trap_Print("one");
+		break;
+	case 2:
+		trap_Print("two");
+		break;
+	case 3:
+		trap_Print("three");
+		break;
+	}
+	return i;
+}
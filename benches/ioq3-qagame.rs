@@ -1,13 +1,18 @@
-#![feature(test)]
+//! Times parsing of the ioquake3 baseq3 `qagame.qvm`.
+//!
+//! Run with `cargo bench`.
 
-extern crate test;
+#[macro_use]
+extern crate criterion;
 extern crate quake3_qvm;
 
-use test::Bencher;
-use quake3_qvm::*;
+use criterion::Criterion;
+use quake3_qvm::parser;
 
-#[bench]
-fn bench_parse_qvm_ioq3_qagame(b: &mut Bencher) {
+fn bench_parse_qvm_ioq3_qagame(c: &mut Criterion) {
     let data = include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm");
-    b.iter(|| parser::parse_qvm(data).unwrap());
+    c.bench_function("parse_qvm_ioq3_qagame", |b| b.iter(|| parser::parse_qvm(data).unwrap()));
 }
+
+criterion_group!(benches, bench_parse_qvm_ioq3_qagame);
+criterion_main!(benches);
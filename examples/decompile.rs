@@ -0,0 +1,44 @@
+//! Decompiles a QVM into C-like pseudocode on stdout.
+//!
+//! ```text
+//! cargo run --example decompile -- qagame.qvm [qagame.map [syscalls.asm]]
+//! ```
+
+extern crate quake3_qvm;
+
+use quake3_qvm::decompiler::Decompiler;
+use quake3_qvm::parser::{parse_qvm, parse_symbol_map};
+use quake3_qvm::q3asm::parse_syscalls;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 3 {
+        eprintln!("usage: decompile <qvm> [<map> [<syscalls.asm>]]");
+        process::exit(2);
+    }
+
+    let mut qvm = parse_qvm(&fs::read(&args[0]).expect("failed to read QVM"))
+        .expect("failed to parse QVM");
+    if let Some(map) = args.get(1) {
+        let symbols = parse_symbol_map(&fs::read(map).expect("failed to read map"))
+            .expect("failed to parse map");
+        qvm.set_symbols(symbols);
+    }
+    let syscalls = args.get(2).map(|path| {
+        let source = fs::read_to_string(path).expect("failed to read system calls");
+        parse_syscalls(path, &source).expect("failed to parse system calls")
+    });
+
+    let mut decompiler = Decompiler::new(&qvm);
+    if let Some(ref syscalls) = syscalls {
+        decompiler.set_syscalls(syscalls);
+    }
+    let stdout = io::stdout();
+    decompiler
+        .write(&mut stdout.lock())
+        .expect("failed to write pseudocode");
+}
@@ -0,0 +1,49 @@
+//! Writes the call graph of a QVM, or the control-flow graph of one of its
+//! procedures, in the Graphviz DOT language to stdout.
+//!
+//! ```text
+//! cargo run --example dot -- qagame.qvm [qagame.map [G_InitGame]] | dot -Tsvg
+//! ```
+
+extern crate quake3_qvm;
+
+use quake3_qvm::cfg::Cfg;
+use quake3_qvm::disassembler::Disassembler;
+use quake3_qvm::dot::{write_call_graph, write_cfg};
+use quake3_qvm::parser::{parse_qvm, parse_symbol_map};
+use quake3_qvm::Segment;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() || args.len() > 3 {
+        eprintln!("usage: dot <qvm> [<map> [<procedure>]]");
+        process::exit(2);
+    }
+
+    let mut qvm = parse_qvm(&fs::read(&args[0]).expect("failed to read QVM"))
+        .expect("failed to parse QVM");
+    if let Some(map) = args.get(1) {
+        let symbols = parse_symbol_map(&fs::read(map).expect("failed to read map"))
+            .expect("failed to parse map");
+        qvm.set_symbols(symbols);
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    match args.get(2) {
+        Some(name) => {
+            let cfg = Cfg::for_qvm(&qvm)
+                .into_iter()
+                .find(|cfg| qvm.symbol_name(Segment::CODE, cfg.procedure().start()) ==
+                            Some(name.as_str()))
+                .expect("unknown procedure");
+            write_cfg(&Disassembler::new(&qvm), &cfg, &mut stdout)
+                .expect("failed to write graph");
+        }
+        None => write_call_graph(&qvm, &mut stdout).expect("failed to write graph"),
+    }
+}
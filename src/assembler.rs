@@ -0,0 +1,398 @@
+//! Assembler for the textual representation of QVM code.
+//!
+//! This accepts the output of `disassembler`, i.e. one instruction per line
+//! like `CONST -1`, labels like `vmMain:` and segment directives. Comments
+//! start with `;`, and leading instruction index and offset columns are
+//! ignored, so disassembly can be edited and assembled again.
+//!
+//! Unlike `q3asm`, this does not translate LCC bytecode but assembles raw
+//! `Instruction`s.
+
+use super::{QVM, Version};
+use bytecode::{Address, Instruction};
+use opcodes::{Opcode, OperandKind};
+use super::errors::*;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// An operand that is either a number or a label.
+enum Operand {
+    Value(u32),
+    Label(String),
+}
+
+/// A parsed line with its line number, for error messages.
+enum Item {
+    Instruction(usize, Opcode, Option<Operand>),
+    Target(usize, Operand),
+}
+
+/// Parses an integer in decimal or `0x` hexadecimal notation.
+fn parse_integer(token: &str) -> Option<u32> {
+    if let Some(hex) = token.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if token.starts_with('-') {
+        i32::from_str(token).ok().map(|value| value as u32)
+    } else {
+        u32::from_str(token).ok()
+    }
+}
+
+/// Parses an integer or float literal.
+///
+/// Literals need a digit, so that labels like `inf` or `nan` stay labels.
+fn parse_literal(token: &str) -> Option<u32> {
+    if !token.contains(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    parse_integer(token).or_else(|| f32::from_str(token).ok().map(f32::to_bits))
+}
+
+/// Parses an operand, which may be a label if it starts like an identifier.
+fn parse_operand(token: &str) -> Option<Operand> {
+    match parse_literal(token) {
+        Some(value) => Some(Operand::Value(value)),
+        None if token.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+            Some(Operand::Label(token.to_string()))
+        }
+        None => None,
+    }
+}
+
+struct State<'a> {
+    name: &'a str,
+    line: usize,
+    version: Version,
+    labels: HashMap<String, Address>,
+    items: Vec<Item>,
+    instruction_count: usize,
+    data: Vec<u32>,
+    lit: Vec<u8>,
+    bss_length: u32,
+}
+
+impl<'a> State<'a> {
+    fn error<T>(&self, line: usize, message: String) -> Result<T> {
+        Err(Error::Assembler(self.name.to_string(), line, message))
+    }
+
+    fn define_label(&mut self, label: &str) -> Result<()> {
+        match parse_operand(label) {
+            Some(Operand::Label(_)) => {}
+            _ => return self.error(self.line, format!("invalid label {:?}", label)),
+        }
+        let index = self.instruction_count as Address;
+        if self.labels.insert(label.to_string(), index).is_some() {
+            return self.error(self.line, format!("label {} redefined", label));
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &str, arguments: &[&str]) -> Result<()> {
+        match directive {
+            ".version" => {
+                self.version = match arguments {
+                    ["1"] => Version::V1,
+                    ["2"] => Version::V2,
+                    _ => return self.error(self.line, "expected version 1 or 2".to_string()),
+                }
+            }
+            ".bss" => {
+                self.bss_length = match arguments {
+                    [length] => {
+                        match parse_integer(length) {
+                            Some(length) => length,
+                            None => return self.error(self.line, "invalid length".to_string()),
+                        }
+                    }
+                    _ => return self.error(self.line, "expected length".to_string()),
+                }
+            }
+            ".data" => {
+                for argument in arguments {
+                    match parse_literal(argument) {
+                        Some(word) => self.data.push(word),
+                        None => return self.error(self.line, format!("invalid word {}", argument)),
+                    }
+                }
+            }
+            ".lit" => {
+                for argument in arguments {
+                    match parse_integer(argument) {
+                        Some(byte) if byte <= 0xff => self.lit.push(byte as u8),
+                        _ => return self.error(self.line, format!("invalid byte {}", argument)),
+                    }
+                }
+            }
+            ".jtrg" => {
+                self.version = Version::V2;
+                for argument in arguments {
+                    match parse_operand(argument) {
+                        Some(target) => self.items.push(Item::Target(self.line, target)),
+                        None => {
+                            return self.error(self.line, format!("invalid target {}", argument))
+                        }
+                    }
+                }
+            }
+            _ => return self.error(self.line, format!("unknown directive {}", directive)),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, arguments: &[&str]) -> Result<()> {
+        let opcode = match Opcode::from_name(mnemonic) {
+            Some(opcode) => opcode,
+            None => return self.error(self.line, format!("unknown instruction {}", mnemonic)),
+        };
+        let operand = match (opcode.operand(), arguments) {
+            (OperandKind::None, []) => None,
+            (OperandKind::None, _) => {
+                return self.error(self.line, format!("{} takes no operand", mnemonic))
+            }
+            (_, [operand]) => {
+                match parse_operand(operand) {
+                    Some(operand) => Some(operand),
+                    None => return self.error(self.line, format!("invalid operand {}", operand)),
+                }
+            }
+            (_, _) => return self.error(self.line, format!("{} takes one operand", mnemonic)),
+        };
+        self.items.push(Item::Instruction(self.line, opcode, operand));
+        self.instruction_count += 1;
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        let line = match line.find(';') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        while let Some(label) = tokens.first().and_then(|token| token.strip_suffix(':')) {
+            self.define_label(label)?;
+            tokens.remove(0);
+        }
+        // Skip the index and offset columns of the disassembly
+        while tokens.first().is_some_and(|token| token.starts_with(|c: char| c.is_ascii_digit())) {
+            tokens.remove(0);
+        }
+        match tokens.split_first() {
+            Some((directive, arguments)) if directive.starts_with('.') => {
+                self.directive(directive, arguments)
+            }
+            Some((mnemonic, arguments)) => self.instruction(mnemonic, arguments),
+            None => Ok(()),
+        }
+    }
+
+    fn resolve(&self, line: usize, operand: &Operand) -> Result<u32> {
+        match *operand {
+            Operand::Value(value) => Ok(value),
+            Operand::Label(ref label) => {
+                match self.labels.get(label) {
+                    Some(&address) => Ok(address),
+                    None => self.error(line, format!("undefined label {}", label)),
+                }
+            }
+        }
+    }
+
+    fn qvm(self) -> Result<QVM> {
+        let mut code = Vec::with_capacity(self.instruction_count);
+        let mut jtrg = vec![];
+        for item in &self.items {
+            match *item {
+                Item::Instruction(line, opcode, ref operand) => {
+                    let value = match *operand {
+                        Some(ref operand) => self.resolve(line, operand)?,
+                        None => 0,
+                    };
+                    if opcode.operand() == OperandKind::ArgOffset && value > 0xff {
+                        return self.error(line, format!("operand {} out of range", value));
+                    }
+                    code.push(Instruction::new(opcode, value));
+                }
+                Item::Target(line, ref target) => jtrg.push(self.resolve(line, target)?),
+            }
+        }
+        match self.version {
+            Version::V1 => QVM::new(code, self.data, self.lit, self.bss_length),
+            Version::V2 => QVM::new_v2(code, self.data, self.lit, self.bss_length, jtrg),
+        }
+    }
+}
+
+/// Assembles a VM from its textual representation.
+///
+/// `name` is only used for error messages. The VM is version 1 unless there is
+/// a `.version 2` or `.jtrg` directive.
+///
+/// # Errors
+/// Returns `Error::Assembler` for the first error in `source`, or
+/// `Error::Invalid` if the VM fails validation.
+pub fn assemble(name: &str, source: &str) -> Result<QVM> {
+    let mut state = State {
+        name,
+        line: 0,
+        version: Version::V1,
+        labels: HashMap::new(),
+        items: vec![],
+        instruction_count: 0,
+        data: vec![],
+        lit: vec![],
+        bss_length: 0,
+    };
+    for (index, line) in source.lines().enumerate() {
+        state.line = index + 1;
+        state.line(line)?;
+    }
+    state.qvm()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::assemble;
+    use bytecode::Instruction;
+    use disassembler::disassemble;
+    use errors::Error;
+    use parser::{parse_qvm, parse_symbol_map};
+    use writer::write_qvm;
+    use {QVM, Version};
+
+    fn assert_round_trip(data: &[u8], map: Option<&[u8]>) {
+        let mut qvm = parse_qvm(data).unwrap();
+        if let Some(map) = map {
+            qvm.set_symbols(parse_symbol_map(map).unwrap());
+        }
+        let assembled = assemble("test", &disassemble(&qvm)).unwrap();
+        let mut result = vec![];
+        write_qvm(&assembled, &mut result).unwrap();
+        assert!(result == data, "assembled binary differs");
+    }
+
+    #[test]
+    fn test_round_trip_files() {
+        assert_round_trip(include_bytes!("../assets/mod-minimal.qvm"), None);
+        assert_round_trip(include_bytes!("../assets/mod-bss.qvm"),
+                          Some(include_bytes!("../assets/mod-bss.map")));
+        assert_round_trip(include_bytes!("../assets/mod-data.qvm"),
+                          Some(include_bytes!("../assets/mod-data.map")));
+        assert_round_trip(include_bytes!("../assets/mod-lit.qvm"),
+                          Some(include_bytes!("../assets/mod-lit.map")));
+        assert_round_trip(include_bytes!("../assets/mod-syscall.qvm"),
+                          Some(include_bytes!("../assets/mod-syscall.map")));
+    }
+
+    #[test]
+    fn test_round_trip_ioq3_qagame() {
+        let data = include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm");
+        assert_round_trip(data, None);
+        assert_round_trip(data, Some(include_bytes!("../assets/ioq3/baseq3/vm/qagame.map")));
+    }
+
+    #[test]
+    fn test_round_trip_v2() {
+        let code = vec![Instruction::ENTER(8),
+                        Instruction::CONST(4),
+                        Instruction::JUMP,
+                        Instruction::const_f32(0.5),
+                        Instruction::LEAVE(8)];
+        let qvm = QVM::new_v2(code, vec![0], vec![], 0, vec![3, 4]).unwrap();
+        let assembled = assemble("test", &disassemble(&qvm)).unwrap();
+        assert_eq!(assembled.version(), Version::V2);
+        assert_eq!(assembled, qvm);
+    }
+
+    #[test]
+    fn test_round_trip_infinity() {
+        let code = vec![Instruction::ENTER(8),
+                        Instruction::const_f32(1.0),
+                        Instruction::const_f32(f32::INFINITY),
+                        Instruction::ADDF,
+                        Instruction::const_f32(f32::NEG_INFINITY),
+                        Instruction::ADDF,
+                        Instruction::LEAVE(8)];
+        let qvm = QVM::new(code, vec![0], vec![], 0).unwrap();
+        let source = disassemble(&qvm);
+        assert!(source.contains("0x7f800000") && source.contains("0xff800000"));
+        assert_eq!(assemble("test", &source).unwrap(), qvm);
+    }
+
+    #[test]
+    fn test_assemble_hand_written() {
+        let source = "
+            ; return -1 if the command is negative
+            .bss 0x10000
+            .data 0
+            vmMain:
+                ENTER 8
+                LOCAL 16
+                LOAD4
+                CONST 0
+                GEI positive
+                CONST -1
+                LEAVE 8
+            positive: CONST 1.0
+                LEAVE 8
+        ";
+        let qvm = assemble("test", source).unwrap();
+        assert_eq!(qvm.version(), Version::V1);
+        assert_eq!(qvm.bss_length(), 0x10000);
+        assert_eq!(qvm.instructions(),
+                   &vec![Instruction::ENTER(8),
+                         Instruction::LOCAL(16),
+                         Instruction::LOAD4,
+                         Instruction::CONST(0),
+                         Instruction::GEI(7),
+                         Instruction::const_i32(-1),
+                         Instruction::LEAVE(8),
+                         Instruction::const_f32(1.0),
+                         Instruction::LEAVE(8)]);
+    }
+
+    #[test]
+    fn test_assemble_float_like_labels() {
+        let source = "
+            vmMain:
+                ENTER 8
+                CONST inf
+                JUMP
+            inf: CONST nan
+                JUMP
+            nan: CONST 1e3
+                LEAVE 8
+        ";
+        let qvm = assemble("test", source).unwrap();
+        assert_eq!(qvm.instructions(),
+                   &vec![Instruction::ENTER(8),
+                         Instruction::CONST(3),
+                         Instruction::JUMP,
+                         Instruction::CONST(5),
+                         Instruction::JUMP,
+                         Instruction::const_f32(1e3),
+                         Instruction::LEAVE(8)]);
+    }
+
+    fn assert_error(source: &str, line: usize, message: &str) {
+        match assemble("test", source) {
+            Err(Error::Assembler(ref file, l, ref m)) => {
+                assert_eq!((file.as_str(), l, m.as_str()), ("test", line, message))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_error("ENTER 8\nFOO\n", 2, "unknown instruction FOO");
+        assert_error("ENTER\n", 1, "ENTER takes one operand");
+        assert_error("PUSH 1\n", 1, "PUSH takes no operand");
+        assert_error("ENTER 8\nEQ nowhere\n", 2, "undefined label nowhere");
+        assert_error("a:\na:\n", 2, "label a redefined");
+        assert_error("ARG 256\n", 1, "operand 256 out of range");
+        assert_error(".lit 0x100\n", 1, "invalid byte 0x100");
+        assert_error(".text\n", 1, "unknown directive .text");
+    }
+}
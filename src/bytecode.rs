@@ -1,5 +1,8 @@
 //! Types for the compiled format of a QVM.
 
+use opcodes::{ControlFlow, Opcode, StackEffect};
+use std::fmt;
+
 /// Size of procedure stack adjustment.
 pub type FrameSize = u32;
 
@@ -16,6 +19,8 @@ pub type ArgOffset = u8;
 pub type Address = u32;
 
 /// Literal value.
+///
+/// These are just 32 bits, see `LiteralKind` for their interpretations.
 pub type Literal = u32;
 
 // These should match their opcodes
@@ -164,3 +169,372 @@ pub enum Instruction {
     /// Convert float to signed integer.
     CVFI,
 }
+
+impl Instruction {
+    /// Creates an instruction from its opcode and raw operand.
+    ///
+    /// The operand is ignored for opcodes without one and truncated for
+    /// `ARG`.
+    pub fn new(opcode: Opcode, operand: u32) -> Instruction {
+        match opcode {
+            Opcode::UNDEF => Instruction::UNDEF,
+            Opcode::IGNORE => Instruction::IGNORE,
+            Opcode::BREAK => Instruction::BREAK,
+            Opcode::ENTER => Instruction::ENTER(operand),
+            Opcode::LEAVE => Instruction::LEAVE(operand),
+            Opcode::CALL => Instruction::CALL,
+            Opcode::PUSH => Instruction::PUSH,
+            Opcode::POP => Instruction::POP,
+            Opcode::CONST => Instruction::CONST(operand),
+            Opcode::LOCAL => Instruction::LOCAL(operand),
+            Opcode::JUMP => Instruction::JUMP,
+            Opcode::EQ => Instruction::EQ(operand),
+            Opcode::NE => Instruction::NE(operand),
+            Opcode::LTI => Instruction::LTI(operand),
+            Opcode::LEI => Instruction::LEI(operand),
+            Opcode::GTI => Instruction::GTI(operand),
+            Opcode::GEI => Instruction::GEI(operand),
+            Opcode::LTU => Instruction::LTU(operand),
+            Opcode::LEU => Instruction::LEU(operand),
+            Opcode::GTU => Instruction::GTU(operand),
+            Opcode::GEU => Instruction::GEU(operand),
+            Opcode::EQF => Instruction::EQF(operand),
+            Opcode::NEF => Instruction::NEF(operand),
+            Opcode::LTF => Instruction::LTF(operand),
+            Opcode::LEF => Instruction::LEF(operand),
+            Opcode::GTF => Instruction::GTF(operand),
+            Opcode::GEF => Instruction::GEF(operand),
+            Opcode::LOAD1 => Instruction::LOAD1,
+            Opcode::LOAD2 => Instruction::LOAD2,
+            Opcode::LOAD4 => Instruction::LOAD4,
+            Opcode::STORE1 => Instruction::STORE1,
+            Opcode::STORE2 => Instruction::STORE2,
+            Opcode::STORE4 => Instruction::STORE4,
+            Opcode::ARG => Instruction::ARG(operand as ArgOffset),
+            Opcode::BLOCK_COPY => Instruction::BLOCK_COPY(operand),
+            Opcode::SEX8 => Instruction::SEX8,
+            Opcode::SEX16 => Instruction::SEX16,
+            Opcode::NEGI => Instruction::NEGI,
+            Opcode::ADD => Instruction::ADD,
+            Opcode::SUB => Instruction::SUB,
+            Opcode::DIVI => Instruction::DIVI,
+            Opcode::DIVU => Instruction::DIVU,
+            Opcode::MODI => Instruction::MODI,
+            Opcode::MODU => Instruction::MODU,
+            Opcode::MULI => Instruction::MULI,
+            Opcode::MULU => Instruction::MULU,
+            Opcode::BAND => Instruction::BAND,
+            Opcode::BOR => Instruction::BOR,
+            Opcode::BXOR => Instruction::BXOR,
+            Opcode::BCOM => Instruction::BCOM,
+            Opcode::LSH => Instruction::LSH,
+            Opcode::RSHI => Instruction::RSHI,
+            Opcode::RSHU => Instruction::RSHU,
+            Opcode::NEGF => Instruction::NEGF,
+            Opcode::ADDF => Instruction::ADDF,
+            Opcode::SUBF => Instruction::SUBF,
+            Opcode::DIVF => Instruction::DIVF,
+            Opcode::MULF => Instruction::MULF,
+            Opcode::CVIF => Instruction::CVIF,
+            Opcode::CVFI => Instruction::CVFI,
+        }
+    }
+
+    /// Creates a `CONST` of a signed integer.
+    pub fn const_i32(value: i32) -> Instruction {
+        Instruction::CONST(value as Literal)
+    }
+
+    /// Creates a `CONST` of a float.
+    pub fn const_f32(value: f32) -> Instruction {
+        Instruction::CONST(value.to_bits())
+    }
+
+    /// Returns the opcode of the instruction.
+    pub fn opcode(&self) -> Opcode {
+        match *self {
+            Instruction::UNDEF => Opcode::UNDEF,
+            Instruction::IGNORE => Opcode::IGNORE,
+            Instruction::BREAK => Opcode::BREAK,
+            Instruction::ENTER(_) => Opcode::ENTER,
+            Instruction::LEAVE(_) => Opcode::LEAVE,
+            Instruction::CALL => Opcode::CALL,
+            Instruction::PUSH => Opcode::PUSH,
+            Instruction::POP => Opcode::POP,
+            Instruction::CONST(_) => Opcode::CONST,
+            Instruction::LOCAL(_) => Opcode::LOCAL,
+            Instruction::JUMP => Opcode::JUMP,
+            Instruction::EQ(_) => Opcode::EQ,
+            Instruction::NE(_) => Opcode::NE,
+            Instruction::LTI(_) => Opcode::LTI,
+            Instruction::LEI(_) => Opcode::LEI,
+            Instruction::GTI(_) => Opcode::GTI,
+            Instruction::GEI(_) => Opcode::GEI,
+            Instruction::LTU(_) => Opcode::LTU,
+            Instruction::LEU(_) => Opcode::LEU,
+            Instruction::GTU(_) => Opcode::GTU,
+            Instruction::GEU(_) => Opcode::GEU,
+            Instruction::EQF(_) => Opcode::EQF,
+            Instruction::NEF(_) => Opcode::NEF,
+            Instruction::LTF(_) => Opcode::LTF,
+            Instruction::LEF(_) => Opcode::LEF,
+            Instruction::GTF(_) => Opcode::GTF,
+            Instruction::GEF(_) => Opcode::GEF,
+            Instruction::LOAD1 => Opcode::LOAD1,
+            Instruction::LOAD2 => Opcode::LOAD2,
+            Instruction::LOAD4 => Opcode::LOAD4,
+            Instruction::STORE1 => Opcode::STORE1,
+            Instruction::STORE2 => Opcode::STORE2,
+            Instruction::STORE4 => Opcode::STORE4,
+            Instruction::ARG(_) => Opcode::ARG,
+            Instruction::BLOCK_COPY(_) => Opcode::BLOCK_COPY,
+            Instruction::SEX8 => Opcode::SEX8,
+            Instruction::SEX16 => Opcode::SEX16,
+            Instruction::NEGI => Opcode::NEGI,
+            Instruction::ADD => Opcode::ADD,
+            Instruction::SUB => Opcode::SUB,
+            Instruction::DIVI => Opcode::DIVI,
+            Instruction::DIVU => Opcode::DIVU,
+            Instruction::MODI => Opcode::MODI,
+            Instruction::MODU => Opcode::MODU,
+            Instruction::MULI => Opcode::MULI,
+            Instruction::MULU => Opcode::MULU,
+            Instruction::BAND => Opcode::BAND,
+            Instruction::BOR => Opcode::BOR,
+            Instruction::BXOR => Opcode::BXOR,
+            Instruction::BCOM => Opcode::BCOM,
+            Instruction::LSH => Opcode::LSH,
+            Instruction::RSHI => Opcode::RSHI,
+            Instruction::RSHU => Opcode::RSHU,
+            Instruction::NEGF => Opcode::NEGF,
+            Instruction::ADDF => Opcode::ADDF,
+            Instruction::SUBF => Opcode::SUBF,
+            Instruction::DIVF => Opcode::DIVF,
+            Instruction::MULF => Opcode::MULF,
+            Instruction::CVIF => Opcode::CVIF,
+            Instruction::CVFI => Opcode::CVFI,
+        }
+    }
+
+    /// Returns the raw operand of the instruction, if it has one.
+    pub fn operand(&self) -> Option<u32> {
+        match *self {
+            Instruction::ENTER(x) |
+            Instruction::LEAVE(x) |
+            Instruction::CONST(x) |
+            Instruction::LOCAL(x) |
+            Instruction::EQ(x) |
+            Instruction::NE(x) |
+            Instruction::LTI(x) |
+            Instruction::LEI(x) |
+            Instruction::GTI(x) |
+            Instruction::GEI(x) |
+            Instruction::LTU(x) |
+            Instruction::LEU(x) |
+            Instruction::GTU(x) |
+            Instruction::GEU(x) |
+            Instruction::EQF(x) |
+            Instruction::NEF(x) |
+            Instruction::LTF(x) |
+            Instruction::LEF(x) |
+            Instruction::GTF(x) |
+            Instruction::GEF(x) |
+            Instruction::BLOCK_COPY(x) => Some(x),
+            Instruction::ARG(x) => Some(x as u32),
+            _ => None,
+        }
+    }
+
+    /// Returns the literal of a `CONST`.
+    pub fn literal(&self) -> Option<Literal> {
+        match *self {
+            Instruction::CONST(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Returns the literal of a `CONST` as a signed integer.
+    pub fn literal_i32(&self) -> Option<i32> {
+        self.literal().map(|x| x as i32)
+    }
+
+    /// Returns the literal of a `CONST` as a float.
+    pub fn literal_f32(&self) -> Option<f32> {
+        self.literal().map(f32::from_bits)
+    }
+
+    /// Returns the number of octets of the encoded instruction.
+    pub fn size(&self) -> usize {
+        self.opcode().size()
+    }
+
+    /// Returns how the instruction affects the operand stack.
+    pub fn stack_effect(&self) -> StackEffect {
+        self.opcode().stack_effect()
+    }
+
+    /// Returns how the instruction affects control flow.
+    pub fn control_flow(&self) -> ControlFlow {
+        self.opcode().control_flow()
+    }
+
+    /// Returns the target of a conditional branch.
+    pub fn branch_target(&self) -> Option<Address> {
+        if self.opcode().is_branch() {
+            self.operand()
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether this is a conditional branch.
+    pub fn is_branch(&self) -> bool {
+        self.opcode().is_branch()
+    }
+
+    /// Returns whether this is a procedure or system call.
+    pub fn is_call(&self) -> bool {
+        self.opcode().is_call()
+    }
+
+    /// Returns whether execution never continues with the next instruction.
+    pub fn is_terminator(&self) -> bool {
+        self.opcode().is_terminator()
+    }
+}
+
+
+/// An interpretation of the 32 bits of a `Literal`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LiteralKind {
+    /// Unsigned integer.
+    Unsigned,
+    /// Signed integer.
+    Signed,
+    /// IEEE 754 single precision float.
+    Float,
+    /// Instruction index, or a system call if negative.
+    CodeAddress,
+    /// Octet offset within the memory image.
+    DataAddress,
+}
+
+impl LiteralKind {
+    /// Infers the kind of the literal of the `CONST` at `index` from the
+    /// instruction that consumes it.
+    ///
+    /// This is a heuristic and defaults to `LiteralKind::Signed`.
+    pub fn infer(code: &[Instruction], index: usize) -> LiteralKind {
+        match code.get(index + 1) {
+            Some(&Instruction::CALL) |
+            Some(&Instruction::JUMP) => LiteralKind::CodeAddress,
+            Some(&Instruction::LOAD1) |
+            Some(&Instruction::LOAD2) |
+            Some(&Instruction::LOAD4) => LiteralKind::DataAddress,
+            Some(next) if next.opcode().has_float_operands() => LiteralKind::Float,
+            _ => LiteralKind::Signed,
+        }
+    }
+}
+
+/// A `Literal` with its interpretation, e.g. for display.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TypedLiteral {
+    /// The raw literal.
+    pub value: Literal,
+    /// The interpretation of `value`.
+    pub kind: LiteralKind,
+}
+
+impl TypedLiteral {
+    /// Creates a new typed literal.
+    pub fn new(value: Literal, kind: LiteralKind) -> TypedLiteral {
+        TypedLiteral { value, kind }
+    }
+}
+
+impl fmt::Display for TypedLiteral {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            LiteralKind::Unsigned => write!(f, "{}", self.value),
+            LiteralKind::Signed |
+            LiteralKind::CodeAddress => write!(f, "{}", self.value as i32),
+            // NaN payloads would get lost, and infinities have no digits
+            LiteralKind::Float if !f32::from_bits(self.value).is_finite() => {
+                write!(f, "{:#010x}", self.value)
+            }
+            LiteralKind::Float => write!(f, "{:?}", f32::from_bits(self.value)),
+            LiteralKind::DataAddress => write!(f, "{:#010x}", self.value),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Instruction, LiteralKind, TypedLiteral};
+    use opcodes::{ControlFlow, Opcode};
+
+    #[test]
+    fn test_new_opcode_round_trip() {
+        for octet in 0..60 {
+            let opcode = Opcode::from_u8(octet).unwrap();
+            let instruction = Instruction::new(opcode, 0x42);
+            assert_eq!(instruction.opcode(), opcode);
+            let operand = instruction.operand();
+            assert_eq!(operand.is_some(), opcode.size() > 1);
+            if let Some(operand) = operand {
+                assert_eq!(operand, 0x42);
+            }
+        }
+    }
+
+    #[test]
+    fn test_instruction_metadata() {
+        assert_eq!(Instruction::ARG(8).size(), 2);
+        assert_eq!(Instruction::ARG(8).operand(), Some(8));
+        assert_eq!(Instruction::PUSH.operand(), None);
+        assert_eq!(Instruction::GEU(7).branch_target(), Some(7));
+        assert_eq!(Instruction::CONST(7).branch_target(), None);
+        assert_eq!(Instruction::JUMP.control_flow(), ControlFlow::Jump);
+        assert_eq!(Instruction::BLOCK_COPY(16).stack_effect().delta(), -2);
+        assert!(Instruction::LEAVE(8).is_terminator());
+        assert!(Instruction::CALL.is_call());
+    }
+
+    #[test]
+    fn test_typed_constructors() {
+        assert_eq!(Instruction::const_i32(-666), Instruction::CONST(0xfffffd66));
+        assert_eq!(Instruction::const_f32(1.0), Instruction::CONST(0x3f800000));
+        assert_eq!(Instruction::const_i32(-1).literal_i32(), Some(-1));
+        assert_eq!(Instruction::const_f32(0.5).literal_f32(), Some(0.5));
+        assert_eq!(Instruction::PUSH.literal(), None);
+    }
+
+    #[test]
+    fn test_literal_kind_infer() {
+        let code = [Instruction::const_i32(-666),
+                    Instruction::CALL,
+                    Instruction::CONST(4),
+                    Instruction::LOAD4,
+                    Instruction::const_f32(2.0),
+                    Instruction::MULF,
+                    Instruction::const_i32(-1)];
+        assert_eq!(LiteralKind::infer(&code, 0), LiteralKind::CodeAddress);
+        assert_eq!(LiteralKind::infer(&code, 2), LiteralKind::DataAddress);
+        assert_eq!(LiteralKind::infer(&code, 4), LiteralKind::Float);
+        assert_eq!(LiteralKind::infer(&code, 6), LiteralKind::Signed);
+    }
+
+    #[test]
+    fn test_typed_literal_display() {
+        let display = |value, kind| TypedLiteral::new(value, kind).to_string();
+        assert_eq!(display(0xffffffff, LiteralKind::Unsigned), "4294967295");
+        assert_eq!(display(0xffffffff, LiteralKind::Signed), "-1");
+        assert_eq!(display(0xfffffd66, LiteralKind::CodeAddress), "-666");
+        assert_eq!(display(0x3f800000, LiteralKind::Float), "1.0");
+        assert_eq!(display(0x7fc00001, LiteralKind::Float), "0x7fc00001");
+        assert_eq!(display(0xff800000, LiteralKind::Float), "0xff800000");
+        assert_eq!(display(0x1234, LiteralKind::DataAddress), "0x00001234");
+    }
+}
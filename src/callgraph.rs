@@ -0,0 +1,269 @@
+//! Call graph of the procedures of a VM.
+//!
+//! Calls are `CALL` instructions, whose target is on top of the operand stack.
+//! q3asm emits direct calls as `CONST target; CALL`, where negative targets
+//! are system calls. Any other `CALL`, e.g. through a function pointer, cannot
+//! be resolved statically.
+
+use super::QVM;
+use bytecode::{Address, Instruction};
+use cfg::{procedures, Procedure};
+use std::collections::BTreeSet;
+
+/// The target of a call.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Callee {
+    /// A procedure, by the address of its `ENTER` instruction.
+    Procedure(Address),
+    /// A system call, by its negative address.
+    Syscall(i32),
+    /// A computed address, e.g. a function pointer.
+    Indirect,
+}
+
+/// A `CALL` instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CallSite {
+    /// Address of the `CALL` instruction.
+    pub index: Address,
+    /// The target of the call.
+    pub callee: Callee,
+}
+
+/// The calls of all procedures of a VM.
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    procedures: Vec<Procedure>,
+    calls: Vec<Vec<CallSite>>,
+}
+
+impl CallGraph {
+    /// Builds the call graph of the given code.
+    pub fn new(code: &[Instruction]) -> CallGraph {
+        let procedures = procedures(code);
+        let calls = procedures
+            .iter()
+            .map(|procedure| {
+                (procedure.start()..procedure.end())
+                    .filter(|&index| code[index as usize] == Instruction::CALL)
+                    .map(|index| {
+                        let callee = match code[index as usize - 1] {
+                            Instruction::CONST(target) if (target as i32) < 0 => {
+                                Callee::Syscall(target as i32)
+                            }
+                            Instruction::CONST(target) => Callee::Procedure(target),
+                            _ => Callee::Indirect,
+                        };
+                        CallSite { index, callee }
+                    })
+                    .collect()
+            })
+            .collect();
+        CallGraph { procedures, calls }
+    }
+
+    /// Builds the call graph of a VM.
+    pub fn for_qvm(qvm: &QVM) -> CallGraph {
+        CallGraph::new(qvm.instructions())
+    }
+
+    /// Returns all procedures, ordered by address.
+    pub fn procedures(&self) -> &[Procedure] {
+        &self.procedures
+    }
+
+    /// Returns the procedure that contains the instruction at `address`.
+    pub fn procedure_at(&self, address: Address) -> Option<&Procedure> {
+        self.position(address).map(|i| &self.procedures[i])
+    }
+
+    fn position(&self, address: Address) -> Option<usize> {
+        let index = match self.procedures
+                  .binary_search_by_key(&address, |procedure| procedure.start()) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        if self.procedures[index].contains(address) {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the index of the procedure starting at `procedure`.
+    fn index(&self, procedure: Address) -> Option<usize> {
+        self.procedures
+            .binary_search_by_key(&procedure, |procedure| procedure.start())
+            .ok()
+    }
+
+    /// Returns the calls of the procedure at `procedure`, in code order.
+    ///
+    /// This is empty if there is no procedure at that address.
+    pub fn calls(&self, procedure: Address) -> &[CallSite] {
+        match self.index(procedure) {
+            Some(index) => &self.calls[index],
+            None => &[],
+        }
+    }
+
+    /// Returns the distinct targets called by the procedure at `procedure`.
+    pub fn callees(&self, procedure: Address) -> BTreeSet<Callee> {
+        self.calls(procedure).iter().map(|call| call.callee).collect()
+    }
+
+    /// Returns the procedures that directly call the procedure at `procedure`.
+    pub fn callers(&self, procedure: Address) -> Vec<Address> {
+        self.procedures
+            .iter()
+            .zip(&self.calls)
+            .filter(|&(_, calls)| {
+                        calls
+                            .iter()
+                            .any(|call| call.callee == Callee::Procedure(procedure))
+                    })
+            .map(|(caller, _)| caller.start())
+            .collect()
+    }
+
+    /// Returns all calls that cannot be resolved statically.
+    pub fn indirect_calls(&self) -> Vec<CallSite> {
+        self.calls
+            .iter()
+            .flat_map(|calls| calls.iter())
+            .filter(|call| call.callee == Callee::Indirect)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the procedures reachable from the procedure at `procedure`
+    /// through direct calls, including itself.
+    pub fn reachable(&self, procedure: Address) -> BTreeSet<Address> {
+        let mut reachable = BTreeSet::new();
+        let mut pending = vec![procedure];
+        while let Some(procedure) = pending.pop() {
+            if self.index(procedure).is_none() || !reachable.insert(procedure) {
+                continue;
+            }
+            for call in self.calls(procedure) {
+                if let Callee::Procedure(callee) = call.callee {
+                    pending.push(callee);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Returns the system calls made by the procedure at `procedure` or any
+    /// procedure it reaches through direct calls.
+    pub fn reachable_syscalls(&self, procedure: Address) -> BTreeSet<i32> {
+        self.reachable(procedure)
+            .into_iter()
+            .flat_map(|procedure| self.calls(procedure).iter())
+            .filter_map(|call| match call.callee {
+                            Callee::Syscall(syscall) => Some(syscall),
+                            _ => None,
+                        })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{CallGraph, CallSite, Callee};
+    use bytecode::Instruction;
+    use parser::{parse_qvm, parse_symbol_map};
+    use Segment;
+
+    fn test_code() -> Vec<Instruction> {
+        vec![Instruction::ENTER(8),
+             Instruction::CONST(6),
+             Instruction::CALL,
+             Instruction::const_i32(-1),
+             Instruction::CALL,
+             Instruction::LEAVE(8),
+             Instruction::ENTER(8),
+             Instruction::CONST(6),
+             Instruction::CALL,
+             Instruction::LOCAL(8),
+             Instruction::LOAD4,
+             Instruction::CALL,
+             Instruction::const_i32(-2),
+             Instruction::CALL,
+             Instruction::LEAVE(8),
+             Instruction::ENTER(8),
+             Instruction::LEAVE(8)]
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let graph = CallGraph::new(&test_code());
+        assert_eq!(graph.procedures().len(), 3);
+        assert_eq!(graph.calls(0),
+                   &[CallSite {
+                         index: 2,
+                         callee: Callee::Procedure(6),
+                     },
+                     CallSite {
+                         index: 4,
+                         callee: Callee::Syscall(-1),
+                     }]);
+        assert_eq!(graph.calls(1), &[]);
+        assert_eq!(graph.callees(6).into_iter().collect::<Vec<_>>(),
+                   vec![Callee::Procedure(6), Callee::Syscall(-2), Callee::Indirect]);
+        assert_eq!(graph.callers(6), vec![0, 6]);
+        assert_eq!(graph.callers(15), vec![]);
+        assert_eq!(graph.indirect_calls(),
+                   vec![CallSite {
+                            index: 11,
+                            callee: Callee::Indirect,
+                        }]);
+        assert_eq!(graph.procedure_at(10).map(|p| p.start()), Some(6));
+    }
+
+    #[test]
+    fn test_reachable_syscalls() {
+        let graph = CallGraph::new(&test_code());
+        assert_eq!(graph.reachable(0).into_iter().collect::<Vec<_>>(), vec![0, 6]);
+        assert_eq!(graph.reachable_syscalls(0).into_iter().collect::<Vec<_>>(),
+                   vec![-2, -1]);
+        assert_eq!(graph.reachable_syscalls(6).into_iter().collect::<Vec<_>>(),
+                   vec![-2]);
+        assert!(graph.reachable_syscalls(15).is_empty());
+    }
+
+    #[test]
+    fn test_call_graph_ioq3_qagame() {
+        let mut qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        qvm.set_symbols(parse_symbol_map(include_bytes!("../assets/ioq3/baseq3/vm/qagame.map"))
+                            .unwrap());
+        let graph = CallGraph::for_qvm(&qvm);
+        let symbols = qvm.symbols().unwrap();
+        let address = |name| match symbols.resolve(name) {
+            Some((Segment::CODE, address)) => address,
+            _ => panic!("no procedure {}", name),
+        };
+        let syscall_names = |procedure| {
+            graph
+                .reachable_syscalls(address(procedure))
+                .into_iter()
+                .map(|syscall| symbols.syscall_name(syscall).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(syscall_names("G_Printf").contains(&"trap_Print"));
+        assert!(syscall_names("G_InitGame").contains(&"trap_Cvar_Register"));
+        assert!(syscall_names("vmMain").contains(&"trap_Print"));
+        assert!(graph.callers(address("G_Printf")).contains(&address("G_InitGame")));
+
+        // Entities think through function pointers
+        let indirect = graph.indirect_calls();
+        assert!(!indirect.is_empty());
+        let g_run_think = graph.procedure_at(address("G_RunThink")).unwrap();
+        assert!(indirect
+                    .iter()
+                    .any(|call| g_run_think.contains(call.index)));
+    }
+}
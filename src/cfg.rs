@@ -0,0 +1,393 @@
+//! Procedures and their control-flow graphs.
+//!
+//! q3asm emits every procedure as a contiguous run of instructions, starting
+//! with `ENTER` and ending right before the next procedure's `ENTER`.
+
+use super::QVM;
+use bytecode::{Address, FrameSize, Instruction};
+use opcodes::ControlFlow;
+use std::collections::{BTreeSet, HashMap};
+
+/// A procedure, i.e. the instructions from an `ENTER` up to the next one.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Procedure {
+    start: Address,
+    end: Address,
+    frame_size: FrameSize,
+}
+
+impl Procedure {
+    /// Returns the address of the `ENTER` instruction.
+    pub fn start(&self) -> Address {
+        self.start
+    }
+
+    /// Returns the address after the last instruction.
+    pub fn end(&self) -> Address {
+        self.end
+    }
+
+    /// Returns the frame size of the `ENTER` instruction.
+    pub fn frame_size(&self) -> FrameSize {
+        self.frame_size
+    }
+
+    /// Returns whether the instruction at `address` belongs to the procedure.
+    pub fn contains(&self, address: Address) -> bool {
+        self.start <= address && address < self.end
+    }
+}
+
+/// Splits code into procedures at each `ENTER`.
+///
+/// Instructions before the first `ENTER` do not belong to any procedure.
+pub fn procedures(code: &[Instruction]) -> Vec<Procedure> {
+    let mut procedures: Vec<Procedure> = vec![];
+    for (index, instruction) in code.iter().enumerate() {
+        if let Instruction::ENTER(frame_size) = *instruction {
+            if let Some(previous) = procedures.last_mut() {
+                previous.end = index as Address;
+            }
+            procedures.push(Procedure {
+                                start: index as Address,
+                                end: code.len() as Address,
+                                frame_size,
+                            });
+        }
+    }
+    procedures
+}
+
+/// Index of a basic block within its `Cfg`.
+pub type BlockId = usize;
+
+/// A sequence of instructions that is only entered at the start and only
+/// left at the end.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BasicBlock {
+    start: Address,
+    end: Address,
+}
+
+impl BasicBlock {
+    /// Returns the address of the first instruction.
+    pub fn start(&self) -> Address {
+        self.start
+    }
+
+    /// Returns the address after the last instruction.
+    pub fn end(&self) -> Address {
+        self.end
+    }
+
+    /// Returns the address of the last instruction.
+    pub fn last(&self) -> Address {
+        self.end - 1
+    }
+}
+
+/// Why control flows along an edge.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EdgeKind {
+    /// The block ends without a branch, before a jump target.
+    Fallthrough,
+    /// The condition of the branch is true.
+    True,
+    /// The condition of the branch is false.
+    False,
+    /// A `JUMP` to a constant address.
+    Jump,
+    /// A `JUMP` to a computed address, e.g. via a switch jump table.
+    Indirect,
+}
+
+/// A control-flow edge to a basic block.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Edge {
+    /// The block control flows to.
+    pub target: BlockId,
+    /// Why control flows along this edge.
+    pub kind: EdgeKind,
+}
+
+/// The control-flow graph of a procedure.
+///
+/// The entry block is always `0`, and blocks are ordered by address.
+#[derive(Debug, Clone)]
+pub struct Cfg<'a> {
+    code: &'a [Instruction],
+    procedure: Procedure,
+    blocks: Vec<BasicBlock>,
+    successors: Vec<Vec<Edge>>,
+    predecessors: Vec<Vec<BlockId>>,
+    unresolved: Vec<BlockId>,
+}
+
+/// Returns the target of a `CONST; JUMP` at `index`.
+fn direct_jump_target(code: &[Instruction], index: usize) -> Option<Address> {
+    match (index.checked_sub(1).map(|i| code[i]), code[index]) {
+        (Some(Instruction::CONST(target)), Instruction::JUMP) => Some(target),
+        _ => None,
+    }
+}
+
+impl<'a> Cfg<'a> {
+    /// Builds the control-flow graph of a procedure.
+    ///
+    /// Indirect `JUMP`s are assumed to target any of `jump_targets` within the
+    /// procedure. Without jump targets, e.g. for version 1 VMs, they are
+    /// reported by `unresolved_jumps`.
+    pub fn new(code: &'a [Instruction],
+               procedure: &Procedure,
+               jump_targets: Option<&[Address]>)
+               -> Cfg<'a> {
+        let indirect_targets: Vec<Address> = jump_targets
+            .unwrap_or(&[])
+            .iter()
+            .cloned()
+            .filter(|&target| procedure.contains(target))
+            .collect();
+
+        let mut leaders = BTreeSet::new();
+        leaders.insert(procedure.start);
+        leaders.extend(indirect_targets.iter().cloned());
+        for index in procedure.start..procedure.end {
+            let instruction = code[index as usize];
+            match instruction.control_flow() {
+                ControlFlow::Next | ControlFlow::Call => continue,
+                _ => {}
+            }
+            if let Some(target) = instruction
+                   .branch_target()
+                   .or_else(|| direct_jump_target(code, index as usize)) {
+                if procedure.contains(target) {
+                    leaders.insert(target);
+                }
+            }
+            if index + 1 < procedure.end {
+                leaders.insert(index + 1);
+            }
+        }
+
+        let leaders: Vec<Address> = leaders.into_iter().collect();
+        let mut blocks = Vec::with_capacity(leaders.len());
+        let mut block_ids = HashMap::new();
+        for (id, &start) in leaders.iter().enumerate() {
+            let end = leaders.get(id + 1).cloned().unwrap_or(procedure.end);
+            block_ids.insert(start, id);
+            blocks.push(BasicBlock { start, end });
+        }
+
+        let mut successors = vec![vec![]; blocks.len()];
+        let mut predecessors = vec![vec![]; blocks.len()];
+        let mut unresolved = vec![];
+        for (id, block) in blocks.iter().enumerate() {
+            let last = block.last() as usize;
+            let instruction = code[last];
+            let next = if block.end < procedure.end {
+                Some(id + 1)
+            } else {
+                None
+            };
+            let mut edges = vec![];
+            match instruction.control_flow() {
+                ControlFlow::Next | ControlFlow::Call => {
+                    if let Some(next) = next {
+                        edges.push((next, EdgeKind::Fallthrough));
+                    }
+                }
+                ControlFlow::Branch => {
+                    let target = instruction.branch_target().and_then(|t| block_ids.get(&t));
+                    if let Some(&target) = target {
+                        edges.push((target, EdgeKind::True));
+                    }
+                    if let Some(next) = next {
+                        edges.push((next, EdgeKind::False));
+                    }
+                }
+                ControlFlow::Jump => {
+                    match direct_jump_target(code, last) {
+                        Some(target) => {
+                            if let Some(&target) = block_ids.get(&target) {
+                                edges.push((target, EdgeKind::Jump));
+                            }
+                        }
+                        None if jump_targets.is_some() => {
+                            for target in &indirect_targets {
+                                edges.push((block_ids[target], EdgeKind::Indirect));
+                            }
+                        }
+                        None => unresolved.push(id),
+                    }
+                }
+                ControlFlow::Return => {}
+            }
+            for (target, kind) in edges {
+                successors[id].push(Edge { target, kind });
+                if !predecessors[target].contains(&id) {
+                    predecessors[target].push(id);
+                }
+            }
+        }
+
+        Cfg {
+            code,
+            procedure: *procedure,
+            blocks,
+            successors,
+            predecessors,
+            unresolved,
+        }
+    }
+
+    /// Builds the control-flow graphs of all procedures of a VM.
+    pub fn for_qvm(qvm: &'a QVM) -> Vec<Cfg<'a>> {
+        let jump_targets = qvm.jump_targets().map(|targets| targets.as_slice());
+        procedures(qvm.instructions())
+            .iter()
+            .map(|procedure| Cfg::new(qvm.instructions(), procedure, jump_targets))
+            .collect()
+    }
+
+    /// Returns the procedure of the graph.
+    pub fn procedure(&self) -> &Procedure {
+        &self.procedure
+    }
+
+    /// Returns all basic blocks, ordered by address.
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    /// Returns the basic block with the given id.
+    pub fn block(&self, id: BlockId) -> &BasicBlock {
+        &self.blocks[id]
+    }
+
+    /// Returns the instructions of a basic block.
+    pub fn instructions(&self, id: BlockId) -> &'a [Instruction] {
+        let block = self.blocks[id];
+        &self.code[block.start as usize..block.end as usize]
+    }
+
+    /// Returns the block that contains the instruction at `address`.
+    pub fn block_at(&self, address: Address) -> Option<BlockId> {
+        if !self.procedure.contains(address) {
+            return None;
+        }
+        match self.blocks.binary_search_by_key(&address, |block| block.start) {
+            Ok(id) => Some(id),
+            Err(id) => Some(id - 1),
+        }
+    }
+
+    /// Returns the outgoing edges of a block.
+    pub fn successors(&self, id: BlockId) -> &[Edge] {
+        &self.successors[id]
+    }
+
+    /// Returns the blocks with edges to a block.
+    pub fn predecessors(&self, id: BlockId) -> &[BlockId] {
+        &self.predecessors[id]
+    }
+
+    /// Returns the blocks ending in an indirect `JUMP` without known targets.
+    pub fn unresolved_jumps(&self) -> &[BlockId] {
+        &self.unresolved
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{procedures, BasicBlock, Cfg, Edge, EdgeKind};
+    use bytecode::Instruction;
+    use parser::parse_qvm;
+
+    fn edge(target: usize, kind: EdgeKind) -> Edge {
+        Edge { target, kind }
+    }
+
+    #[test]
+    fn test_procedures() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::LEAVE(8),
+                    Instruction::ENTER(12),
+                    Instruction::PUSH,
+                    Instruction::LEAVE(12)];
+        let procedures = procedures(&code);
+        assert_eq!(procedures.len(), 2);
+        assert_eq!((procedures[0].start(), procedures[0].end()), (0, 2));
+        assert_eq!((procedures[1].start(), procedures[1].end()), (2, 5));
+        assert_eq!(procedures[1].frame_size(), 12);
+    }
+
+    #[test]
+    fn test_cfg_if_else() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::LOCAL(16),
+                    Instruction::LOAD4,
+                    Instruction::CONST(0),
+                    Instruction::EQ(8),
+                    Instruction::CONST(1),
+                    Instruction::CONST(9),
+                    Instruction::JUMP,
+                    Instruction::CONST(2),
+                    Instruction::LEAVE(8)];
+        let procedure = procedures(&code)[0];
+        let cfg = Cfg::new(&code, &procedure, None);
+        assert_eq!(cfg.blocks(),
+                   &[BasicBlock { start: 0, end: 5 },
+                     BasicBlock { start: 5, end: 8 },
+                     BasicBlock { start: 8, end: 9 },
+                     BasicBlock { start: 9, end: 10 }]);
+        assert_eq!(cfg.successors(0),
+                   &[edge(2, EdgeKind::True), edge(1, EdgeKind::False)]);
+        assert_eq!(cfg.successors(1), &[edge(3, EdgeKind::Jump)]);
+        assert_eq!(cfg.successors(2), &[edge(3, EdgeKind::Fallthrough)]);
+        assert_eq!(cfg.successors(3), &[]);
+        assert_eq!(cfg.predecessors(3), &[1, 2]);
+        assert_eq!(cfg.block_at(6), Some(1));
+        assert_eq!(cfg.instructions(2), &[Instruction::CONST(2)]);
+        assert!(cfg.unresolved_jumps().is_empty());
+    }
+
+    #[test]
+    fn test_cfg_indirect_jump() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::LOCAL(16),
+                    Instruction::LOAD4,
+                    Instruction::JUMP,
+                    Instruction::CONST(1),
+                    Instruction::LEAVE(8),
+                    Instruction::CONST(2),
+                    Instruction::LEAVE(8)];
+        let procedure = procedures(&code)[0];
+        let cfg = Cfg::new(&code, &procedure, Some(&[4, 6]));
+        assert_eq!(cfg.successors(0),
+                   &[edge(1, EdgeKind::Indirect), edge(2, EdgeKind::Indirect)]);
+        let cfg = Cfg::new(&code, &procedure, None);
+        assert_eq!(cfg.successors(0), &[]);
+        assert_eq!(cfg.unresolved_jumps(), &[0]);
+    }
+
+    #[test]
+    fn test_cfg_ioq3_qagame() {
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        let cfgs = Cfg::for_qvm(&qvm);
+        assert_eq!(cfgs.len(), procedures(qvm.instructions()).len());
+        for cfg in &cfgs {
+            let blocks = cfg.blocks();
+            assert_eq!(blocks[0].start(), cfg.procedure().start());
+            assert_eq!(blocks[blocks.len() - 1].end(), cfg.procedure().end());
+            for (id, block) in blocks.iter().enumerate() {
+                for edge in cfg.successors(id) {
+                    assert!(cfg.predecessors(edge.target).contains(&id));
+                }
+                if id > 0 {
+                    assert_eq!(blocks[id - 1].end(), block.start());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1765 @@
+//! Decompiler to C-like pseudocode.
+//!
+//! Procedures are lifted into SSA form, whose values are inlined into the
+//! expression trees they came from. Control flow is structured into `if`,
+//! `while`, `do`/`while` and `switch` statements using dominators and natural
+//! loops, falling back to `goto` where this fails.
+//!
+//! Frame slots are named after their offset, e.g. `local_8`, and arguments
+//! after their position, e.g. `arg0`, and declared with the types recovered by
+//! `types`. Globals, procedures and system calls are named after the symbol
+//! map attached to the VM, if any, and strings in the LIT segment are inlined.
+//!
+//! ```text
+//! int vmMain(void) {
+//!     trap_Print("Hello, world!");
+//!     return -1;
+//! }
+//! ```
+
+use super::{QVM, Segment};
+use bytecode::{Address, ArgOffset, BlockSize, FrameOffset, Literal};
+use cfg::{BlockId, Cfg, EdgeKind};
+use disassembler::lit_string;
+use dominators::Dominators;
+use loops::{Loop, Loops};
+use opcodes::Opcode;
+use opstack::StackError;
+use signatures::param_index;
+use ssa::{Function, Op, ValueId};
+use symbols::SymbolMap;
+use syscalls::syscall_number;
+use types::{Location, Type, Types};
+use super::errors::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Write;
+use std::mem;
+
+/// Precedence of names, literals and calls.
+const ATOM: u8 = 15;
+/// Precedence of unary operators and casts.
+const UNARY: u8 = 14;
+
+/// A rendered expression.
+struct Expr {
+    text: String,
+    /// The precedence of the outermost operator, as in C.
+    precedence: u8,
+}
+
+impl Expr {
+    fn atom(text: String) -> Expr {
+        Expr {
+            text,
+            precedence: ATOM,
+        }
+    }
+
+    fn unary(text: String) -> Expr {
+        Expr {
+            text,
+            precedence: UNARY,
+        }
+    }
+
+    /// Returns the expression, in parentheses if it binds less tightly than
+    /// `precedence`.
+    fn wrap(self, precedence: u8) -> String {
+        if self.precedence < precedence {
+            format!("({})", self.text)
+        } else {
+            self.text
+        }
+    }
+}
+
+/// The condition of a conditional branch, or of several combined by
+/// short-circuit evaluation.
+#[derive(Clone)]
+enum Condition {
+    Compare {
+        opcode: Opcode,
+        left: String,
+        right: String,
+        negated: bool,
+    },
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+/// How the condition of a block is combined with the previous ones.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Junction {
+    /// `previous || condition`, or with the negated condition.
+    Or(bool),
+    /// `previous && condition`, or with the negated condition.
+    And(bool),
+}
+
+/// Blocks merged into a short-circuit condition, and its true and false
+/// targets.
+type ShortCircuit = (Vec<(BlockId, Junction)>, BlockId, BlockId);
+
+impl Condition {
+    fn negate(self) -> Condition {
+        match self {
+            Condition::Compare {
+                opcode,
+                left,
+                right,
+                negated,
+            } => {
+                Condition::Compare {
+                    opcode,
+                    left,
+                    right,
+                    negated: !negated,
+                }
+            }
+            Condition::And(a, b) => Condition::Or(Box::new(a.negate()), Box::new(b.negate())),
+            Condition::Or(a, b) => Condition::And(Box::new(a.negate()), Box::new(b.negate())),
+        }
+    }
+
+    fn combine(self, junction: Junction, other: Condition) -> Condition {
+        let (other, negated) = match junction {
+            Junction::Or(negated) | Junction::And(negated) => (other, negated),
+        };
+        let other = Box::new(if negated { other.negate() } else { other });
+        match junction {
+            Junction::Or(_) => Condition::Or(Box::new(self), other),
+            Junction::And(_) => Condition::And(Box::new(self), other),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use opcodes::Opcode::*;
+
+        let (opcode, left, right, negated) = match *self {
+            Condition::Compare {
+                opcode,
+                ref left,
+                ref right,
+                negated,
+            } => (opcode, left, right, negated),
+            // `&&` binds more tightly, but mixing them is confusing to read
+            Condition::And(ref a, ref b) => {
+                let a = match **a {
+                    Condition::Or(..) => format!("({})", a),
+                    _ => a.to_string(),
+                };
+                let b = match **b {
+                    Condition::Or(..) => format!("({})", b),
+                    _ => b.to_string(),
+                };
+                return write!(f, "{} && {}", a, b);
+            }
+            Condition::Or(ref a, ref b) => {
+                let a = match **a {
+                    Condition::And(..) => format!("({})", a),
+                    _ => a.to_string(),
+                };
+                let b = match **b {
+                    Condition::And(..) => format!("({})", b),
+                    _ => b.to_string(),
+                };
+                return write!(f, "{} || {}", a, b);
+            }
+        };
+
+        // Negated float comparisons are true for NaN
+        let ordered_float = matches!(opcode, LTF | LEF | GTF | GEF);
+        let inverse = match (negated, opcode) {
+            (false, opcode) => opcode,
+            (true, _) if ordered_float => opcode,
+            (true, EQ) => NE,
+            (true, NE) => EQ,
+            (true, EQF) => NEF,
+            (true, NEF) => EQF,
+            (true, LTI) => GEI,
+            (true, GEI) => LTI,
+            (true, LEI) => GTI,
+            (true, GTI) => LEI,
+            (true, LTU) => GEU,
+            (true, GEU) => LTU,
+            (true, LEU) => GTU,
+            (true, GTU) => LEU,
+            (true, opcode) => opcode,
+        };
+        let (operator, _) = binary_operator(inverse);
+        if negated && ordered_float {
+            write!(f, "!({} {} {})", left, operator, right)
+        } else {
+            write!(f, "{} {} {}", left, operator, right)
+        }
+    }
+}
+
+/// A structured statement.
+enum Statement {
+    /// An expression statement or assignment, without the semicolon.
+    Simple(String),
+    Return(Option<String>),
+    Break,
+    Continue,
+    Goto(BlockId),
+    /// The start of a block, printed only if it is the target of a `goto`.
+    Label(BlockId),
+    If(Condition, Vec<Statement>, Vec<Statement>),
+    /// A `while` loop; `None` loops forever.
+    While(Option<Condition>, Vec<Statement>),
+    /// A `for` loop without initialization.
+    For(Condition, String, Vec<Statement>),
+    DoWhile(Vec<Statement>, Condition),
+    /// A `switch` with the labels and statements of each case.
+    Switch(String, Vec<(Vec<String>, Vec<Statement>)>),
+}
+
+/// A construct that `break` or `continue` can leave.
+enum Context {
+    Loop {
+        /// Index of the loop in `Loops`.
+        index: usize,
+        follow: Option<BlockId>,
+        /// The block a `continue` jumps to.
+        next: BlockId,
+    },
+    Switch { follow: Option<BlockId> },
+}
+
+/// A `switch` jump table as generated by LCC.
+///
+/// LCC checks the bounds of the selector before indexing the table:
+///
+/// ```text
+/// if (x < min) goto default;
+/// if (x > max) goto default;
+/// goto *table[x];
+/// ```
+struct JumpTable {
+    /// The blocks of the bounds check and the `JUMP`.
+    checks: [BlockId; 2],
+    selector: ValueId,
+    cases: Vec<(i32, BlockId)>,
+    default: BlockId,
+}
+
+/// Returns the C operator and its precedence of a binary operation or
+/// comparison.
+fn binary_operator(opcode: Opcode) -> (&'static str, u8) {
+    use opcodes::Opcode::*;
+    match opcode {
+        MULI | MULU | MULF => ("*", 13),
+        DIVI | DIVU | DIVF => ("/", 13),
+        MODI | MODU => ("%", 13),
+        ADD | ADDF => ("+", 12),
+        SUB | SUBF => ("-", 12),
+        LSH => ("<<", 11),
+        RSHI | RSHU => (">>", 11),
+        LTI | LTU | LTF => ("<", 10),
+        LEI | LEU | LEF => ("<=", 10),
+        GTI | GTU | GTF => (">", 10),
+        GEI | GEU | GEF => (">=", 10),
+        EQ | EQF => ("==", 9),
+        NE | NEF => ("!=", 9),
+        BAND => ("&", 8),
+        BXOR => ("^", 7),
+        BOR => ("|", 6),
+        _ => (opcode.name(), 0),
+    }
+}
+
+/// Returns the C operator or cast of a unary operation.
+fn unary_operator(opcode: Opcode) -> &'static str {
+    use opcodes::Opcode::*;
+    match opcode {
+        NEGI | NEGF => "-",
+        BCOM => "~",
+        SEX8 => "(char)",
+        SEX16 => "(short)",
+        CVIF => "(float)",
+        CVFI => "(int)",
+        _ => opcode.name(),
+    }
+}
+
+/// Returns the name of a frame slot.
+fn slot_name(frame_size: u32, offset: FrameOffset) -> String {
+    match param_index(frame_size, offset) {
+        Some(param) => format!("arg{}", param),
+        None => format!("local_{}", offset),
+    }
+}
+
+/// A decompiler for the procedures of a VM.
+pub struct Decompiler<'a> {
+    qvm: &'a QVM,
+    syscalls: Option<&'a SymbolMap>,
+    types: Types,
+}
+
+impl<'a> Decompiler<'a> {
+    /// Creates a new decompiler, naming symbols after the symbol map attached
+    /// to the VM, if any, and inferring the types of all procedures.
+    pub fn new(qvm: &'a QVM) -> Decompiler<'a> {
+        Decompiler {
+            qvm,
+            syscalls: None,
+            types: Types::for_qvm(qvm),
+        }
+    }
+
+    /// Sets a table of system call names, used for system calls missing from
+    /// the VM's symbol map.
+    ///
+    /// See `q3asm::parse_syscalls` to read such a table from a
+    /// `syscalls.asm` file.
+    pub fn set_syscalls(&mut self, syscalls: &'a SymbolMap) {
+        self.syscalls = Some(syscalls);
+    }
+
+    /// Returns the name of the procedure at `address`.
+    fn procedure_name(&self, address: Address) -> String {
+        match self.qvm.symbol_name(Segment::CODE, address) {
+            Some(name) => name.to_string(),
+            None => format!("L{}", address),
+        }
+    }
+
+    /// Returns the name of a system call by its negative address.
+    fn syscall_name(&self, address: i32) -> String {
+        self.qvm
+            .symbols()
+            .and_then(|symbols| symbols.syscall_name(address))
+            .or_else(|| self.syscalls.and_then(|symbols| symbols.syscall_name(address)))
+            .map_or_else(|| format!("syscall_{}", syscall_number(address)),
+                         str::to_string)
+    }
+
+    /// Returns the name of the global at `address` of the memory image.
+    fn global_name(&self, address: Literal) -> String {
+        match self.qvm.segment_address(address) {
+            Some((segment, relative)) => {
+                match self.qvm.symbol_name(segment, relative) {
+                    Some(name) => name.to_string(),
+                    None => format!("{}_{:08x}", format!("{:?}", segment).to_lowercase(), address),
+                }
+            }
+            None => format!("*(int *){:#x}", address),
+        }
+    }
+
+    /// Decompiles a procedure.
+    ///
+    /// # Errors
+    /// Returns a `StackError` if the procedure can not be lifted, see
+    /// `ssa::Function::lift`.
+    pub fn decompile_procedure(&self, cfg: &Cfg) -> ::std::result::Result<String, StackError> {
+        let function = Function::lift(cfg)?;
+        Ok(Builder::new(self, cfg, function).build())
+    }
+
+    /// Writes the pseudocode of all procedures, separated by blank lines.
+    ///
+    /// Procedures that can not be lifted are written as comments.
+    ///
+    /// # Errors
+    /// Returns `Error::Io` if writing fails.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for (i, cfg) in Cfg::for_qvm(self.qvm).iter().enumerate() {
+            if i > 0 {
+                writeln!(writer)?;
+            }
+            match self.decompile_procedure(cfg) {
+                Ok(code) => write!(writer, "{}", code)?,
+                Err(error) => {
+                    writeln!(writer,
+                             "/* {}: {} */",
+                             self.procedure_name(cfg.procedure().start()),
+                             error)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decompiles all procedures of a VM into a string, see `Decompiler::write`.
+pub fn decompile(qvm: &QVM) -> String {
+    let mut output = vec![];
+    Decompiler::new(qvm)
+        .write(&mut output)
+        .expect("writing to a Vec never fails");
+    String::from_utf8(output).expect("pseudocode is UTF-8")
+}
+
+/// Decompiles a single procedure.
+struct Builder<'d, 'c: 'd> {
+    decompiler: &'d Decompiler<'d>,
+    cfg: &'d Cfg<'c>,
+    function: Function,
+    uses: Vec<Vec<ValueId>>,
+    /// Whether a value is assigned to a temporary instead of being inlined.
+    temporaries: Vec<bool>,
+    dominators: Dominators,
+    post_dominators: Dominators,
+    loops: Loops,
+    emitted: Vec<bool>,
+    gotos: BTreeSet<BlockId>,
+    contexts: Vec<Context>,
+    /// Arguments for the next call, by offset.
+    args: Vec<(ArgOffset, String)>,
+    slots: BTreeSet<FrameOffset>,
+    temporary_names: BTreeSet<ValueId>,
+}
+
+impl<'d, 'c> Builder<'d, 'c> {
+    fn new(decompiler: &'d Decompiler<'d>,
+           cfg: &'d Cfg<'c>,
+           function: Function)
+           -> Builder<'d, 'c> {
+        let uses = function.uses();
+        let temporaries = (0..function.values.len())
+            .map(|id| {
+                let value = &function.values[id];
+                matches!(value.op, Op::Phi(_)) ||
+                uses[id].iter().any(|&user| {
+                                        let user = &function.values[user];
+                                        user.block != value.block ||
+                                        matches!(user.op, Op::Phi(_))
+                                    })
+            })
+            .collect();
+        let dominators = Dominators::new(cfg);
+        let loops = Loops::new(cfg, &dominators);
+        Builder {
+            decompiler,
+            cfg,
+            uses,
+            temporaries,
+            post_dominators: Dominators::post_dominators(cfg),
+            dominators,
+            loops,
+            emitted: vec![false; function.blocks.len()],
+            gotos: BTreeSet::new(),
+            contexts: vec![],
+            args: vec![],
+            slots: BTreeSet::new(),
+            temporary_names: BTreeSet::new(),
+            function,
+        }
+    }
+
+    fn build(mut self) -> String {
+        let mut body = self.sequence(0, None);
+        for block in 0..self.function.blocks.len() {
+            let is_inlined = self.returned_constant(block).is_some() ||
+                             self.forwarded(block).is_some();
+            if !self.emitted[block] && !self.is_dead(block) && !is_inlined {
+                self.gotos.insert(block);
+                body.extend(self.sequence(block, None));
+            }
+        }
+        // The epilogue of procedures without a return value
+        if let Some(&Statement::Return(None)) = body.last() {
+            body.pop();
+        }
+
+        let frame_size = self.function.frame_size;
+        let start = self.function.start;
+        let types = &self.decompiler.types;
+        let params: Vec<String> = types.params(start)
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| ty.declare(&format!("arg{}", i)))
+            .collect();
+        let name = self.decompiler.procedure_name(start);
+        let mut code = format!("{}({}) {{\n",
+                               match types.returns(start) {
+                                   Some(ty) => ty.declare(&name),
+                                   None => format!("void {}", name),
+                               },
+                               if params.is_empty() {
+                                   "void".to_string()
+                               } else {
+                                   params.join(", ")
+                               });
+        let mut declarations = 0;
+        for &offset in &self.slots {
+            let name = slot_name(frame_size, offset);
+            if !name.starts_with("arg") {
+                let ty = self.type_of(Location::Local(start, offset));
+                code.push_str(&format!("    {};\n", ty.declare(&name)));
+                declarations += 1;
+            }
+        }
+        for &temporary in &self.temporary_names {
+            let ty = types
+                .value_type(self.decompiler.qvm, &self.function, temporary)
+                .unwrap_or(Type::Int);
+            code.push_str(&format!("    {};\n", ty.declare(&format!("t{}", temporary))));
+            declarations += 1;
+        }
+        if declarations > 0 && !body.is_empty() {
+            code.push('\n');
+        }
+        self.print(&body, 1, &mut code);
+        code.push_str("}\n");
+        code
+    }
+
+    fn print(&self, statements: &[Statement], indent: usize, code: &mut String) {
+        let pad = "    ".repeat(indent);
+        for statement in statements {
+            match *statement {
+                Statement::Simple(ref text) => code.push_str(&format!("{}{};\n", pad, text)),
+                Statement::Return(None) => code.push_str(&format!("{}return;\n", pad)),
+                Statement::Return(Some(ref value)) => {
+                    code.push_str(&format!("{}return {};\n", pad, value))
+                }
+                Statement::Break => code.push_str(&format!("{}break;\n", pad)),
+                Statement::Continue => code.push_str(&format!("{}continue;\n", pad)),
+                Statement::Goto(block) => {
+                    code.push_str(&format!("{}goto L{};\n", pad, self.function.blocks[block].start))
+                }
+                Statement::Label(block) => {
+                    if self.gotos.contains(&block) {
+                        let pad = "    ".repeat(indent - 1);
+                        code.push_str(&format!("{}L{}:\n", pad, self.function.blocks[block].start));
+                    }
+                }
+                Statement::If(ref condition, ref then, ref otherwise) => {
+                    code.push_str(&format!("{}if ({}) {{\n", pad, condition));
+                    self.print(then, indent + 1, code);
+                    let mut otherwise = otherwise;
+                    // Print `else if` chains flat
+                    while let [Statement::If(ref condition, ref then, ref next)] = otherwise[..] {
+                        code.push_str(&format!("{}}} else if ({}) {{\n",
+                                               pad,
+                                               condition));
+                        self.print(then, indent + 1, code);
+                        otherwise = next;
+                    }
+                    if !otherwise.is_empty() {
+                        code.push_str(&format!("{}}} else {{\n", pad));
+                        self.print(otherwise, indent + 1, code);
+                    }
+                    code.push_str(&format!("{}}}\n", pad));
+                }
+                Statement::While(ref condition, ref body) => {
+                    let condition = match *condition {
+                        Some(ref condition) => condition.to_string(),
+                        None => "1".to_string(),
+                    };
+                    code.push_str(&format!("{}while ({}) {{\n", pad, condition));
+                    self.print(body, indent + 1, code);
+                    code.push_str(&format!("{}}}\n", pad));
+                }
+                Statement::For(ref condition, ref increment, ref body) => {
+                    code.push_str(&format!("{}for (; {}; {}) {{\n", pad, condition, increment));
+                    self.print(body, indent + 1, code);
+                    code.push_str(&format!("{}}}\n", pad));
+                }
+                Statement::DoWhile(ref body, ref condition) => {
+                    code.push_str(&format!("{}do {{\n", pad));
+                    self.print(body, indent + 1, code);
+                    code.push_str(&format!("{}}} while ({});\n", pad, condition));
+                }
+                Statement::Switch(ref selector, ref cases) => {
+                    code.push_str(&format!("{}switch ({}) {{\n", pad, selector));
+                    for (labels, body) in cases {
+                        for label in labels {
+                            code.push_str(&format!("{}{}:\n", pad, label));
+                        }
+                        self.print(body, indent + 1, code);
+                    }
+                    code.push_str(&format!("{}}}\n", pad));
+                }
+            }
+        }
+    }
+
+    /// Returns whether an unreachable block is only the dead code LCC emits
+    /// after `return` statements.
+    fn is_dead(&self, block: BlockId) -> bool {
+        !self.dominators.is_reachable(block) &&
+        self.roots(block)
+            .iter()
+            .all(|&value| matches!(self.function.values[value].op, Op::Jump(_) | Op::Return(_)))
+    }
+
+    /// Returns whether a value is part of the expression of a later value in
+    /// the same block.
+    fn is_inlined(&self, value: ValueId) -> bool {
+        !self.temporaries[value] && !self.uses[value].is_empty()
+    }
+
+    /// Returns the values of a block that are not inlined.
+    fn roots(&self, block: BlockId) -> Vec<ValueId> {
+        self.function.blocks[block]
+            .values
+            .iter()
+            .cloned()
+            .filter(|&value| !self.is_inlined(value))
+            .collect()
+    }
+
+    /// Returns the last value of a block if it ends the block.
+    fn terminator(&self, block: BlockId) -> Option<&Op> {
+        self.function.blocks[block]
+            .values
+            .last()
+            .map(|&value| &self.function.values[value].op)
+            .filter(|op| matches!(**op, Op::Branch(..) | Op::Jump(_) | Op::Return(_)))
+    }
+
+    /// Returns whether a block consists only of its conditional branch.
+    fn is_condition(&self, block: BlockId) -> bool {
+        self.function.blocks[block].phis.is_empty() &&
+        matches!(self.terminator(block), Some(&Op::Branch(..))) && self.roots(block).len() == 1
+    }
+
+    /// Returns the targets of the true and false edges of a block.
+    fn branch_targets(&self, block: BlockId) -> Option<(BlockId, BlockId)> {
+        let successors = &self.function.blocks[block].successors;
+        let target = |kind| successors.iter().find(|edge| edge.kind == kind).map(|e| e.target);
+        match (target(EdgeKind::True), target(EdgeKind::False)) {
+            (Some(t), Some(f)) => Some((t, f)),
+            _ => None,
+        }
+    }
+
+    /// Returns the recovered type of a location, `int` if it is unknown.
+    fn type_of(&self, location: Location) -> Type {
+        self.decompiler.types.get(location).unwrap_or(Type::Int)
+    }
+
+    /// Returns whether an address refers to a float frame slot or global.
+    fn is_float_location(&self, address: ValueId) -> bool {
+        if self.temporaries[address] {
+            return false;
+        }
+        let location = match self.function.values[address].op {
+            Op::Local(offset) => {
+                Location::slot(self.function.start, self.function.frame_size, offset)
+            }
+            Op::Const(address) => Location::Global(address),
+            _ => return false,
+        };
+        self.type_of(location) == Type::Float
+    }
+
+    fn temporary_name(&mut self, value: ValueId) -> String {
+        let mut name = value;
+        if let [user] = self.uses[value][..] {
+            if !self.is_phi(value) && self.is_phi(user) {
+                name = user;
+            }
+        }
+        self.temporary_names.insert(name);
+        format!("t{}", name)
+    }
+
+    fn is_phi(&self, value: ValueId) -> bool {
+        matches!(self.function.values[value].op, Op::Phi(_))
+    }
+
+    fn slot(&mut self, offset: FrameOffset) -> String {
+        self.slots.insert(offset);
+        slot_name(self.function.frame_size, offset)
+    }
+
+    /// Renders a value as an expression.
+    fn expr(&mut self, value: ValueId) -> Expr {
+        self.operand(value, false)
+    }
+
+    /// Renders a value as an expression, with literals as floats if `float`.
+    fn operand(&mut self, value: ValueId, float: bool) -> Expr {
+        if self.temporaries[value] {
+            return Expr::atom(self.temporary_name(value));
+        }
+        match self.function.values[value].op.clone() {
+            Op::Const(literal) => Expr::atom(self.literal(literal, float)),
+            Op::Local(offset) => Expr::unary(format!("&{}", self.slot(offset))),
+            Op::Undefined => Expr::atom("undefined".to_string()),
+            Op::Load(size, address) => self.lvalue(address, size),
+            Op::Unary(opcode, a) => {
+                let a = self.operand(a, opcode.has_float_operands()).wrap(UNARY);
+                Expr::unary(format!("{}{}", unary_operator(opcode), a))
+            }
+            Op::Binary(opcode, a, b) => {
+                let float = opcode.has_float_operands();
+                let (a, b) = if opcode == Opcode::ADD {
+                    (self.indexed(a, b), self.indexed(b, a))
+                } else {
+                    (self.operand(a, float), self.operand(b, float))
+                };
+                let (operator, precedence) = binary_operator(opcode);
+                Expr {
+                    text: format!("{} {} {}",
+                                  a.wrap(precedence),
+                                  operator,
+                                  b.wrap(precedence + 1)),
+                    precedence,
+                }
+            }
+            Op::Call(target) => {
+                let mut args = mem::take(&mut self.args);
+                let callee = match self.function.values[target].op {
+                    Op::Const(address) if (address as i32) < 0 => {
+                        self.decompiler.syscall_name(address as i32)
+                    }
+                    Op::Const(address) => self.decompiler.procedure_name(address),
+                    _ => self.expr(target).wrap(ATOM),
+                };
+                args.sort_by_key(|&(offset, _)| offset);
+                let args: Vec<String> = args.into_iter().map(|(_, arg)| arg).collect();
+                Expr::atom(format!("{}({})", callee, args.join(", ")))
+            }
+            ref op => Expr::atom(format!("/* {} */", op)),
+        }
+    }
+
+    /// Renders the operand of an `ADD`, as the address of a global if it is
+    /// indexed by the other operand, e.g. `(i << 2) + &table`.
+    fn indexed(&mut self, value: ValueId, other: ValueId) -> Expr {
+        let is_index = matches!(self.function.values[other].op,
+                                Op::Binary(Opcode::LSH, ..) |
+                                Op::Binary(Opcode::MULI, ..) |
+                                Op::Binary(Opcode::MULU, ..));
+        if let Op::Const(address) = self.function.values[value].op {
+            let qvm = self.decompiler.qvm;
+            let symbol = qvm.segment_address(address)
+                .and_then(|(segment, relative)| qvm.symbol_name(segment, relative));
+            if let (true, false, Some(name)) = (is_index, self.temporaries[value], symbol) {
+                return Expr::unary(format!("&{}", name));
+            }
+        }
+        self.expr(value)
+    }
+
+    fn literal(&self, literal: Literal, float: bool) -> String {
+        if float {
+            let value = f32::from_bits(literal);
+            if value.is_finite() {
+                return format!("{:?}", value);
+            }
+            return format!("{:#010x}", literal);
+        }
+        let qvm = self.decompiler.qvm;
+        if let Some((Segment::LIT, address)) = qvm.segment_address(literal) {
+            if let Some(string) = lit_string(qvm.lit(), address) {
+                return format!("{:?}", string);
+            }
+        }
+        (literal as i32).to_string()
+    }
+
+    /// Renders the memory accessed through an address.
+    fn lvalue(&mut self, address: ValueId, size: u8) -> Expr {
+        if !self.temporaries[address] {
+            match self.function.values[address].op {
+                Op::Local(offset) => return Expr::atom(self.slot(offset)),
+                Op::Const(literal) => return Expr::atom(self.decompiler.global_name(literal)),
+                _ => {}
+            }
+        }
+        let address = self.expr(address).wrap(UNARY);
+        Expr::unary(match size {
+                           1 => format!("*(unsigned char *){}", address),
+                           2 => format!("*(unsigned short *){}", address),
+                           _ => format!("*{}", address),
+                       })
+    }
+
+    /// Renders the branch at the end of a block as its condition for the
+    /// true edge.
+    fn condition(&mut self, block: BlockId) -> Condition {
+        let value = *self.function.blocks[block].values.last().expect("branches end blocks");
+        match self.function.values[value].op {
+            Op::Branch(opcode, a, b, _) => {
+                let float = opcode.has_float_operands();
+                let (_, precedence) = binary_operator(opcode);
+                Condition::Compare {
+                    opcode,
+                    left: self.operand(a, float).wrap(precedence),
+                    right: self.operand(b, float).wrap(precedence + 1),
+                    negated: false,
+                }
+            }
+            _ => panic!("block {} does not end with a branch", block),
+        }
+    }
+
+    /// Finds the blocks whose conditions are combined with the one of `block`
+    /// by `&&` or `||`, returning them and the final true and false targets.
+    fn short_circuit(&self, block: BlockId) -> Option<ShortCircuit> {
+        let (mut t, mut f) = self.branch_targets(block)?;
+        let mut merged = vec![];
+        let is_mergeable = |next: BlockId| {
+            next != block && self.is_condition(next) && !self.emitted[next] &&
+            !self.loops.is_header(next) &&
+            self.function.blocks[next].predecessors.len() == 1
+        };
+        loop {
+            if is_mergeable(f) && !merged.iter().any(|&(b, _)| b == f) {
+                let (t2, f2) = self.branch_targets(f)?;
+                if t2 == t {
+                    merged.push((f, Junction::Or(false)));
+                    f = f2;
+                    continue;
+                }
+                if f2 == t {
+                    merged.push((f, Junction::Or(true)));
+                    f = t2;
+                    continue;
+                }
+            }
+            if is_mergeable(t) && !merged.iter().any(|&(b, _)| b == t) {
+                let (t2, f2) = self.branch_targets(t)?;
+                if f2 == f {
+                    merged.push((t, Junction::And(false)));
+                    t = t2;
+                    continue;
+                }
+                if t2 == f {
+                    merged.push((t, Junction::And(true)));
+                    t = f2;
+                    continue;
+                }
+            }
+            return Some((merged, t, f));
+        }
+    }
+
+    /// Renders the conditions of a block and the blocks merged into it by
+    /// `short_circuit`.
+    fn merged_condition(&mut self, block: BlockId, merged: &[(BlockId, Junction)]) -> Condition {
+        let mut condition = self.condition(block);
+        for &(next, junction) in merged {
+            self.emitted[next] = true;
+            let next = self.condition(next);
+            condition = condition.combine(junction, next);
+        }
+        condition
+    }
+
+    /// Renders the statements of a block, except for its branch or jump.
+    fn statements(&mut self, block: BlockId, out: &mut Vec<Statement>) {
+        for value in self.roots(block) {
+            let statement = match self.function.values[value].op.clone() {
+                Op::Branch(..) | Op::Jump(_) => continue,
+                Op::Nop(Opcode::BREAK) => Statement::Simple("/* BREAK */".to_string()),
+                Op::Nop(_) => continue,
+                Op::Return(a) => {
+                    match self.function.values[a].op {
+                        Op::Undefined if !self.temporaries[a] => Statement::Return(None),
+                        _ => {
+                            let float = self.returns_float();
+                            Statement::Return(Some(self.operand(a, float).text))
+                        }
+                    }
+                }
+                Op::Store(4, address, a) if self.is_copy_to_itself(address, a) => continue,
+                Op::Store(size, address, a) => {
+                    let float = size == 4 && self.is_float_location(address);
+                    let target = self.lvalue(address, size).text;
+                    Statement::Simple(format!("{} = {}", target, self.operand(a, float).text))
+                }
+                Op::Arg(offset, a) => {
+                    let arg = self.expr(a).text;
+                    self.args.push((offset, arg));
+                    continue;
+                }
+                Op::BlockCopy(size, to, from) => self.block_copy(size, to, from),
+                Op::Pop(a) => {
+                    match self.function.values[a].op {
+                        Op::Undefined | Op::Const(_) | Op::Local(_) => continue,
+                        _ => Statement::Simple(self.expr(a).text),
+                    }
+                }
+                _ if self.temporaries[value] => {
+                    let name = self.temporary_name(value);
+                    self.temporaries[value] = false;
+                    let text = self.expr(value).text;
+                    self.temporaries[value] = true;
+                    Statement::Simple(format!("{} = {}", name, text))
+                }
+                _ => Statement::Simple(self.expr(value).text),
+            };
+            out.push(statement);
+        }
+
+        // Values left on the operand stack for phi nodes of successors
+        for edge in self.function.blocks[block].successors.clone() {
+            for phi in self.function.blocks[edge.target].phis.clone() {
+                let incoming = match self.function.values[phi].op {
+                    Op::Phi(ref incoming) => incoming.clone(),
+                    _ => continue,
+                };
+                for (_, value) in incoming.into_iter().filter(|&(p, _)| p == block) {
+                    let name = self.temporary_name(value);
+                    let phi = self.temporary_name(phi);
+                    if name != phi {
+                        out.push(Statement::Simple(format!("{} = {}", phi, name)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether a store writes the value loaded from the same frame
+    /// slot, which LCC emits for arguments.
+    fn is_copy_to_itself(&self, address: ValueId, value: ValueId) -> bool {
+        match (&self.function.values[address].op, &self.function.values[value].op) {
+            (&Op::Local(offset), &Op::Load(4, source)) => {
+                !self.temporaries[address] && !self.temporaries[value] &&
+                !self.temporaries[source] &&
+                self.function.values[source].op == Op::Local(offset)
+            }
+            _ => false,
+        }
+    }
+
+    fn block_copy(&mut self, size: BlockSize, to: ValueId, from: ValueId) -> Statement {
+        let to = self.expr(to).text;
+        let from = self.expr(from).text;
+        Statement::Simple(format!("memcpy({}, {}, {})", to, from, size))
+    }
+
+    /// Structures the blocks starting at `start` until control reaches `stop`
+    /// or leaves the current construct.
+    fn sequence(&mut self, start: BlockId, stop: Option<BlockId>) -> Vec<Statement> {
+        let mut out = vec![];
+        let mut current = Some(start);
+        while let Some(block) = current {
+            current = self.block(block, stop, &mut out);
+        }
+        out
+    }
+
+    /// Structures the blocks starting at `start` like `sequence`, preceded by
+    /// the jump to `start` if there is one.
+    fn arm(&mut self, start: BlockId, stop: Option<BlockId>) -> Vec<Statement> {
+        let mut out = vec![];
+        if let Some(start) = self.transition(start, stop, &mut out) {
+            out.extend(self.sequence(start, stop));
+        }
+        out
+    }
+
+    /// Handles control flowing to `target`, returning it if the current
+    /// sequence continues with it.
+    fn transition(&mut self,
+                  target: BlockId,
+                  stop: Option<BlockId>,
+                  out: &mut Vec<Statement>)
+                  -> Option<BlockId> {
+        if Some(target) == stop {
+            return None;
+        }
+        match self.jump(target) {
+            Some(statement) => {
+                if let Statement::Goto(target) = statement {
+                    self.gotos.insert(target);
+                }
+                out.push(statement);
+                None
+            }
+            None => Some(target),
+        }
+    }
+
+    /// Returns the statement that jumps to `target` if it is not the next
+    /// block of a sequence.
+    ///
+    /// Blocks that only return a constant, or only jump elsewhere, are
+    /// inlined into the jump.
+    fn jump(&self, target: BlockId) -> Option<Statement> {
+        let mut innermost = true;
+        let mut looped = false;
+        for context in self.contexts.iter().rev() {
+            match *context {
+                Context::Loop { follow, next, .. } => {
+                    if !looped && target == next {
+                        return Some(Statement::Continue);
+                    }
+                    if innermost && Some(target) == follow {
+                        return Some(Statement::Break);
+                    }
+                    looped = true;
+                }
+                Context::Switch { follow } => {
+                    if innermost && Some(target) == follow {
+                        return Some(Statement::Break);
+                    }
+                }
+            }
+            innermost = false;
+        }
+        if self.emitted[target] {
+            return Some(Statement::Goto(target));
+        }
+        if let Some(value) = self.returned_constant(target) {
+            return Some(Statement::Return(value));
+        }
+        match self.forwarded(target) {
+            Some(next) if next != target => self.jump(next),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of a block that only returns a constant, e.g. the
+    /// epilogue of procedures without a return value.
+    fn returned_constant(&self, block: BlockId) -> Option<Option<String>> {
+        let block = &self.function.blocks[block];
+        if !block.phis.is_empty() {
+            return None;
+        }
+        match block.values[..] {
+            [value, ret] if self.function.values[ret].op == Op::Return(value) => {
+                match self.function.values[value].op {
+                    Op::Undefined => Some(None),
+                    Op::Const(literal) => Some(Some(self.literal(literal, self.returns_float()))),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether the procedure returns a float.
+    fn returns_float(&self) -> bool {
+        self.decompiler.types.returns(self.function.start) == Some(Type::Float)
+    }
+
+    /// Returns the target of a block that only jumps to another block.
+    fn forwarded(&self, block: BlockId) -> Option<BlockId> {
+        let block = &self.function.blocks[block];
+        match (&block.phis[..], &block.values[..], &block.successors[..]) {
+            (&[], &[_, _], &[edge]) if edge.kind == EdgeKind::Jump => Some(edge.target),
+            _ => None,
+        }
+    }
+
+    /// Returns the innermost loop being structured.
+    fn current_loop(&self) -> Option<&Loop> {
+        self.contexts
+            .iter()
+            .rev()
+            .filter_map(|context| match *context {
+                            Context::Loop { index, .. } => Some(&self.loops.loops()[index]),
+                            Context::Switch { .. } => None,
+                        })
+            .next()
+    }
+
+    /// Returns the block where the paths from `block` join again, within the
+    /// current loop.
+    fn join(&self, block: BlockId) -> Option<BlockId> {
+        let join = self.post_dominators.immediate_dominator(block)?;
+        match self.current_loop() {
+            Some(l) if !l.contains(join) => None,
+            _ => Some(join),
+        }
+    }
+
+    /// Structures a block and the constructs it starts, returning the next
+    /// block of the sequence.
+    fn block(&mut self,
+             block: BlockId,
+             stop: Option<BlockId>,
+             out: &mut Vec<Statement>)
+             -> Option<BlockId> {
+        let is_structured = self.contexts.iter().any(|context| match *context {
+                                                         Context::Loop { index, .. } => {
+                                                             self.loops.loops()[index].header ==
+                                                             block
+                                                         }
+                                                         Context::Switch { .. } => false,
+                                                     });
+        if !is_structured {
+            if let Some(index) = self.loops.loops().iter().position(|l| l.header == block) {
+                return self.structure_loop(index, stop, out);
+            }
+        }
+
+        self.emitted[block] = true;
+        out.push(Statement::Label(block));
+        if let Some(table) = self.jump_table(block) {
+            return self.structure_switch(block, table, stop, out);
+        }
+        self.statements(block, out);
+
+        let successors = self.function.blocks[block].successors.clone();
+        match self.terminator(block).cloned() {
+            Some(Op::Return(_)) => None,
+            Some(Op::Branch(..)) => self.structure_if(block, stop, out),
+            Some(Op::Jump(target)) if successors.iter().all(|e| e.kind == EdgeKind::Jump) => {
+                match successors.first() {
+                    Some(edge) => self.transition(edge.target, stop, out),
+                    None => {
+                        let target = self.expr(target).wrap(UNARY);
+                        out.push(Statement::Simple(format!("goto *{}", target)));
+                        None
+                    }
+                }
+            }
+            Some(Op::Jump(target)) => {
+                let target = self.expr(target).wrap(UNARY);
+                out.push(Statement::Simple(format!("goto *{}", target)));
+                None
+            }
+            _ => {
+                successors
+                    .first()
+                    .and_then(|edge| self.transition(edge.target, stop, out))
+            }
+        }
+    }
+
+    fn structure_if(&mut self,
+                    block: BlockId,
+                    stop: Option<BlockId>,
+                    out: &mut Vec<Statement>)
+                    -> Option<BlockId> {
+        let (condition, t, f) = match self.short_circuit(block) {
+            Some((ref merged, t, f)) if t != f => (self.merged_condition(block, merged), t, f),
+            _ => {
+                let successors = &self.function.blocks[block].successors;
+                let next = successors.first().map(|edge| edge.target);
+                return next.and_then(|next| self.transition(next, stop, out));
+            }
+        };
+        let join = self.join(block);
+
+        // Branches that leave the construct, e.g. `if (x) break;`
+        let is_leaving = |target| Some(target) != stop && Some(target) != join;
+        if is_leaving(t) && self.jump(t).is_some() {
+            let then = self.arm(t, None);
+            out.push(Statement::If(condition, then, vec![]));
+            return self.transition(f, stop, out);
+        }
+        if is_leaving(f) && self.jump(f).is_some() {
+            let then = self.arm(f, None);
+            out.push(Statement::If(condition.negate(), then, vec![]));
+            return self.transition(t, stop, out);
+        }
+
+        match join {
+            Some(join) if join == t => {
+                let then = self.arm(f, Some(join));
+                out.push(Statement::If(condition.negate(), then, vec![]));
+            }
+            Some(join) if join == f => {
+                let then = self.arm(t, Some(join));
+                out.push(Statement::If(condition, then, vec![]));
+            }
+            _ => {
+                // The fallthrough is the `then` branch, as in LCC's code
+                let inner = join.or(stop);
+                let then = self.arm(f, inner);
+                let otherwise = self.arm(t, inner);
+                if then.is_empty() {
+                    out.push(Statement::If(condition, otherwise, then));
+                } else {
+                    out.push(Statement::If(condition.negate(), then, otherwise));
+                }
+            }
+        }
+        join.and_then(|join| self.transition(join, stop, out))
+    }
+
+    /// Returns the block following a loop, i.e. the first exit after it.
+    fn loop_follow(l: &Loop) -> Option<BlockId> {
+        let last = *l.body.last().expect("loops are not empty");
+        let exits: BTreeSet<BlockId> = l.exits.iter().map(|&(_, target)| target).collect();
+        exits
+            .iter()
+            .cloned()
+            .find(|&target| target > last)
+            .or_else(|| exits.iter().next_back().cloned())
+    }
+
+    fn structure_loop(&mut self,
+                      index: usize,
+                      stop: Option<BlockId>,
+                      out: &mut Vec<Statement>)
+                      -> Option<BlockId> {
+        let l = self.loops.loops()[index].clone();
+        let header = l.header;
+        let follow = Builder::loop_follow(&l);
+        let exits_to_follow = |targets: (BlockId, BlockId)| match targets {
+            (t, f) if l.contains(t) && !l.contains(f) && Some(f) == follow => Some((t, false)),
+            (t, f) if l.contains(f) && !l.contains(t) && Some(t) == follow => Some((f, true)),
+            _ => None,
+        };
+
+        let condition = self.short_circuit(header)
+            .filter(|_| self.is_condition(header))
+            .and_then(|(merged, t, f)| exits_to_follow((t, f)).map(|exit| (merged, exit)));
+        let statement = if let Some((merged, (inside, negated))) = condition {
+            // `while` and LCC's rotated `for` loops
+            self.emitted[header] = true;
+            out.push(Statement::Label(header));
+            self.contexts.push(Context::Loop {
+                                   index,
+                                   follow,
+                                   next: header,
+                               });
+            let mut condition = self.merged_condition(header, &merged);
+            if negated {
+                condition = condition.negate();
+            }
+            match self.increment(&l) {
+                Some(increment) => {
+                    // `continue` in `for` loops jumps to the increment
+                    if let Some(Context::Loop { next, .. }) = self.contexts.last_mut() {
+                        *next = increment;
+                    }
+                    let body = self.arm(inside, Some(increment));
+                    self.emitted[increment] = true;
+                    let mut statements = vec![];
+                    self.statements(increment, &mut statements);
+                    let increment: Vec<String> = statements
+                        .into_iter()
+                        .filter_map(|statement| match statement {
+                                        Statement::Simple(text) => Some(text),
+                                        _ => None,
+                                    })
+                        .collect();
+                    Statement::For(condition, increment.join(", "), body)
+                }
+                None => Statement::While(Some(condition), self.arm(inside, Some(header))),
+            }
+        } else if let Some((latch, negated)) = self.do_while_latch(&l, follow) {
+            self.contexts.push(Context::Loop {
+                                   index,
+                                   follow,
+                                   next: latch,
+                               });
+            let mut body = vec![];
+            if latch == header {
+                self.emitted[header] = true;
+                body.push(Statement::Label(header));
+                self.statements(header, &mut body);
+            } else {
+                body = self.sequence(header, Some(latch));
+                self.emitted[latch] = true;
+                body.push(Statement::Label(latch));
+            }
+            let mut condition = self.condition(latch);
+            if negated {
+                condition = condition.negate();
+            }
+            Statement::DoWhile(body, condition)
+        } else {
+            self.contexts.push(Context::Loop {
+                                   index,
+                                   follow,
+                                   next: header,
+                               });
+            let mut body = self.sequence(header, None);
+            if let Some(&Statement::Continue) = body.last() {
+                body.pop();
+            }
+            Statement::While(None, body)
+        };
+        self.contexts.pop();
+        out.push(statement);
+        follow.and_then(|follow| self.transition(follow, stop, out))
+    }
+
+    /// Returns the block with the increment of a `for` loop, i.e. a single
+    /// latch that is also the target of `continue` statements.
+    fn increment(&self, l: &Loop) -> Option<BlockId> {
+        let latch = match l.latches[..] {
+            [latch] if latch != l.header => latch,
+            _ => return None,
+        };
+        let block = &self.function.blocks[latch];
+        let is_simple = self.roots(latch).iter().all(|&value| {
+            matches!(self.function.values[value].op, Op::Store(..) | Op::Pop(_) | Op::Jump(_))
+        });
+        if block.predecessors.len() > 1 && block.phis.is_empty() && is_simple &&
+           block.successors.iter().all(|edge| edge.target == l.header) {
+            Some(latch)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the block with the condition of a `do`/`while` loop, and
+    /// whether the condition is negated.
+    fn do_while_latch(&self, l: &Loop, follow: Option<BlockId>) -> Option<(BlockId, bool)> {
+        let latch = match l.latches[..] {
+            [latch] => latch,
+            _ => return None,
+        };
+        if latch != l.header &&
+           (!self.is_condition(latch) || !self.post_dominators.dominates(latch, l.header)) {
+            return None;
+        }
+        match self.branch_targets(latch) {
+            Some((t, f)) if t == l.header && Some(f) == follow => Some((latch, false)),
+            Some((t, f)) if f == l.header && Some(t) == follow => Some((latch, true)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether two values load the same variable.
+    fn is_same_variable(&self, a: ValueId, b: ValueId) -> bool {
+        let address = |value: ValueId| match self.function.values[value].op {
+            Op::Load(4, address) => Some(self.function.values[address].op.clone()),
+            _ => None,
+        };
+        match (address(a), address(b)) {
+            (Some(Op::Local(a)), Some(Op::Local(b))) => a == b,
+            (Some(Op::Const(a)), Some(Op::Const(b))) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Returns the value of a `CONST`.
+    fn constant(&self, value: ValueId) -> Option<Literal> {
+        match self.function.values[value].op {
+            Op::Const(literal) => Some(literal),
+            _ => None,
+        }
+    }
+
+    /// Recognizes an LCC `switch` starting with the bounds check at the end of
+    /// `block`.
+    fn jump_table(&self, block: BlockId) -> Option<JumpTable> {
+        let values = &self.function.values;
+        let compare = |block: BlockId, opcodes: [Opcode; 2]| match self.terminator(block) {
+            Some(&Op::Branch(opcode, a, b, _)) if opcodes.contains(&opcode) => {
+                Some((a, self.constant(b)? as i32))
+            }
+            _ => None,
+        };
+        let (selector, min) = compare(block, [Opcode::LTI, Opcode::LTU])?;
+        let (default, upper) = self.branch_targets(block)?;
+        let (max_selector, max) = compare(upper, [Opcode::GTI, Opcode::GTU])?;
+        let (max_default, jump) = self.branch_targets(upper)?;
+        if max_default != default || !self.is_condition(upper) ||
+           self.function.blocks[upper].predecessors != [block] ||
+           self.function.blocks[jump].predecessors != [upper] ||
+           self.roots(jump).len() != 1 {
+            return None;
+        }
+
+        // `goto *(x << 2) + table`, where table is biased by the minimum
+        let address = match self.terminator(jump) {
+            Some(&Op::Jump(target)) => {
+                match values[target].op {
+                    Op::Load(4, address) => address,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        let (index, base) = match values[address].op {
+            Op::Binary(Opcode::ADD, a, b) => {
+                match (self.constant(a), self.constant(b)) {
+                    (None, Some(base)) => (a, base),
+                    (Some(base), None) => (b, base),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        let index = match values[index].op {
+            Op::Binary(Opcode::LSH, index, shift) if self.constant(shift) == Some(2) => index,
+            _ => return None,
+        };
+        if !self.is_same_variable(selector, max_selector) ||
+           !self.is_same_variable(selector, index) || max < min || max - min > 0x1000 {
+            return None;
+        }
+
+        let data = self.decompiler.qvm.data();
+        let mut cases = vec![];
+        for case in min..max + 1 {
+            let entry = base.wrapping_add((case as u32).wrapping_mul(4));
+            let target = match data.get(entry as usize / 4) {
+                Some(&target) if entry % 4 == 0 => target,
+                _ => return None,
+            };
+            match self.cfg.block_at(target) {
+                Some(block) if self.cfg.block(block).start() == target => {
+                    if block != default {
+                        cases.push((case, block));
+                    }
+                }
+                _ => return None,
+            }
+        }
+
+        Some(JumpTable {
+                 checks: [upper, jump],
+                 selector,
+                 cases,
+                 default,
+             })
+    }
+
+    fn structure_switch(&mut self,
+                        block: BlockId,
+                        table: JumpTable,
+                        stop: Option<BlockId>,
+                        out: &mut Vec<Statement>)
+                        -> Option<BlockId> {
+        self.statements(block, out);
+        for &check in &table.checks {
+            self.emitted[check] = true;
+        }
+        let selector = self.expr(table.selector).text;
+        let join = self.join(block);
+
+        let mut groups: BTreeMap<BlockId, Vec<String>> = BTreeMap::new();
+        for &(case, target) in &table.cases {
+            groups.entry(target).or_default().push(format!("case {}", case));
+        }
+        if Some(table.default) != join {
+            groups.entry(table.default).or_default().push("default".to_string());
+        }
+
+        self.contexts.push(Context::Switch { follow: join });
+        let targets: Vec<BlockId> = groups.keys().cloned().collect();
+        let mut cases = vec![];
+        for (i, (target, labels)) in groups.into_iter().enumerate() {
+            // Cases without `break` fall through into the next one
+            let next = targets.get(i + 1).cloned().or(join);
+            cases.push((labels, self.arm(target, next)));
+        }
+        self.contexts.pop();
+        out.push(Statement::Switch(selector, cases));
+        join.and_then(|join| self.transition(join, stop, out))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{decompile, Decompiler};
+    use assembler::assemble;
+    use cfg::Cfg;
+    use parser::{parse_qvm, parse_symbol_map};
+    use q3asm::{parse_syscalls, Assembler};
+    use QVM;
+
+    fn load(qvm: &[u8], map: &[u8]) -> QVM {
+        let mut qvm = parse_qvm(qvm).unwrap();
+        qvm.set_symbols(parse_symbol_map(map).unwrap());
+        qvm
+    }
+
+    fn decompile_source(source: &str) -> String {
+        let qvm = assemble("test", source).unwrap();
+        let cfgs = Cfg::for_qvm(&qvm);
+        Decompiler::new(&qvm).decompile_procedure(&cfgs[0]).unwrap()
+    }
+
+    #[test]
+    fn test_decompile_mods() {
+        let minimal = "int vmMain(void) {\n    return -1;\n}\n";
+        let mods: [(&[u8], &[u8], &str); 5] =
+            [(include_bytes!("../assets/mod-bss.qvm"),
+              include_bytes!("../assets/mod-bss.map"),
+              minimal),
+             (include_bytes!("../assets/mod-data.qvm"),
+              include_bytes!("../assets/mod-data.map"),
+              minimal),
+             (include_bytes!("../assets/mod-lit.qvm"),
+              include_bytes!("../assets/mod-lit.map"),
+              minimal),
+             (include_bytes!("../assets/mod-minimal.qvm"),
+              include_bytes!("../assets/mod-minimal.map"),
+              minimal),
+             (include_bytes!("../assets/mod-syscall.qvm"),
+              include_bytes!("../assets/mod-syscall.map"),
+              "int vmMain(void) {
+    trap_Print(\"Hello, world!\");
+    return -1;
+}
+")];
+        for &(qvm, map, expected) in &mods {
+            assert_eq!(decompile(&load(qvm, map)), expected);
+        }
+    }
+
+    #[test]
+    fn test_decompile_mod_control() {
+        // Without q3lcc, mod-control.asm was written by hand after mod-control.c,
+        // so this assembles it with our own q3asm instead of using a reference binary
+        let mut assembler = Assembler::new();
+        assembler.add_source("mod-control.asm", include_str!("../assets/mod-control.asm"));
+        assembler.add_source("syscalls.asm", include_str!("../assets/syscalls.asm"));
+        let qvm = assembler.assemble().unwrap();
+        assert_eq!(decompile(&qvm),
+                   "int vmMain(int arg0, int arg1) {
+    int local_12;
+
+    if (arg0 < 0) {
+        trap_Print(\"negative\");
+        return -1;
+    } else {
+        local_12 = 0;
+        while (local_12 < arg1) {
+            local_12 = local_12 + arg0;
+        }
+        switch (arg0) {
+        case 0:
+            trap_Print(\"zero\");
+            break;
+        case 1:
+            trap_Print(\"one\");
+            break;
+        case 2:
+            trap_Print(\"two\");
+            break;
+        case 3:
+            trap_Print(\"three\");
+        }
+        return local_12;
+    }
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_syscall_table() {
+        let qvm = parse_qvm(include_bytes!("../assets/mod-syscall.qvm")).unwrap();
+        assert!(decompile(&qvm).contains("    syscall_665(\"Hello, world!\");\n"));
+
+        let syscalls = parse_syscalls("syscalls.asm", include_str!("../assets/syscalls.asm"))
+            .unwrap();
+        let mut decompiler = Decompiler::new(&qvm);
+        decompiler.set_syscalls(&syscalls);
+        let mut output = vec![];
+        decompiler.write(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "int L0(void) {
+    trap_Print(\"Hello, world!\");
+    return -1;
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_if_else() {
+        let code = decompile_source("
+                    ENTER 16
+                    LOCAL 24
+                    LOAD4
+                    CONST 0
+                    LEI else
+                    LOCAL 4
+                    CONST 1
+                    STORE4
+                    CONST done
+                    JUMP
+            else:   LOCAL 4
+                    LOCAL 28
+                    LOAD4
+                    NEGI
+                    STORE4
+            done:   LOCAL 4
+                    LOAD4
+                    LEAVE 16
+        ");
+        assert_eq!(code,
+                   "int L0(int arg0, int arg1) {
+    int local_4;
+
+    if (arg0 > 0) {
+        local_4 = 1;
+    } else {
+        local_4 = -arg1;
+    }
+    return local_4;
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_typed_temporaries() {
+        // return x ? f + 1.0 : (float)x;
+        let code = decompile_source("
+                    ENTER 16
+                    LOCAL 24
+                    LOAD4
+                    CONST 0
+                    EQ else
+                    LOCAL 4
+                    LOAD4
+                    CONST 1.0
+                    ADDF
+                    CONST done
+                    JUMP
+            else:   LOCAL 24
+                    LOAD4
+                    CVIF
+            done:   LEAVE 16
+        ");
+        assert_eq!(code,
+                   "float L0(int arg0) {
+    float local_4;
+    float t7;
+
+    if (arg0 != 0) {
+        t7 = local_4 + 1.0;
+    } else {
+        t7 = (float)arg0;
+    }
+    return t7;
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_loops() {
+        // while (x < 10) { if (y) break; x++; } do { x--; } while (x);
+        let code = decompile_source("
+                    ENTER 16
+                    CONST cond
+                    JUMP
+            body:   LOCAL 8
+                    LOAD4
+                    CONST 0
+                    NE out
+                    LOCAL 4
+                    LOCAL 4
+                    LOAD4
+                    CONST 1
+                    ADD
+                    STORE4
+            cond:   LOCAL 4
+                    LOAD4
+                    CONST 10
+                    LTI body
+            out:    LOCAL 4
+                    LOCAL 4
+                    LOAD4
+                    CONST 1
+                    SUB
+                    STORE4
+                    LOCAL 4
+                    LOAD4
+                    CONST 0
+                    NE out
+                    PUSH
+                    LEAVE 16
+        ");
+        assert_eq!(code,
+                   "void L0(void) {
+    int local_4;
+    int local_8;
+
+    while (local_4 < 10 && local_8 == 0) {
+        local_4 = local_4 + 1;
+    }
+    do {
+        local_4 = local_4 - 1;
+    } while (local_4 != 0);
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_switch() {
+        let mut assembler = Assembler::new();
+        assembler.add_source("switch.asm",
+                             "export f
+code
+proc f 4 4
+ADDRLP4 0
+ADDRFP4 0
+INDIRI4
+ASGNI4
+ADDRLP4 0
+INDIRI4
+CNSTI4 1
+LTI4 $3
+ADDRLP4 0
+INDIRI4
+CNSTI4 3
+GTI4 $3
+ADDRLP4 0
+INDIRI4
+CNSTI4 2
+LSHI4
+ADDRGP4 $6-4
+ADDP4
+INDIRP4
+JUMPV
+LABELV $6
+address $4
+address $5
+address $4
+code
+LABELV $4
+CNSTI4 10
+RETI4
+LABELV $5
+ADDRGP4 $7
+ARGP4
+ADDRGP4 g
+CALLV
+pop
+LABELV $3
+CNSTI4 0
+RETI4
+LABELV $2
+endproc f 4 4
+export g
+proc g 0 0
+LABELV $8
+endproc g 0 0
+lit
+align 1
+LABELV $7
+byte 1 37
+byte 1 115
+byte 1 0
+");
+        let qvm = assembler.assemble().unwrap();
+        let cfgs = Cfg::for_qvm(&qvm);
+        let code = Decompiler::new(&qvm).decompile_procedure(&cfgs[0]).unwrap();
+        assert_eq!(code,
+                   "int f(int arg0) {
+    int local_12;
+
+    local_12 = arg0;
+    switch (local_12) {
+    case 1:
+    case 3:
+        return 10;
+    case 2:
+        g(\"%s\");
+    default:
+        return 0;
+    }
+}
+");
+    }
+
+    #[test]
+    fn test_decompile_ioq3_qagame() {
+        let qvm = load(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm"),
+                       include_bytes!("../assets/ioq3/baseq3/vm/qagame.map"));
+        let code = decompile(&qvm);
+        assert!(code.contains("void G_Printf(void *arg0, "));
+        assert!(code.contains("void Q_strncpyz(void *arg0, void *arg1, int arg2) {"));
+        assert!(code.contains("float VectorNormalize(void *arg0) {\n    float local_12;\n"));
+        assert!(code.contains("trap_Print("));
+        assert!(code.contains("switch (local_20) {\n    case 0:\n"));
+        assert!(code.contains("while ("));
+        assert!(!code.contains("/* "));
+    }
+}
@@ -0,0 +1,424 @@
+//! Disassembler for the textual representation of QVM code.
+//!
+//! Each instruction is written on its own line as
+//!
+//! ```text
+//!      3  0x0000000c  CONST -666  ; trap_Print
+//! ```
+//!
+//! i.e. instruction index, octet offset within the code segment, mnemonic,
+//! operand and an optional comment. Branch and call targets are labels, which
+//! are either symbol names or generated as `L<index>`.
+//!
+//! With types recovered by `types`, procedures are annotated with their
+//! signature, and frame slots and globals with their type:
+//!
+//! ```text
+//! ; procedure float (void *), frame size 44
+//! VectorNormalize:
+//!      ...
+//!      4  0x00000012  LOCAL 24  ; float
+//! ```
+//!
+//! The other segments are written as directives, which `assembler` accepts:
+//!
+//! ```text
+//! .version 1
+//! .bss 65536
+//! .data 0x00000000 0xdeadbeef
+//! .lit 0x48 0x65 0x6c 0x6c 0x6f 0x00
+//! .jtrg L21 L37
+//! ```
+
+use super::{QVM, Segment, Version};
+use bytecode::{Address, Instruction, LiteralKind, TypedLiteral};
+use cfg::{procedures, Procedure};
+use types::{Location, Types};
+use super::errors::*;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Returns the NUL-terminated string at `address` in the LIT segment, if it
+/// looks like text.
+pub fn lit_string(lit: &[u8], address: Address) -> Option<String> {
+    let address = address as usize;
+    if address > 0 && lit.get(address - 1) != Some(&0) {
+        // Points into the middle of a string
+        return None;
+    }
+    let bytes = lit.get(address..)?;
+    let length = bytes.iter().position(|&b| b == 0)?;
+    let bytes = &bytes[..length];
+    let printable = |b: &u8| *b == b'\t' || *b == b'\n' || (0x20..0x7f).contains(b);
+    if bytes.is_empty() || !bytes.iter().all(printable) {
+        return None;
+    }
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// A disassembler for the code of a VM.
+///
+/// Symbol names are taken from the symbol map attached to the VM, if any.
+pub struct Disassembler<'a> {
+    qvm: &'a QVM,
+    labels: HashMap<Address, String>,
+    types: Option<&'a Types>,
+    procedures: Vec<Procedure>,
+}
+
+impl<'a> Disassembler<'a> {
+    /// Creates a new disassembler, collecting the labels of all procedures
+    /// and jump targets.
+    pub fn new(qvm: &'a QVM) -> Disassembler<'a> {
+        let code = qvm.instructions();
+        let mut targets = vec![];
+        for (index, instruction) in code.iter().enumerate() {
+            match *instruction {
+                Instruction::ENTER(_) => targets.push(index as Address),
+                Instruction::CONST(target) => {
+                    match code.get(index + 1) {
+                        Some(&Instruction::CALL) |
+                        Some(&Instruction::JUMP) if (target as i32) >= 0 => targets.push(target),
+                        _ => {}
+                    }
+                }
+                _ => {
+                    if let Some(target) = instruction.branch_target() {
+                        targets.push(target);
+                    }
+                }
+            }
+        }
+        if let Some(jtrg) = qvm.jump_targets() {
+            targets.extend(jtrg.iter().cloned());
+        }
+
+        let mut labels = HashMap::new();
+        for target in targets {
+            if (target as usize) < code.len() {
+                labels
+                    .entry(target)
+                    .or_insert_with(|| match qvm.symbol_name(Segment::CODE, target) {
+                                        Some(name) => name.to_string(),
+                                        None => format!("L{}", target),
+                                    });
+            }
+        }
+
+        Disassembler {
+            qvm,
+            labels,
+            types: None,
+            procedures: vec![],
+        }
+    }
+
+    /// Returns the disassembled VM.
+    pub fn qvm(&self) -> &'a QVM {
+        self.qvm
+    }
+
+    /// Sets the recovered types to annotate procedures, frame slots and
+    /// globals with.
+    pub fn set_types(&mut self, types: &'a Types) {
+        self.types = Some(types);
+        self.procedures = procedures(self.qvm.instructions());
+    }
+
+    /// Returns the procedure containing the instruction at `index`.
+    fn procedure(&self, index: Address) -> Option<&Procedure> {
+        let position = match self.procedures.binary_search_by_key(&index, Procedure::start) {
+            Ok(position) => position,
+            Err(0) => return None,
+            Err(position) => position - 1,
+        };
+        Some(&self.procedures[position]).filter(|procedure| procedure.contains(index))
+    }
+
+    /// Returns the label of the instruction at `index`, if it is a procedure or
+    /// jump target.
+    pub fn label(&self, index: Address) -> Option<&str> {
+        self.labels.get(&index).map(String::as_str)
+    }
+
+    /// Returns the operand and comment of the instruction at `index`.
+    fn operand(&self, index: usize) -> (Option<String>, Option<String>) {
+        let code = self.qvm.instructions();
+        let instruction = code[index];
+        if let Some(target) = instruction.branch_target() {
+            let operand = match self.label(target) {
+                Some(label) => label.to_string(),
+                None => target.to_string(),
+            };
+            return (Some(operand), None);
+        }
+        if let (Instruction::LOCAL(offset), Some(types)) = (instruction, self.types) {
+            let ty = self.procedure(index as Address)
+                .and_then(|procedure| {
+                              types.get(Location::slot(procedure.start(),
+                                                       procedure.frame_size(),
+                                                       offset))
+                          });
+            return (Some(offset.to_string()), ty.map(|ty| ty.to_string()));
+        }
+        let value = match instruction.literal() {
+            Some(value) => value,
+            None => return (instruction.operand().map(|x| x.to_string()), None),
+        };
+
+        match LiteralKind::infer(code, index) {
+            LiteralKind::CodeAddress if (value as i32) < 0 => {
+                let comment = self.qvm
+                    .symbols()
+                    .and_then(|symbols| symbols.syscall_name(value as i32))
+                    .map(str::to_string);
+                (Some((value as i32).to_string()), comment)
+            }
+            LiteralKind::CodeAddress => {
+                let operand = match self.label(value) {
+                    Some(label) => label.to_string(),
+                    None => value.to_string(),
+                };
+                (Some(operand), None)
+            }
+            kind => {
+                let operand = TypedLiteral::new(value, kind).to_string();
+                let comment = match self.qvm.segment_address(value) {
+                    Some((segment, address)) => {
+                        match self.qvm.symbol_name(segment, address) {
+                            Some(name) => Some(name.to_string()),
+                            None if segment == Segment::LIT => {
+                                lit_string(self.qvm.lit(), address).map(|s| format!("{:?}", s))
+                            }
+                            None => None,
+                        }
+                    }
+                    None => None,
+                };
+                let ty = self.types.and_then(|types| types.get(Location::Global(value)));
+                let comment = match (comment, ty) {
+                    (Some(comment), Some(ty)) => Some(format!("{}: {}", comment, ty)),
+                    (None, Some(ty)) => Some(ty.to_string()),
+                    (comment, None) => comment,
+                };
+                (Some(operand), comment)
+            }
+        }
+    }
+
+    /// Returns the mnemonic, operand and comment of the instruction at
+    /// `index`, e.g. `CONST -666  ; trap_Print`.
+    pub fn instruction(&self, index: usize) -> String {
+        let mut line = self.qvm.instructions()[index].opcode().name().to_string();
+        let (operand, comment) = self.operand(index);
+        if let Some(operand) = operand {
+            line.push(' ');
+            line.push_str(&operand);
+        }
+        if let Some(comment) = comment {
+            line.push_str("  ; ");
+            line.push_str(&comment);
+        }
+        line
+    }
+
+    /// Writes the version and the DATA, LIT, BSS and JTRG segments as
+    /// directives.
+    ///
+    /// # Errors
+    /// Returns `Error::Io` if writing fails.
+    pub fn write_segments<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let version = match self.qvm.version() {
+            Version::V1 => 1,
+            Version::V2 => 2,
+        };
+        writeln!(writer, ".version {}", version)?;
+        writeln!(writer, ".bss {}", self.qvm.bss_length())?;
+        for words in self.qvm.data().chunks(8) {
+            let words: Vec<_> = words.iter().map(|word| format!("{:#010x}", word)).collect();
+            writeln!(writer, ".data {}", words.join(" "))?;
+        }
+        for bytes in self.qvm.lit().chunks(16) {
+            let bytes: Vec<_> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+            writeln!(writer, ".lit {}", bytes.join(" "))?;
+        }
+        if let Some(jtrg) = self.qvm.jump_targets() {
+            for targets in jtrg.chunks(8) {
+                let targets: Vec<_> = targets
+                    .iter()
+                    .map(|&target| match self.label(target) {
+                             Some(label) => label.to_string(),
+                             None => target.to_string(),
+                         })
+                    .collect();
+                writeln!(writer, ".jtrg {}", targets.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the disassembly of all instructions.
+    ///
+    /// # Errors
+    /// Returns `Error::Io` if writing fails.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut offset = 0;
+        for (index, instruction) in self.qvm.instructions().iter().enumerate() {
+            if let Instruction::ENTER(frame_size) = *instruction {
+                writeln!(writer)?;
+                match self.types {
+                    Some(types) => {
+                        let start = index as Address;
+                        let returns = types.returns(start)
+                            .map_or("void".to_string(), |ty| ty.to_string());
+                        let params: Vec<String> = types.params(start)
+                            .iter()
+                            .map(|ty| ty.to_string())
+                            .collect();
+                        writeln!(writer,
+                                 "; procedure {} ({}), frame size {}",
+                                 returns,
+                                 params.join(", "),
+                                 frame_size)?
+                    }
+                    None => writeln!(writer, "; procedure, frame size {}", frame_size)?,
+                }
+            }
+            if let Some(label) = self.label(index as Address) {
+                writeln!(writer, "{}:", label)?;
+            }
+
+            writeln!(writer, "{:6}  {:#010x}  {}", index, offset, self.instruction(index))?;
+
+            offset += instruction.size();
+        }
+        Ok(())
+    }
+}
+
+/// Disassembles a VM into a string, with segment directives followed by code.
+pub fn disassemble(qvm: &QVM) -> String {
+    let disassembler = Disassembler::new(qvm);
+    let mut output = vec![];
+    disassembler
+        .write_segments(&mut output)
+        .and_then(|_| disassembler.write(&mut output))
+        .expect("writing to a Vec does not fail");
+    String::from_utf8(output).expect("disassembly is UTF-8")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, Disassembler};
+    use assembler::assemble;
+    use bytecode::Instruction;
+    use parser::{parse_qvm, parse_symbol_map};
+    use types::Types;
+    use QVM;
+
+    #[test]
+    fn test_disassemble_file_syscall() {
+        let mut qvm = parse_qvm(include_bytes!("../assets/mod-syscall.qvm")).unwrap();
+        qvm.set_symbols(parse_symbol_map(include_bytes!("../assets/mod-syscall.map")).unwrap());
+        let expected = ".version 1
+.bss 65536
+.data 0x00000000
+.lit 0x48 0x65 0x6c 0x6c 0x6f 0x2c 0x20 0x77 0x6f 0x72 0x6c 0x64 0x21 0x00 0x00 0x00
+
+; procedure, frame size 12
+vmMain:
+     0  0x00000000  ENTER 12
+     1  0x00000005  CONST 4  ; \"Hello, world!\"
+     2  0x0000000a  ARG 8
+     3  0x0000000c  CONST -666  ; trap_Print
+     4  0x00000011  CALL
+     5  0x00000012  POP
+     6  0x00000013  CONST -1
+     7  0x00000018  LEAVE 12
+     8  0x0000001d  PUSH
+     9  0x0000001e  LEAVE 12
+";
+        assert_eq!(disassemble(&qvm), expected);
+    }
+
+    #[test]
+    fn test_disassemble_labels() {
+        let code = vec![Instruction::ENTER(8),
+                        Instruction::CONST(5),
+                        Instruction::CALL,
+                        Instruction::CONST(0),
+                        Instruction::CONST(0),
+                        Instruction::EQ(3),
+                        Instruction::ENTER(8),
+                        Instruction::CONST(0),
+                        Instruction::LOAD4,
+                        Instruction::const_f32(1.5),
+                        Instruction::MULF,
+                        Instruction::LEAVE(8)];
+        let qvm = QVM::new(code, vec![0], vec![], 0).unwrap();
+        let disassembler = Disassembler::new(&qvm);
+        assert_eq!(disassembler.label(0), Some("L0"));
+        assert_eq!(disassembler.label(3), Some("L3"));
+        assert_eq!(disassembler.label(4), None);
+        assert_eq!(disassembler.label(5), Some("L5"));
+        let disassembly = disassemble(&qvm);
+        assert!(disassembly.contains("     1  0x00000005  CONST L5\n"));
+        assert!(disassembly.contains("L3:\n     3  0x0000000b  CONST 0\n"));
+        assert!(disassembly.contains("     5  0x00000015  EQ L3\n"));
+        assert!(disassembly.contains("     7  0x0000001f  CONST 0x00000000\n"));
+        assert!(disassembly.contains("     9  0x00000025  CONST 1.5\n"));
+    }
+
+    #[test]
+    fn test_disassemble_types() {
+        let qvm = assemble("test",
+                           "
+            .data 0 0
+            vmMain:
+                    ENTER 16
+                    CONST 4
+                    LOCAL 12
+                    LOAD4
+                    CVIF
+                    ARG 8
+                    CONST scale
+                    CALL
+                    STORE4
+                    PUSH
+                    LEAVE 16
+            scale:
+                    ENTER 8
+                    LOCAL 16
+                    LOAD4
+                    CONST 2.0
+                    MULF
+                    LEAVE 8
+        ")
+                .unwrap();
+        let types = Types::for_qvm(&qvm);
+        let mut disassembler = Disassembler::new(&qvm);
+        disassembler.set_types(&types);
+        let mut output = vec![];
+        disassembler.write(&mut output).unwrap();
+        let disassembly = String::from_utf8(output).unwrap();
+        assert!(disassembly.contains("; procedure void (), frame size 16\n"));
+        assert!(disassembly.contains("     1  0x00000005  CONST 4  ; float\n"));
+        assert!(disassembly.contains("     2  0x0000000a  LOCAL 12  ; int\n"));
+        assert!(disassembly.contains("; procedure float (float), frame size 8\n"));
+        assert!(disassembly.contains("    12  0x00000025  LOCAL 16  ; float\n"));
+    }
+
+    #[test]
+    fn test_disassemble_ioq3_qagame() {
+        let mut qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        qvm.set_symbols(parse_symbol_map(include_bytes!("../assets/ioq3/baseq3/vm/qagame.map"))
+                            .unwrap());
+        let disassembly = disassemble(&qvm);
+        let instructions = disassembly.lines()
+            .filter(|line| line.starts_with(|c: char| c == ' ' || c.is_ascii_digit()));
+        assert_eq!(instructions.count(), 0x24d44);
+        assert!(disassembly.contains("\nG_InitGame:\n"));
+        assert!(disassembly.contains("; trap_Print\n"));
+    }
+}
@@ -0,0 +1,260 @@
+//! Dominator and post-dominator trees of control-flow graphs.
+//!
+//! A block dominates another if every path from the entry to the latter passes
+//! through the former. Post-dominance is the same on the reversed graph, from
+//! the exits of the procedure, i.e. the blocks without successors.
+//!
+//! This uses the iterative algorithm of Cooper, Harvey and Kennedy, "A Simple,
+//! Fast Dominance Algorithm".
+
+use cfg::{BlockId, Cfg};
+
+/// The (post-)dominator tree of a control-flow graph.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Dominators {
+    idoms: Vec<Option<BlockId>>,
+    reachable: Vec<bool>,
+}
+
+/// Returns the nodes reachable from `root` in reverse postorder.
+fn reverse_postorder(successors: &[Vec<usize>], root: usize) -> Vec<usize> {
+    let mut visited = vec![false; successors.len()];
+    let mut postorder = vec![];
+    let mut visits = vec![(root, 0)];
+    visited[root] = true;
+    while let Some(&mut (node, ref mut next)) = visits.last_mut() {
+        match successors[node].get(*next) {
+            Some(&successor) => {
+                *next += 1;
+                if !visited[successor] {
+                    visited[successor] = true;
+                    visits.push((successor, 0));
+                }
+            }
+            None => {
+                postorder.push(node);
+                visits.pop();
+            }
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+impl Dominators {
+    /// Computes the dominators of a graph with the given edges.
+    ///
+    /// A virtual root node is added as predecessor of all `roots`, so
+    /// `roots` have no immediate dominator.
+    fn compute(successors: &[Vec<BlockId>], roots: &[BlockId]) -> Dominators {
+        let count = successors.len();
+        let root = count;
+        let mut successors = successors.to_vec();
+        successors.push(roots.to_vec());
+        let mut predecessors = vec![vec![]; count + 1];
+        for (node, targets) in successors.iter().enumerate() {
+            for &target in targets {
+                predecessors[target].push(node);
+            }
+        }
+
+        let order = reverse_postorder(&successors, root);
+        let mut numbers = vec![usize::MAX; count + 1];
+        for (number, &node) in order.iter().enumerate() {
+            numbers[node] = number;
+        }
+
+        let mut idoms: Vec<Option<usize>> = vec![None; count + 1];
+        idoms[root] = Some(root);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in &order[1..] {
+                let mut new_idom = None;
+                for &predecessor in &predecessors[node] {
+                    if idoms[predecessor].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                                        None => predecessor,
+                                        Some(mut other) => {
+                                            let mut finger = predecessor;
+                                            while finger != other {
+                                                while numbers[finger] > numbers[other] {
+                                                    finger = idoms[finger].expect("processed");
+                                                }
+                                                while numbers[other] > numbers[finger] {
+                                                    other = idoms[other].expect("processed");
+                                                }
+                                            }
+                                            finger
+                                        }
+                                    });
+                }
+                if new_idom.is_some() && idoms[node] != new_idom {
+                    idoms[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        Dominators {
+            reachable: (0..count).map(|node| idoms[node].is_some()).collect(),
+            idoms: idoms[..count]
+                .iter()
+                .map(|&idom| idom.filter(|&idom| idom != root))
+                .collect(),
+        }
+    }
+
+    /// Computes the dominator tree of a procedure, rooted at its entry block.
+    pub fn new(cfg: &Cfg) -> Dominators {
+        let successors: Vec<Vec<BlockId>> = (0..cfg.blocks().len())
+            .map(|id| cfg.successors(id).iter().map(|edge| edge.target).collect())
+            .collect();
+        Dominators::compute(&successors, &[0])
+    }
+
+    /// Computes the post-dominator tree of a procedure, rooted at its exits.
+    ///
+    /// Blocks that never reach an exit, e.g. in infinite loops, are not
+    /// reachable.
+    pub fn post_dominators(cfg: &Cfg) -> Dominators {
+        let count = cfg.blocks().len();
+        let predecessors: Vec<Vec<BlockId>> = (0..count)
+            .map(|id| cfg.predecessors(id).to_vec())
+            .collect();
+        let exits: Vec<BlockId> = (0..count)
+            .filter(|&id| cfg.successors(id).is_empty())
+            .collect();
+        Dominators::compute(&predecessors, &exits)
+    }
+
+    /// Returns whether a block is reachable from the root, i.e. whether the
+    /// other methods are meaningful for it.
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.reachable[block]
+    }
+
+    /// Returns the closest strict dominator of a block.
+    ///
+    /// This is `None` for the entry block, or the exits for post-dominators,
+    /// and unreachable blocks.
+    pub fn immediate_dominator(&self, block: BlockId) -> Option<BlockId> {
+        self.idoms[block]
+    }
+
+    /// Returns the dominators of a block, starting with the block itself and
+    /// ending with a root.
+    pub fn dominators(&self, block: BlockId) -> Vec<BlockId> {
+        if !self.is_reachable(block) {
+            return vec![];
+        }
+        let mut dominators = vec![block];
+        while let Some(idom) = self.idoms[*dominators.last().expect("not empty")] {
+            dominators.push(idom);
+        }
+        dominators
+    }
+
+    /// Returns whether `dominator` dominates `block`.
+    ///
+    /// Every reachable block dominates itself.
+    pub fn dominates(&self, dominator: BlockId, block: BlockId) -> bool {
+        self.dominators(block).contains(&dominator)
+    }
+
+    /// Returns the blocks immediately dominated by `block`, i.e. its children
+    /// in the dominator tree.
+    pub fn children(&self, block: BlockId) -> Vec<BlockId> {
+        (0..self.idoms.len())
+            .filter(|&child| self.idoms[child] == Some(block))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::Dominators;
+    use assembler::assemble;
+    use cfg::Cfg;
+
+    const NESTED_LOOPS: &str = "
+        vmMain:
+                ENTER 16
+        outer:  LOCAL 8
+                LOAD4
+                CONST 0
+                EQ done
+        inner:  LOCAL 12
+                LOAD4
+                CONST 0
+                EQ next
+                CONST inner
+                JUMP
+        next:   CONST outer
+                JUMP
+        done:   CONST 0
+                LEAVE 16
+    ";
+
+    #[test]
+    fn test_dominators() {
+        let qvm = assemble("test", NESTED_LOOPS).unwrap();
+        let cfg = &Cfg::for_qvm(&qvm)[0];
+        assert_eq!(cfg.blocks().len(), 6);
+        let dominators = Dominators::new(cfg);
+        let idoms: Vec<_> = (0..6)
+            .map(|block| dominators.immediate_dominator(block))
+            .collect();
+        assert_eq!(idoms, vec![None, Some(0), Some(1), Some(2), Some(2), Some(1)]);
+        assert_eq!(dominators.dominators(4), vec![4, 2, 1, 0]);
+        assert!(dominators.dominates(1, 5));
+        assert!(dominators.dominates(3, 3));
+        assert!(!dominators.dominates(3, 4));
+        assert_eq!(dominators.children(2), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_post_dominators() {
+        let qvm = assemble("test", NESTED_LOOPS).unwrap();
+        let cfg = &Cfg::for_qvm(&qvm)[0];
+        let post_dominators = Dominators::post_dominators(cfg);
+        let ipdoms: Vec<_> = (0..6)
+            .map(|block| post_dominators.immediate_dominator(block))
+            .collect();
+        assert_eq!(ipdoms, vec![Some(1), Some(5), Some(4), Some(2), Some(1), None]);
+        assert!(post_dominators.dominates(5, 0));
+        assert!(!post_dominators.dominates(3, 2));
+    }
+
+    #[test]
+    fn test_post_dominators_infinite_loop() {
+        let qvm = assemble("test", "ENTER 8\nloop: CONST loop\nJUMP\n").unwrap();
+        let cfg = &Cfg::for_qvm(&qvm)[0];
+        let dominators = Dominators::new(cfg);
+        assert_eq!(dominators.immediate_dominator(1), Some(0));
+        let post_dominators = Dominators::post_dominators(cfg);
+        assert!(!post_dominators.is_reachable(0));
+        assert!(!post_dominators.is_reachable(1));
+        assert_eq!(post_dominators.dominators(1), vec![]);
+    }
+
+    #[test]
+    fn test_dominators_ioq3_qagame() {
+        use parser::parse_qvm;
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        for cfg in &Cfg::for_qvm(&qvm) {
+            let dominators = Dominators::new(cfg);
+            for block in 1..cfg.blocks().len() {
+                if let Some(idom) = dominators.immediate_dominator(block) {
+                    assert!(cfg.predecessors(block)
+                                .iter()
+                                .all(|&p| !dominators.is_reachable(p) ||
+                                          dominators.dominates(idom, p)));
+                }
+            }
+        }
+    }
+}
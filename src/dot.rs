@@ -0,0 +1,184 @@
+//! Export of control-flow and call graphs in the Graphviz DOT language.
+//!
+//! This only writes the textual graph description, which can be rendered with
+//! e.g. `dot -Tsvg`, so it does not need any graphical environment.
+
+use super::{QVM, Segment};
+use bytecode::Address;
+use callgraph::{CallGraph, Callee};
+use cfg::{Cfg, EdgeKind};
+use disassembler::Disassembler;
+use super::errors::*;
+use std::io::Write;
+
+/// Escapes a string for use within a quoted DOT ID.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Returns the name of the procedure at `address`.
+fn procedure_name(qvm: &QVM, address: Address) -> String {
+    match qvm.symbol_name(Segment::CODE, address) {
+        Some(name) => name.to_string(),
+        None => format!("L{}", address),
+    }
+}
+
+/// Writes the basic blocks of a procedure as a DOT graph.
+///
+/// Every block is a node listing its disassembled instructions, and edges of
+/// conditional branches are labeled `true` and `false`. Creating the
+/// `Disassembler` scans the whole code segment, so reuse it for all
+/// procedures of a VM.
+///
+/// # Errors
+/// Returns `Error::Io` if writing fails.
+pub fn write_cfg<W: Write>(disassembler: &Disassembler, cfg: &Cfg, writer: &mut W) -> Result<()> {
+    let name = procedure_name(disassembler.qvm(), cfg.procedure().start());
+    writeln!(writer, "digraph \"{}\" {{", escape(&name))?;
+    writeln!(writer, "    node [shape=box, fontname=\"monospace\"];")?;
+    for (id, block) in cfg.blocks().iter().enumerate() {
+        let mut label = String::new();
+        if let Some(name) = disassembler.label(block.start()) {
+            label.push_str(&escape(name));
+            label.push_str(":\\l");
+        }
+        for index in block.start()..block.end() {
+            let line = format!("{:6}  {}", index, disassembler.instruction(index as usize));
+            label.push_str(&escape(&line));
+            label.push_str("\\l");
+        }
+        writeln!(writer, "    b{} [label=\"{}\"];", id, label)?;
+    }
+    for id in 0..cfg.blocks().len() {
+        for edge in cfg.successors(id) {
+            let attributes = match edge.kind {
+                EdgeKind::True => " [label=\"true\", color=\"darkgreen\"]",
+                EdgeKind::False => " [label=\"false\", color=\"red\"]",
+                EdgeKind::Indirect => " [style=\"dashed\"]",
+                EdgeKind::Fallthrough | EdgeKind::Jump => "",
+            };
+            writeln!(writer, "    b{} -> b{}{};", id, edge.target, attributes)?;
+        }
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+/// Writes the call graph of all procedures as a DOT graph.
+///
+/// Nodes are named after the symbol map attached to the VM, if any. Only
+/// direct calls of `CONST` addresses are included.
+///
+/// # Errors
+/// Returns `Error::Io` if writing fails.
+pub fn write_call_graph<W: Write>(qvm: &QVM, writer: &mut W) -> Result<()> {
+    let graph = CallGraph::for_qvm(qvm);
+    writeln!(writer, "digraph \"calls\" {{")?;
+    writeln!(writer, "    node [shape=box];")?;
+    for procedure in graph.procedures() {
+        writeln!(writer,
+                 "    p{} [label=\"{}\"];",
+                 procedure.start(),
+                 escape(&procedure_name(qvm, procedure.start())))?;
+    }
+    for procedure in graph.procedures() {
+        for callee in graph.callees(procedure.start()) {
+            if let Callee::Procedure(callee) = callee {
+                writeln!(writer, "    p{} -> p{};", procedure.start(), callee)?;
+            }
+        }
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, write_call_graph, write_cfg};
+    use bytecode::Instruction;
+    use cfg::Cfg;
+    use disassembler::Disassembler;
+    use parser::{parse_qvm, parse_symbol_map};
+    use QVM;
+
+    fn test_qvm() -> QVM {
+        let code = vec![Instruction::ENTER(8),
+                        Instruction::LOCAL(16),
+                        Instruction::LOAD4,
+                        Instruction::CONST(0),
+                        Instruction::EQ(7),
+                        Instruction::CONST(9),
+                        Instruction::CALL,
+                        Instruction::CONST(1),
+                        Instruction::LEAVE(8),
+                        Instruction::ENTER(8),
+                        Instruction::LEAVE(8)];
+        QVM::new(code, vec![0], vec![], 0).unwrap()
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(r#"CONST 4  ; "a\"b""#), r#"CONST 4  ; \"a\\\"b\""#);
+    }
+
+    #[test]
+    fn test_write_cfg() {
+        let qvm = test_qvm();
+        let cfgs = Cfg::for_qvm(&qvm);
+        let mut output = vec![];
+        write_cfg(&Disassembler::new(&qvm), &cfgs[0], &mut output).unwrap();
+        let expected = r#"digraph "L0" {
+    node [shape=box, fontname="monospace"];
+    b0 [label="L0:\l     0  ENTER 8\l     1  LOCAL 16\l     2  LOAD4\l     3  CONST 0\l     4  EQ L7\l"];
+    b1 [label="     5  CONST L9\l     6  CALL\l"];
+    b2 [label="L7:\l     7  CONST 1\l     8  LEAVE 8\l"];
+    b0 -> b2 [label="true", color="darkgreen"];
+    b0 -> b1 [label="false", color="red"];
+    b1 -> b2;
+}
+"#;
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_call_graph() {
+        let qvm = test_qvm();
+        let mut output = vec![];
+        write_call_graph(&qvm, &mut output).unwrap();
+        let expected = r#"digraph "calls" {
+    node [shape=box];
+    p0 [label="L0"];
+    p9 [label="L9"];
+    p0 -> p9;
+}
+"#;
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_write_ioq3_qagame() {
+        let mut qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        qvm.set_symbols(parse_symbol_map(include_bytes!("../assets/ioq3/baseq3/vm/qagame.map"))
+                            .unwrap());
+        let mut output = vec![];
+        write_call_graph(&qvm, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("p0 [label=\"vmMain\"];\n"));
+        assert!(output.ends_with("}\n"));
+
+        let disassembler = Disassembler::new(&qvm);
+        let outputs: Vec<_> = Cfg::for_qvm(&qvm)
+            .iter()
+            .map(|cfg| {
+                     let mut output = vec![];
+                     write_cfg(&disassembler, cfg, &mut output).unwrap();
+                     String::from_utf8(output).unwrap()
+                 })
+            .collect();
+        assert!(outputs[0].starts_with("digraph \"vmMain\" {\n"));
+        assert!(outputs[0].contains("[label=\"true\""));
+        assert!(outputs.iter().all(|output| output.ends_with("}\n")));
+    }
+}
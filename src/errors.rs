@@ -1,13 +1,75 @@
 //! `Error` and `Result` types of this crate.
 
-use nom;
-
-error_chain!{
-    errors {
-        #[doc="A parsing error from `nom`."]
-        Parser(e: nom::IError) {
-            description("parsing error")
-            display("parsing error: {:?}", e)
+use parser::ParseError;
+use std::error;
+use std::fmt;
+use std::io;
+use validation::Violation;
+
+/// An error of this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O error while writing.
+    Io(io::Error),
+    /// An error while parsing a file.
+    Parser(ParseError),
+    /// A VM that violates semantic rules.
+    Invalid(Vec<Violation>),
+    /// An error in an assembler source file and line.
+    Assembler(String, usize, String),
+    /// An error while executing a VM.
+    Interpreter(String),
+    /// A system call without implementation.
+    UnknownSyscall(i32),
+    /// An error in the implementation of a system call.
+    Syscall(String),
+}
+
+/// The result of fallible operations of this crate.
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref error) => write!(f, "I/O error: {}", error),
+            Error::Parser(ref error) => write!(f, "parsing error: {}", error),
+            Error::Invalid(ref violations) => {
+                write!(f,
+                       "invalid VM: {}",
+                       violations
+                           .iter()
+                           .map(|v| v.to_string())
+                           .collect::<Vec<_>>()
+                           .join(", "))
+            }
+            Error::Assembler(ref file, line, ref message) => {
+                write!(f, "{}:{}: {}", file, line, message)
+            }
+            Error::Interpreter(ref message) => write!(f, "interpreter error: {}", message),
+            Error::UnknownSyscall(number) => write!(f, "unknown system call: {}", number),
+            Error::Syscall(ref message) => write!(f, "system call error: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref error) => Some(error),
+            Error::Parser(ref error) => Some(error),
+            _ => None,
         }
     }
 }
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Error {
+        Error::Parser(error)
+    }
+}
@@ -0,0 +1,680 @@
+//! An interpreter to execute QVMs.
+//!
+//! The semantics follow ioquake3's `VM_CallInterpreted` in
+//! `qcommon/vm_interpreted.c`.
+
+use super::QVM;
+use bytecode::Instruction;
+use syscalls::{syscall_number, SyscallHandler};
+use super::errors::*;
+
+/// Maximum number of arguments to `vmMain`.
+pub const MAX_VMMAIN_ARGS: usize = 13;
+
+/// Number of arguments passed to a system call, excluding its number.
+pub const MAX_SYSCALL_ARGS: usize = 16;
+
+/// Size of the program stack at the top of the memory image.
+pub const PROGRAM_STACK_SIZE: u32 = 0x10000;
+
+/// Maximum depth of the operand stack.
+pub const OPSTACK_SIZE: usize = 256;
+
+/// The memory image of a VM.
+///
+/// It contains the DATA, LIT and BSS segments, in that order, and is rounded
+/// up to a power of two.
+/// Just like in ioquake3, all addresses are masked to the size of the image,
+/// so accesses never fail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Memory {
+    bytes: Vec<u8>,
+    mask: u32,
+}
+
+impl Memory {
+    /// Creates the initial memory image of a VM.
+    pub fn new(qvm: &QVM) -> Memory {
+        let length = qvm.memory_size() as usize;
+
+        let mut bytes = Vec::with_capacity(length);
+        for word in qvm.data() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(qvm.lit());
+        bytes.resize(length, 0);
+
+        Memory {
+            bytes,
+            mask: (length - 1) as u32,
+        }
+    }
+
+    /// Returns the size of the memory image in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns whether the memory image is empty, which it never is.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns the mask that is applied to all addresses.
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// Returns the raw memory image.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the raw memory image for modification.
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    /// Reads an octet.
+    pub fn read_u8(&self, address: u32) -> u8 {
+        self.bytes[(address & self.mask) as usize]
+    }
+
+    /// Reads a 2-octet value, aligned to 2 octets.
+    pub fn read_u16(&self, address: u32) -> u16 {
+        let address = (address & self.mask & !1) as usize;
+        u16::from_le_bytes([self.bytes[address], self.bytes[address + 1]])
+    }
+
+    /// Reads a 4-octet value, aligned to 4 octets.
+    pub fn read_u32(&self, address: u32) -> u32 {
+        let address = (address & self.mask & !3) as usize;
+        u32::from_le_bytes([self.bytes[address],
+                            self.bytes[address + 1],
+                            self.bytes[address + 2],
+                            self.bytes[address + 3]])
+    }
+
+    /// Writes an octet.
+    pub fn write_u8(&mut self, address: u32, value: u8) {
+        self.bytes[(address & self.mask) as usize] = value;
+    }
+
+    /// Writes a 2-octet value, aligned to 2 octets.
+    pub fn write_u16(&mut self, address: u32, value: u16) {
+        let address = (address & self.mask & !1) as usize;
+        self.bytes[address..address + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a 4-octet value, aligned to 4 octets.
+    pub fn write_u32(&mut self, address: u32, value: u32) {
+        let address = (address & self.mask & !3) as usize;
+        self.bytes[address..address + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Reads a NUL-terminated string, without the terminator.
+    ///
+    /// The string ends at the end of the memory image if it is not terminated.
+    pub fn read_cstring(&self, address: u32) -> &[u8] {
+        let start = (address & self.mask) as usize;
+        let end = self.bytes[start..]
+            .iter()
+            .position(|&b| b == 0)
+            .map(|i| start + i)
+            .unwrap_or_else(|| self.bytes.len());
+        &self.bytes[start..end]
+    }
+
+    /// Copies a block of memory, see ioquake3's `VM_BlockCopy`.
+    fn block_copy(&mut self, dest: u32, src: u32, n: u32) -> Result<()> {
+        let in_range = |address: u32| {
+            (address & self.mask) == address &&
+            address
+                .checked_add(n)
+                .is_some_and(|end| (end & self.mask) == end || end == self.mask.wrapping_add(1))
+        };
+        if !in_range(dest) || !in_range(src) {
+            return Err(Error::Interpreter("OP_BLOCK_COPY out of range".to_string()));
+        }
+        let (dest, src, n) = (dest as usize, src as usize, n as usize);
+        self.bytes.copy_within(src..src + n, dest);
+        Ok(())
+    }
+}
+
+/// An interpreter for a QVM.
+#[derive(Debug)]
+pub struct Interpreter {
+    qvm: QVM,
+    memory: Memory,
+    program_stack: u32,
+    stack_bottom: u32,
+}
+
+fn error<T>(message: &str) -> Result<T> {
+    Err(Error::Interpreter(message.to_string()))
+}
+
+/// The operand stack.
+struct OpStack {
+    values: Vec<u32>,
+}
+
+impl OpStack {
+    fn push(&mut self, value: u32) -> Result<()> {
+        if self.values.len() >= OPSTACK_SIZE {
+            return error("operand stack overflow");
+        }
+        self.values.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u32> {
+        match self.values.pop() {
+            Some(value) => Ok(value),
+            None => error("operand stack underflow"),
+        }
+    }
+
+    fn pop_f32(&mut self) -> Result<f32> {
+        self.pop().map(f32::from_bits)
+    }
+
+    fn push_f32(&mut self, value: f32) -> Result<()> {
+        self.push(value.to_bits())
+    }
+}
+
+impl Interpreter {
+    /// Creates a new interpreter with the initial memory image of the VM.
+    pub fn new(qvm: QVM) -> Interpreter {
+        let memory = Memory::new(&qvm);
+        let program_stack = memory.mask().wrapping_add(1);
+        Interpreter {
+            qvm,
+            memory,
+            program_stack,
+            stack_bottom: program_stack.saturating_sub(PROGRAM_STACK_SIZE),
+        }
+    }
+
+    /// Returns the interpreted VM.
+    pub fn qvm(&self) -> &QVM {
+        &self.qvm
+    }
+
+    /// Returns the memory image.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Returns the memory image for modification.
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Calls `vmMain` with the given arguments and returns its result.
+    ///
+    /// Missing arguments are zero.
+    /// `CALL`s to negative addresses are handled by `syscalls`, see
+    /// `syscalls::syscall_number` for their numbering.
+    ///
+    /// # Errors
+    /// Returns `Error::Interpreter` if the VM misbehaves, e.g. jumps out of
+    /// bounds or overflows its stacks, and any error of `syscalls`.
+    pub fn call<H>(&mut self, args: &[u32], syscalls: &mut H) -> Result<u32>
+        where H: SyscallHandler + ?Sized
+    {
+        if args.len() > MAX_VMMAIN_ARGS {
+            return error("too many vmMain arguments");
+        }
+
+        let stack_on_entry = self.program_stack;
+        let args_size = 8 + 4 * MAX_VMMAIN_ARGS as u32;
+        if stack_on_entry.wrapping_sub(self.stack_bottom) < args_size {
+            return error("memory too small for vmMain arguments");
+        }
+        let mut program_stack = stack_on_entry.wrapping_sub(args_size);
+        for arg in 0..MAX_VMMAIN_ARGS {
+            let value = args.get(arg).cloned().unwrap_or(0);
+            self.memory
+                .write_u32(program_stack.wrapping_add(8 + arg as u32 * 4), value);
+        }
+        // return stack
+        self.memory.write_u32(program_stack.wrapping_add(4), 0);
+        // will terminate the loop on return
+        self.memory.write_u32(program_stack, -1i32 as u32);
+
+        let mut op_stack = OpStack { values: Vec::with_capacity(OPSTACK_SIZE) };
+        let mut pc: usize = 0;
+        let code = self.qvm.instructions();
+
+        macro_rules! branch {
+            ($target:expr, $condition:expr) => {{
+                if $condition {
+                    if $target as usize >= code.len() {
+                        return error("VM program counter out of range in branch");
+                    }
+                    pc = $target as usize;
+                }
+            }}
+        }
+        macro_rules! compare {
+            ($target:expr, $op:tt, $ty:ty) => {{
+                let r0 = op_stack.pop()? as $ty;
+                let r1 = op_stack.pop()? as $ty;
+                branch!($target, r1 $op r0)
+            }}
+        }
+        macro_rules! compare_f32 {
+            ($target:expr, $op:tt) => {{
+                let r0 = op_stack.pop_f32()?;
+                let r1 = op_stack.pop_f32()?;
+                branch!($target, r1 $op r0)
+            }}
+        }
+        macro_rules! binary {
+            (|$r1:ident, $r0:ident| $e:expr) => {{
+                let $r0 = op_stack.pop()?;
+                let $r1 = op_stack.pop()?;
+                op_stack.push($e)?;
+            }}
+        }
+        macro_rules! binary_f32 {
+            (|$r1:ident, $r0:ident| $e:expr) => {{
+                let $r0 = op_stack.pop_f32()?;
+                let $r1 = op_stack.pop_f32()?;
+                op_stack.push_f32($e)?;
+            }}
+        }
+        macro_rules! unary {
+            (|$r0:ident| $e:expr) => {{
+                let $r0 = op_stack.pop()?;
+                op_stack.push($e)?;
+            }}
+        }
+
+        loop {
+            let instruction = match code.get(pc) {
+                Some(instruction) => *instruction,
+                None => return error("VM program counter out of range"),
+            };
+            pc += 1;
+
+            match instruction {
+                Instruction::UNDEF => return error("bad VM instruction"),
+                Instruction::IGNORE | Instruction::BREAK => {}
+
+                Instruction::ENTER(frame_size) => {
+                    program_stack = program_stack.wrapping_sub(frame_size);
+                    if program_stack <= self.stack_bottom || program_stack > stack_on_entry {
+                        return error("program stack overflow");
+                    }
+                }
+                Instruction::LEAVE(frame_size) => {
+                    // remove our stack frame
+                    program_stack = program_stack.wrapping_add(frame_size);
+                    // grab the saved program counter
+                    let return_address = self.memory.read_u32(program_stack);
+                    // check for leaving the VM
+                    if return_address == -1i32 as u32 {
+                        break;
+                    }
+                    if return_address as usize >= code.len() {
+                        return error("VM program counter out of range in OP_LEAVE");
+                    }
+                    pc = return_address as usize;
+                }
+                Instruction::CALL => {
+                    // save current program counter
+                    self.memory.write_u32(program_stack, pc as u32);
+                    // jump to the location on the stack
+                    let target = op_stack.pop()? as i32;
+                    if target < 0 {
+                        // system call
+                        let number = syscall_number(target);
+                        self.memory.write_u32(program_stack.wrapping_add(4), number as u32);
+                        let mut syscall_args = [0; MAX_SYSCALL_ARGS];
+                        for (i, arg) in syscall_args.iter_mut().enumerate() {
+                            let address = program_stack.wrapping_add(8 + i as u32 * 4);
+                            *arg = self.memory.read_u32(address);
+                        }
+                        let result = syscalls.syscall(&mut self.memory, number, &syscall_args)?;
+                        // save return value
+                        op_stack.push(result)?;
+                        pc = self.memory.read_u32(program_stack) as usize;
+                    } else if target as usize >= code.len() {
+                        return error("VM program counter out of range in OP_CALL");
+                    } else {
+                        pc = target as usize;
+                    }
+                }
+                // push and pop are only needed for discarded or bad function return values
+                Instruction::PUSH => op_stack.push(0)?,
+                Instruction::POP => {
+                    op_stack.pop()?;
+                }
+
+                Instruction::CONST(value) => op_stack.push(value)?,
+                Instruction::LOCAL(offset) => op_stack.push(offset.wrapping_add(program_stack))?,
+
+                Instruction::JUMP => {
+                    let target = op_stack.pop()?;
+                    branch!(target, true);
+                }
+
+                Instruction::EQ(target) => compare!(target, ==, u32),
+                Instruction::NE(target) => compare!(target, !=, u32),
+
+                Instruction::LTI(target) => compare!(target, <, i32),
+                Instruction::LEI(target) => compare!(target, <=, i32),
+                Instruction::GTI(target) => compare!(target, >, i32),
+                Instruction::GEI(target) => compare!(target, >=, i32),
+
+                Instruction::LTU(target) => compare!(target, <, u32),
+                Instruction::LEU(target) => compare!(target, <=, u32),
+                Instruction::GTU(target) => compare!(target, >, u32),
+                Instruction::GEU(target) => compare!(target, >=, u32),
+
+                Instruction::EQF(target) => compare_f32!(target, ==),
+                Instruction::NEF(target) => compare_f32!(target, !=),
+
+                Instruction::LTF(target) => compare_f32!(target, <),
+                Instruction::LEF(target) => compare_f32!(target, <=),
+                Instruction::GTF(target) => compare_f32!(target, >),
+                Instruction::GEF(target) => compare_f32!(target, >=),
+
+                Instruction::LOAD1 => unary!(|r0| self.memory.read_u8(r0) as u32),
+                Instruction::LOAD2 => unary!(|r0| self.memory.read_u16(r0) as u32),
+                Instruction::LOAD4 => unary!(|r0| self.memory.read_u32(r0)),
+                Instruction::STORE1 => {
+                    let r0 = op_stack.pop()?;
+                    let r1 = op_stack.pop()?;
+                    self.memory.write_u8(r1, r0 as u8);
+                }
+                Instruction::STORE2 => {
+                    let r0 = op_stack.pop()?;
+                    let r1 = op_stack.pop()?;
+                    self.memory.write_u16(r1, r0 as u16);
+                }
+                Instruction::STORE4 => {
+                    let r0 = op_stack.pop()?;
+                    let r1 = op_stack.pop()?;
+                    self.memory.write_u32(r1, r0);
+                }
+                Instruction::ARG(offset) => {
+                    // single byte offset from program_stack
+                    let r0 = op_stack.pop()?;
+                    self.memory
+                        .write_u32(program_stack.wrapping_add(offset as u32), r0);
+                }
+
+                Instruction::BLOCK_COPY(size) => {
+                    let r0 = op_stack.pop()?;
+                    let r1 = op_stack.pop()?;
+                    self.memory.block_copy(r1, r0, size)?;
+                }
+
+                Instruction::SEX8 => unary!(|r0| r0 as i8 as i32 as u32),
+                Instruction::SEX16 => unary!(|r0| r0 as i16 as i32 as u32),
+
+                Instruction::NEGI => unary!(|r0| (r0 as i32).wrapping_neg() as u32),
+                Instruction::ADD => binary!(|r1, r0| r1.wrapping_add(r0)),
+                Instruction::SUB => binary!(|r1, r0| r1.wrapping_sub(r0)),
+                Instruction::DIVI => {
+                    binary!(|r1, r0| if r0 == 0 {
+                                return error("division by zero");
+                            } else {
+                                (r1 as i32).wrapping_div(r0 as i32) as u32
+                            })
+                }
+                Instruction::DIVU => {
+                    binary!(|r1, r0| match r1.checked_div(r0) {
+                                Some(v) => v,
+                                None => return error("division by zero"),
+                            })
+                }
+                Instruction::MODI => {
+                    binary!(|r1, r0| if r0 == 0 {
+                                return error("division by zero");
+                            } else {
+                                (r1 as i32).wrapping_rem(r0 as i32) as u32
+                            })
+                }
+                Instruction::MODU => {
+                    binary!(|r1, r0| match r1.checked_rem(r0) {
+                                Some(v) => v,
+                                None => return error("division by zero"),
+                            })
+                }
+                Instruction::MULI => {
+                    binary!(|r1, r0| (r1 as i32).wrapping_mul(r0 as i32) as u32)
+                }
+                Instruction::MULU => binary!(|r1, r0| r1.wrapping_mul(r0)),
+
+                Instruction::BAND => binary!(|r1, r0| r1 & r0),
+                Instruction::BOR => binary!(|r1, r0| r1 | r0),
+                Instruction::BXOR => binary!(|r1, r0| r1 ^ r0),
+                Instruction::BCOM => unary!(|r0| !r0),
+
+                Instruction::LSH => binary!(|r1, r0| r1.wrapping_shl(r0)),
+                Instruction::RSHI => binary!(|r1, r0| (r1 as i32).wrapping_shr(r0) as u32),
+                Instruction::RSHU => binary!(|r1, r0| r1.wrapping_shr(r0)),
+
+                Instruction::NEGF => {
+                    let r0 = op_stack.pop_f32()?;
+                    op_stack.push_f32(-r0)?;
+                }
+                Instruction::ADDF => binary_f32!(|r1, r0| r1 + r0),
+                Instruction::SUBF => binary_f32!(|r1, r0| r1 - r0),
+                Instruction::DIVF => binary_f32!(|r1, r0| r1 / r0),
+                Instruction::MULF => binary_f32!(|r1, r0| r1 * r0),
+
+                Instruction::CVIF => {
+                    let r0 = op_stack.pop()?;
+                    op_stack.push_f32(r0 as i32 as f32)?;
+                }
+                Instruction::CVFI => {
+                    let r0 = op_stack.pop_f32()?;
+                    op_stack.push(r0 as i32 as u32)?;
+                }
+            }
+        }
+
+        if op_stack.values.len() != 1 {
+            return error("operand stack not balanced on return");
+        }
+        op_stack.pop()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Interpreter, Memory};
+    use bytecode::Instruction;
+    use errors::Error;
+    use parser::parse_qvm;
+    use QVM;
+
+    fn no_syscalls(_: &mut Memory, _: i32, _: &[u32]) -> ::errors::Result<u32> {
+        panic!("unexpected system call")
+    }
+
+    /// Runs code that might fail validation, to test the runtime checks.
+    fn run(code: Vec<Instruction>) -> ::errors::Result<u32> {
+        let qvm = QVM {
+            code,
+            data: vec![0],
+            lit: vec![],
+            bss_length: 0x10000,
+            jtrg: None,
+            symbols: None,
+        };
+        Interpreter::new(qvm).call(&[], &mut no_syscalls)
+    }
+
+    #[test]
+    fn test_memory_image() {
+        let qvm = parse_qvm(include_bytes!("../assets/mod-lit.qvm")).unwrap();
+        let memory = Memory::new(&qvm);
+        assert_eq!(memory.len(), 0x20000);
+        assert_eq!(memory.read_cstring(4), b"!");
+        assert_eq!(memory.read_u32(0x20000 + 4), 0x21);
+    }
+
+    #[test]
+    fn test_call_minimal() {
+        let qvm = parse_qvm(include_bytes!("../assets/mod-minimal.qvm")).unwrap();
+        let result = Interpreter::new(qvm).call(&[0], &mut no_syscalls).unwrap();
+        assert_eq!(result as i32, -1);
+    }
+
+    #[test]
+    fn test_call_empty_data() {
+        // The memory image is too small for the arguments of vmMain
+        let code = vec![Instruction::ENTER(8), Instruction::CONST(7), Instruction::LEAVE(8)];
+        let qvm = QVM::new(code, vec![], vec![], 0).unwrap();
+        match Interpreter::new(qvm).call(&[], &mut no_syscalls) {
+            Err(Error::Interpreter(_)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_call_syscall_hello_world() {
+        let qvm = parse_qvm(include_bytes!("../assets/mod-syscall.qvm")).unwrap();
+        let mut interpreter = Interpreter::new(qvm);
+        let mut output = String::new();
+        let result = interpreter
+            .call(&[0], &mut |memory: &mut Memory, number: i32, args: &[u32]| {
+                assert_eq!(number, 665);
+                output.push_str(&String::from_utf8_lossy(memory.read_cstring(args[0])));
+                Ok(0)
+            })
+            .unwrap();
+        assert_eq!(output, "Hello, world!");
+        assert_eq!(result as i32, -1);
+    }
+
+    #[test]
+    fn test_call_arguments_and_procedures() {
+        // vmMain(a, b) { return twice(a) - b; }, twice(x) { return x + x; }
+        let code = vec![Instruction::ENTER(12),
+                        Instruction::LOCAL(20),
+                        Instruction::LOAD4,
+                        Instruction::ARG(8),
+                        Instruction::CONST(12),
+                        Instruction::CALL,
+                        Instruction::LOCAL(24),
+                        Instruction::LOAD4,
+                        Instruction::SUB,
+                        Instruction::LEAVE(12),
+                        Instruction::PUSH,
+                        Instruction::LEAVE(12),
+                        Instruction::ENTER(8),
+                        Instruction::LOCAL(16),
+                        Instruction::LOAD4,
+                        Instruction::LOCAL(16),
+                        Instruction::LOAD4,
+                        Instruction::ADD,
+                        Instruction::LEAVE(8)];
+        let qvm = QVM::new(code, vec![0], vec![], 0x10000).unwrap();
+        let result = Interpreter::new(qvm).call(&[21, 2], &mut no_syscalls).unwrap();
+        assert_eq!(result, 40);
+    }
+
+    #[test]
+    fn test_call_loop_and_floats() {
+        // sum = 0.0; for (i = 0; i != 4; i++) sum += 0.5; return (int)(sum * 10.0);
+        let code = vec![Instruction::ENTER(16),
+                        Instruction::LOCAL(8), // i
+                        Instruction::CONST(0),
+                        Instruction::STORE4,
+                        Instruction::LOCAL(12), // sum
+                        Instruction::const_f32(0.0),
+                        Instruction::STORE4,
+                        // loop header, 7
+                        Instruction::LOCAL(8),
+                        Instruction::LOAD4,
+                        Instruction::CONST(4),
+                        Instruction::EQ(26),
+                        Instruction::LOCAL(12),
+                        Instruction::LOCAL(12),
+                        Instruction::LOAD4,
+                        Instruction::const_f32(0.5),
+                        Instruction::ADDF,
+                        Instruction::STORE4,
+                        Instruction::LOCAL(8),
+                        Instruction::LOCAL(8),
+                        Instruction::LOAD4,
+                        Instruction::CONST(1),
+                        Instruction::ADD,
+                        Instruction::STORE4,
+                        Instruction::CONST(7),
+                        Instruction::JUMP,
+                        Instruction::UNDEF,
+                        // loop exit, 26
+                        Instruction::LOCAL(12),
+                        Instruction::LOAD4,
+                        Instruction::const_f32(10.0),
+                        Instruction::MULF,
+                        Instruction::CVFI,
+                        Instruction::LEAVE(16)];
+        assert_eq!(run(code).unwrap(), 20);
+    }
+
+    #[test]
+    fn test_call_sign_extension() {
+        let code = vec![Instruction::ENTER(8),
+                        Instruction::CONST(0xff),
+                        Instruction::SEX8,
+                        Instruction::LEAVE(8)];
+        assert_eq!(run(code).unwrap() as i32, -1);
+    }
+
+    #[test]
+    fn test_call_division_by_zero() {
+        let code = vec![Instruction::ENTER(8),
+                        Instruction::CONST(1),
+                        Instruction::CONST(0),
+                        Instruction::DIVI,
+                        Instruction::LEAVE(8)];
+        assert!(run(code).is_err());
+    }
+
+    #[test]
+    fn test_call_undef() {
+        assert!(run(vec![Instruction::UNDEF]).is_err());
+    }
+
+    #[test]
+    fn test_call_operand_stack_underflow() {
+        let code = vec![Instruction::ENTER(8), Instruction::POP, Instruction::LEAVE(8)];
+        assert!(run(code).is_err());
+    }
+
+    #[test]
+    fn test_call_jump_out_of_range() {
+        let code = vec![Instruction::ENTER(8), Instruction::CONST(100), Instruction::JUMP];
+        assert!(run(code).is_err());
+    }
+
+    #[test]
+    fn test_call_infinite_recursion() {
+        let code = vec![Instruction::ENTER(0x100),
+                        Instruction::CONST(0),
+                        Instruction::CALL,
+                        Instruction::LEAVE(0x100)];
+        assert!(run(code).is_err());
+    }
+    #[test]
+    fn test_call_stack_exhausted() {
+        // The vmMain arguments take 60 octets of the 0x10000 octet program stack
+        let code = |frame_size| vec![Instruction::ENTER(frame_size),
+                                     Instruction::CONST(0),
+                                     Instruction::LEAVE(frame_size)];
+        assert_eq!(run(code(0x10000 - 64)).unwrap(), 0);
+        assert!(run(code(0x10000 - 60)).is_err());
+    }
+}
@@ -2,24 +2,59 @@
 
 //! A library to handle Quake 3 virtual machines.
 
-// Both `nom` and `error_chain` can recurse deeply
+// `nom` can recurse deeply
 #![recursion_limit="1024"]
 
-#[macro_use]
-extern crate error_chain;
 #[macro_use]
 extern crate nom;
 
 pub mod errors;
+pub mod assembler;
 pub mod bytecode;
+pub mod callgraph;
+pub mod cfg;
+pub mod decompiler;
+pub mod disassembler;
+pub mod dominators;
+pub mod dot;
+pub mod interpreter;
+pub mod loops;
 pub mod opcodes;
+pub mod opstack;
 pub mod parser;
+pub mod q3asm;
+pub mod signatures;
+pub mod ssa;
+pub mod stackusage;
+pub mod symbols;
+pub mod syscalls;
+pub mod types;
+pub mod validation;
+pub mod writer;
 
 pub use bytecode::Instruction;
 
+use bytecode::Address;
 use errors::*;
+use symbols::SymbolMap;
+use validation::validate;
 
 const VM_MAGIC: [u8; 4] = [0x44, 0x14, 0x72, 0x12];
+const VM_MAGIC_VER2: [u8; 4] = [0x45, 0x14, 0x72, 0x12];
+
+const HEADER_LENGTH_V1: u32 = 32;
+const HEADER_LENGTH_V2: u32 = HEADER_LENGTH_V1 + 4;
+
+/// The version of the QVM file format.
+///
+/// See ioquake3's `VM_MAGIC` and `VM_MAGIC_VER2` in `qcommon/qfiles.h`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Version {
+    /// The original format, as produced by id's q3asm.
+    V1,
+    /// The extended format with a jump table targets (JTRG) segment.
+    V2,
+}
 
 /// A Quake 3 virtual machine image.
 ///
@@ -28,31 +63,79 @@ const VM_MAGIC: [u8; 4] = [0x44, 0x14, 0x72, 0x12];
 /// * word-sized data
 /// * byte-sized data (LIT)
 /// * uninitialized data (BSS)
+///
+/// Version 2 VMs additionally contain a table of jump targets (JTRG).
+///
+/// Symbol names are not part of the QVM file format, but can be attached from
+/// a separate `.map` file.
 #[derive(Debug,PartialEq)]
 pub struct QVM {
     code: Vec<Instruction>,
     data: Vec<u32>,
     lit: Vec<u8>,
     bss_length: u32,
+    jtrg: Option<Vec<Address>>,
+    symbols: Option<SymbolMap>,
 }
 
 impl QVM {
-    // TODO: Validate instructions; addresses might be out of bounds etc.
     /// Creates a new VM instance.
     ///
     /// # Errors
-    /// lorem ipsum
+    /// Returns `Error::Invalid` with all violations if the VM fails
+    /// `validation::validate`.
     pub fn new(code: Vec<Instruction>,
                data: Vec<u32>,
                lit: Vec<u8>,
                bss_length: u32)
                -> Result<QVM> {
-        Ok(QVM {
-               code: code,
-               data: data,
-               lit: lit,
-               bss_length: bss_length,
-           })
+        QVM {
+                code,
+                data,
+                lit,
+                bss_length,
+                jtrg: None,
+                symbols: None,
+            }
+            .validated()
+    }
+
+    /// Creates a new version 2 VM instance with jump table targets.
+    ///
+    /// # Errors
+    /// See `new`.
+    pub fn new_v2(code: Vec<Instruction>,
+                  data: Vec<u32>,
+                  lit: Vec<u8>,
+                  bss_length: u32,
+                  jtrg: Vec<Address>)
+                  -> Result<QVM> {
+        QVM {
+                code,
+                data,
+                lit,
+                bss_length,
+                jtrg: Some(jtrg),
+                symbols: None,
+            }
+            .validated()
+    }
+
+    fn validated(self) -> Result<QVM> {
+        let violations = validate(&self);
+        if violations.is_empty() {
+            Ok(self)
+        } else {
+            Err(Error::Invalid(violations))
+        }
+    }
+
+    /// Returns the file format version of the VM.
+    pub fn version(&self) -> Version {
+        match self.jtrg {
+            Some(_) => Version::V2,
+            None => Version::V1,
+        }
     }
 
     /// Returns the instructions of the code segment.
@@ -74,6 +157,61 @@ impl QVM {
     pub fn bss_length(&self) -> u32 {
         self.bss_length
     }
+
+    /// Returns the size of the memory image.
+    ///
+    /// This is the size of the DATA, LIT and BSS segments, rounded up to a power
+    /// of two like ioquake3 does.
+    pub fn memory_size(&self) -> u64 {
+        let length = self.data.len() as u64 * 4 + self.lit.len() as u64 + self.bss_length as u64;
+        length.max(4).next_power_of_two()
+    }
+
+    /// Returns the jump label addresses of the JTRG segment.
+    ///
+    /// This is only available for version 2 VMs.
+    pub fn jump_targets(&self) -> Option<&Vec<Address>> {
+        self.jtrg.as_ref()
+    }
+
+    /// Returns the attached symbol map, if any.
+    pub fn symbols(&self) -> Option<&SymbolMap> {
+        self.symbols.as_ref()
+    }
+
+    /// Attaches a symbol map, replacing any previous one.
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.symbols = Some(symbols);
+    }
+
+    /// Returns the name of the symbol at the given segment and address.
+    ///
+    /// This is always `None` if there is no symbol map attached.
+    pub fn symbol_name(&self, segment: Segment, address: Address) -> Option<&str> {
+        self.symbols
+            .as_ref()
+            .and_then(|symbols| symbols.name_at(segment, address))
+    }
+
+    /// Splits an address of the memory image into its segment and the
+    /// address relative to that segment, like in q3asm symbol maps.
+    ///
+    /// Returns `None` for addresses beyond the BSS segment.
+    pub fn segment_address(&self, address: Address) -> Option<(Segment, Address)> {
+        let address = address as u64;
+        let lit_start = self.data.len() as u64 * 4;
+        let bss_start = lit_start + self.lit.len() as u64;
+        let bss_end = bss_start + self.bss_length as u64;
+        if address < lit_start {
+            Some((Segment::DATA, address as Address))
+        } else if address < bss_start {
+            Some((Segment::LIT, (address - lit_start) as Address))
+        } else if address < bss_end {
+            Some((Segment::BSS, (address - bss_start) as Address))
+        } else {
+            None
+        }
+    }
 }
 
 /// The different segments/sections in a QVM file.
@@ -81,7 +219,7 @@ impl QVM {
 /// See ioquake3's `segmentName_t` in `tools/asm/q3asm.c`
 // These should match the names in ioquake3
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Segment {
     /// The code segment, consisting of instructions.
     CODE,
@@ -0,0 +1,305 @@
+//! Natural loops of control-flow graphs.
+//!
+//! A back edge is an edge to a block that dominates its source, the loop
+//! header. The natural loop of a header consists of all blocks that reach one
+//! of its back edges without passing through the header. q3asm does not
+//! reorder code, so every C loop becomes a natural loop.
+
+use cfg::{BlockId, Cfg};
+use dominators::Dominators;
+use std::collections::BTreeMap;
+
+/// A natural loop.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Loop {
+    /// The block that dominates all blocks of the loop.
+    pub header: BlockId,
+    /// The sources of back edges to the header.
+    pub latches: Vec<BlockId>,
+    /// All blocks of the loop, including the header and nested loops, in
+    /// ascending order.
+    pub body: Vec<BlockId>,
+    /// Edges from a block of the loop to a block outside of it.
+    pub exits: Vec<(BlockId, BlockId)>,
+    /// Index of the innermost loop containing this one.
+    pub parent: Option<usize>,
+    /// Number of loops containing this one, starting at 1.
+    pub depth: usize,
+}
+
+impl Loop {
+    /// Returns whether a block is part of the loop.
+    pub fn contains(&self, block: BlockId) -> bool {
+        self.body.binary_search(&block).is_ok()
+    }
+}
+
+/// The natural loops of a procedure.
+#[derive(Debug, Clone)]
+pub struct Loops {
+    loops: Vec<Loop>,
+    innermost: Vec<Option<usize>>,
+}
+
+impl Loops {
+    /// Finds the natural loops of a procedure with the given dominators.
+    ///
+    /// Loops with the same header are merged into one.
+    pub fn new(cfg: &Cfg, dominators: &Dominators) -> Loops {
+        let count = cfg.blocks().len();
+        let mut latches: BTreeMap<BlockId, Vec<BlockId>> = BTreeMap::new();
+        for block in (0..count).filter(|&block| dominators.is_reachable(block)) {
+            for edge in cfg.successors(block) {
+                if dominators.dominates(edge.target, block) {
+                    latches.entry(edge.target).or_default().push(block);
+                }
+            }
+        }
+
+        let mut loops: Vec<Loop> = latches
+            .into_iter()
+            .map(|(header, latches)| {
+                let mut contained = vec![false; count];
+                contained[header] = true;
+                let mut pending = latches.clone();
+                while let Some(block) = pending.pop() {
+                    if contained[block] {
+                        continue;
+                    }
+                    contained[block] = true;
+                    pending.extend(cfg.predecessors(block)
+                                       .iter()
+                                       .filter(|&&p| dominators.is_reachable(p)));
+                }
+                let body: Vec<BlockId> = (0..count).filter(|&block| contained[block]).collect();
+                let exits = body.iter()
+                    .flat_map(|&block| {
+                                  cfg.successors(block)
+                                      .iter()
+                                      .filter(|edge| !contained[edge.target])
+                                      .map(move |edge| (block, edge.target))
+                              })
+                    .collect();
+                Loop {
+                    header,
+                    latches,
+                    body,
+                    exits,
+                    parent: None,
+                    depth: 1,
+                }
+            })
+            .collect();
+
+        // Loops contain all loops nested in them, so larger loops come first
+        loops.sort_by_key(|l| (!l.body.len(), l.header));
+        let mut innermost: Vec<Option<usize>> = vec![None; count];
+        for index in 0..loops.len() {
+            let parent = (0..index)
+                .rev()
+                .find(|&outer| loops[outer].contains(loops[index].header));
+            if let Some(parent) = parent {
+                loops[index].parent = Some(parent);
+                loops[index].depth = loops[parent].depth + 1;
+            }
+            for &block in &loops[index].body {
+                match innermost[block] {
+                    Some(other) if loops[other].depth > loops[index].depth => {}
+                    _ => innermost[block] = Some(index),
+                }
+            }
+        }
+
+        Loops { loops, innermost }
+    }
+
+    /// Returns all loops, outer loops before the loops nested in them.
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+
+    /// Returns the index of the innermost loop containing a block.
+    pub fn innermost(&self, block: BlockId) -> Option<usize> {
+        self.innermost[block]
+    }
+
+    /// Returns the number of loops containing a block.
+    pub fn depth(&self, block: BlockId) -> usize {
+        self.innermost(block).map_or(0, |index| self.loops[index].depth)
+    }
+
+    /// Returns whether a block is the header of a loop.
+    pub fn is_header(&self, block: BlockId) -> bool {
+        self.loops.iter().any(|l| l.header == block)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Loop, Loops};
+    use assembler::assemble;
+    use cfg::Cfg;
+    use dominators::Dominators;
+    use parser::parse_qvm;
+
+    fn loops(source: &str) -> Loops {
+        let qvm = assemble("test", source).unwrap();
+        let cfg = &Cfg::for_qvm(&qvm)[0];
+        Loops::new(cfg, &Dominators::new(cfg))
+    }
+
+    #[test]
+    fn test_while_loop() {
+        let loops = loops("
+            vmMain:
+                    ENTER 16
+                    LOCAL 4
+                    CONST 0
+                    STORE4
+            head:   LOCAL 4
+                    LOAD4
+                    CONST 10
+                    GEI done
+                    LOCAL 4
+                    LOCAL 4
+                    LOAD4
+                    CONST 1
+                    ADD
+                    STORE4
+                    CONST head
+                    JUMP
+            done:   CONST 0
+                    LEAVE 16
+        ");
+        assert_eq!(loops.loops(),
+                   &[Loop {
+                         header: 1,
+                         latches: vec![2],
+                         body: vec![1, 2],
+                         exits: vec![(1, 3)],
+                         parent: None,
+                         depth: 1,
+                     }]);
+        assert_eq!(loops.depth(0), 0);
+        assert_eq!(loops.depth(2), 1);
+        assert!(loops.is_header(1));
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        let loops = loops("
+            vmMain:
+                    ENTER 16
+            outer:  LOCAL 8
+                    LOAD4
+                    CONST 0
+                    EQ done
+            inner:  LOCAL 12
+                    LOAD4
+                    CONST 0
+                    EQ next
+                    CONST inner
+                    JUMP
+            next:   CONST outer
+                    JUMP
+            done:   CONST 0
+                    LEAVE 16
+        ");
+        let all = loops.loops();
+        assert_eq!(all.len(), 2);
+        assert_eq!((all[0].header, &all[0].body), (1, &vec![1, 2, 3, 4]));
+        assert_eq!((&all[0].exits, all[0].parent), (&vec![(1, 5)], None));
+        assert_eq!((all[1].header, &all[1].body), (2, &vec![2, 3]));
+        assert_eq!((&all[1].exits, all[1].parent), (&vec![(2, 4)], Some(0)));
+        assert_eq!(all[1].depth, 2);
+        assert_eq!(loops.innermost(3), Some(1));
+        assert_eq!(loops.innermost(4), Some(0));
+        assert_eq!((loops.depth(3), loops.depth(4), loops.depth(5)), (2, 1, 0));
+    }
+
+    #[test]
+    fn test_rotated_nested_loops() {
+        // LCC tests the condition of `while` and `for` loops at the bottom
+        let loops = loops("
+            vmMain:
+                    ENTER 16
+                    CONST outer
+                    JUMP
+            obody:  CONST inner
+                    JUMP
+            ibody:  LOCAL 4
+                    CONST 0
+                    STORE4
+            inner:  LOCAL 8
+                    LOAD4
+                    CONST 0
+                    NE ibody
+            outer:  LOCAL 12
+                    LOAD4
+                    CONST 0
+                    NE obody
+                    CONST 0
+                    LEAVE 16
+        ");
+        let all = loops.loops();
+        assert_eq!(all.len(), 2);
+        assert_eq!((all[0].header, &all[0].body), (4, &vec![1, 2, 3, 4]));
+        assert_eq!((all[1].header, &all[1].body), (3, &vec![2, 3]));
+        assert_eq!((all[1].parent, all[1].depth), (Some(0), 2));
+        assert_eq!(loops.innermost(2), Some(1));
+        assert_eq!(loops.innermost(1), Some(0));
+    }
+
+    #[test]
+    fn test_do_while_loop_with_continue() {
+        let loops = loops("
+            vmMain:
+                    ENTER 16
+            body:   LOCAL 8
+                    LOAD4
+                    CONST 0
+                    EQ cond
+                    LOCAL 12
+                    LOAD4
+                    CONST 0
+                    EQ body
+            cond:   LOCAL 4
+                    LOAD4
+                    CONST 0
+                    NE body
+                    CONST 0
+                    LEAVE 16
+        ");
+        assert_eq!(loops.loops().len(), 1);
+        let l = &loops.loops()[0];
+        assert_eq!((l.header, &l.latches, &l.body), (1, &vec![2, 3], &vec![1, 2, 3]));
+        assert_eq!(l.exits, vec![(3, 4)]);
+    }
+
+    #[test]
+    fn test_infinite_loop() {
+        let loops = loops("ENTER 8\nloop: CONST loop\nJUMP\n");
+        assert_eq!(loops.loops().len(), 1);
+        assert_eq!(loops.loops()[0].body, vec![1]);
+        assert!(loops.loops()[0].exits.is_empty());
+    }
+
+    #[test]
+    fn test_loops_ioq3_qagame() {
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        let mut count = 0;
+        for cfg in &Cfg::for_qvm(&qvm) {
+            let dominators = Dominators::new(cfg);
+            let loops = Loops::new(cfg, &dominators);
+            for l in loops.loops() {
+                assert!(l.body.iter().all(|&block| dominators.dominates(l.header, block)));
+                if let Some(parent) = l.parent {
+                    assert!(l.body.iter().all(|&block| loops.loops()[parent].contains(block)));
+                }
+            }
+            count += loops.loops().len();
+        }
+        assert!(count > 100);
+    }
+}
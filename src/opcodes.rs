@@ -1,5 +1,8 @@
-//! Operation codes for QVM instructions.
-//! TODO: Does this have to be in its own mod?
+//! Operation codes for QVM instructions and their metadata.
+
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
 
 // These should match the names in ioquake3
 #[allow(non_camel_case_types)]
@@ -8,7 +11,7 @@
 ///
 /// See ioquake3's `opcode_t` in [qcommon/vm_local.h](https://github.com/ioquake/ioq3/blob/master/code/qcommon/vm_local.h).
 /// See `bytecode::Instruction` for the related, higher-level types.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Opcode {
     UNDEF,
 
@@ -89,3 +92,302 @@ pub enum Opcode {
     CVIF,
     CVFI,
 }
+
+/// Kind of the operand that follows an opcode.
+///
+/// See the type aliases in `bytecode` for their meaning.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OperandKind {
+    /// No operand.
+    None,
+    /// `bytecode::FrameSize`
+    FrameSize,
+    /// `bytecode::Literal`
+    Literal,
+    /// `bytecode::FrameOffset`
+    FrameOffset,
+    /// `bytecode::Address`
+    Address,
+    /// `bytecode::ArgOffset`
+    ArgOffset,
+    /// `bytecode::BlockSize`
+    BlockSize,
+}
+
+impl OperandKind {
+    /// Returns the number of octets of the encoded operand.
+    pub fn size(&self) -> usize {
+        match *self {
+            OperandKind::None => 0,
+            OperandKind::ArgOffset => 1,
+            _ => 4,
+        }
+    }
+}
+
+/// How an instruction affects the operand stack.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct StackEffect {
+    /// Number of values popped.
+    pub pops: u8,
+    /// Number of values pushed after popping.
+    pub pushes: u8,
+}
+
+impl StackEffect {
+    /// Returns the change of the operand stack depth.
+    pub fn delta(&self) -> i32 {
+        self.pushes as i32 - self.pops as i32
+    }
+}
+
+/// How an instruction affects control flow.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ControlFlow {
+    /// Continues with the next instruction.
+    Next,
+    /// Conditionally jumps to the `Address` operand, or continues with the
+    /// next instruction.
+    Branch,
+    /// Jumps to the instruction popped from the operand stack.
+    Jump,
+    /// Calls the procedure or system call popped from the operand stack, then
+    /// continues with the next instruction.
+    Call,
+    /// Returns from the current procedure.
+    Return,
+}
+
+/// Metadata of an opcode.
+struct Info {
+    opcode: Opcode,
+    name: &'static str,
+    operand: OperandKind,
+    stack_effect: StackEffect,
+    control_flow: ControlFlow,
+    float_operands: bool,
+}
+
+macro_rules! info {
+    ($opcode:ident, $operand:ident, $pops:expr, $pushes:expr, $control_flow:ident) => {
+        info!($opcode, $operand, $pops, $pushes, $control_flow, false)
+    };
+    ($opcode:ident, $operand:ident, $pops:expr, $pushes:expr, $control_flow:ident, Float) => {
+        info!($opcode, $operand, $pops, $pushes, $control_flow, true)
+    };
+    ($opcode:ident,
+     $operand:ident,
+     $pops:expr,
+     $pushes:expr,
+     $control_flow:ident,
+     $float_operands:expr) => {
+        Info {
+            opcode: Opcode::$opcode,
+            name: stringify!($opcode),
+            operand: OperandKind::$operand,
+            stack_effect: StackEffect { pops: $pops, pushes: $pushes },
+            control_flow: ControlFlow::$control_flow,
+            float_operands: $float_operands,
+        }
+    };
+}
+
+/// Metadata of all opcodes, indexed by opcode.
+///
+/// Stack effects are those of ioquake3's `VM_CallInterpreted`.
+const INFO: [Info; 60] = [
+    info!(UNDEF, None, 0, 0, Next),
+    info!(IGNORE, None, 0, 0, Next),
+    info!(BREAK, None, 0, 0, Next),
+    info!(ENTER, FrameSize, 0, 0, Next),
+    info!(LEAVE, FrameSize, 0, 0, Return),
+    info!(CALL, None, 1, 1, Call),
+    info!(PUSH, None, 0, 1, Next),
+    info!(POP, None, 1, 0, Next),
+    info!(CONST, Literal, 0, 1, Next),
+    info!(LOCAL, FrameOffset, 0, 1, Next),
+    info!(JUMP, None, 1, 0, Jump),
+    info!(EQ, Address, 2, 0, Branch),
+    info!(NE, Address, 2, 0, Branch),
+    info!(LTI, Address, 2, 0, Branch),
+    info!(LEI, Address, 2, 0, Branch),
+    info!(GTI, Address, 2, 0, Branch),
+    info!(GEI, Address, 2, 0, Branch),
+    info!(LTU, Address, 2, 0, Branch),
+    info!(LEU, Address, 2, 0, Branch),
+    info!(GTU, Address, 2, 0, Branch),
+    info!(GEU, Address, 2, 0, Branch),
+    info!(EQF, Address, 2, 0, Branch, Float),
+    info!(NEF, Address, 2, 0, Branch, Float),
+    info!(LTF, Address, 2, 0, Branch, Float),
+    info!(LEF, Address, 2, 0, Branch, Float),
+    info!(GTF, Address, 2, 0, Branch, Float),
+    info!(GEF, Address, 2, 0, Branch, Float),
+    info!(LOAD1, None, 1, 1, Next),
+    info!(LOAD2, None, 1, 1, Next),
+    info!(LOAD4, None, 1, 1, Next),
+    info!(STORE1, None, 2, 0, Next),
+    info!(STORE2, None, 2, 0, Next),
+    info!(STORE4, None, 2, 0, Next),
+    info!(ARG, ArgOffset, 1, 0, Next),
+    info!(BLOCK_COPY, BlockSize, 2, 0, Next),
+    info!(SEX8, None, 1, 1, Next),
+    info!(SEX16, None, 1, 1, Next),
+    info!(NEGI, None, 1, 1, Next),
+    info!(ADD, None, 2, 1, Next),
+    info!(SUB, None, 2, 1, Next),
+    info!(DIVI, None, 2, 1, Next),
+    info!(DIVU, None, 2, 1, Next),
+    info!(MODI, None, 2, 1, Next),
+    info!(MODU, None, 2, 1, Next),
+    info!(MULI, None, 2, 1, Next),
+    info!(MULU, None, 2, 1, Next),
+    info!(BAND, None, 2, 1, Next),
+    info!(BOR, None, 2, 1, Next),
+    info!(BXOR, None, 2, 1, Next),
+    info!(BCOM, None, 1, 1, Next),
+    info!(LSH, None, 2, 1, Next),
+    info!(RSHI, None, 2, 1, Next),
+    info!(RSHU, None, 2, 1, Next),
+    info!(NEGF, None, 1, 1, Next, Float),
+    info!(ADDF, None, 2, 1, Next, Float),
+    info!(SUBF, None, 2, 1, Next, Float),
+    info!(DIVF, None, 2, 1, Next, Float),
+    info!(MULF, None, 2, 1, Next, Float),
+    info!(CVIF, None, 1, 1, Next),
+    info!(CVFI, None, 1, 1, Next, Float),
+];
+
+impl Opcode {
+    fn info(&self) -> &'static Info {
+        &INFO[*self as usize]
+    }
+
+    /// Returns the opcode for an encoded octet.
+    pub fn from_u8(octet: u8) -> Option<Opcode> {
+        INFO.get(octet as usize).map(|info| info.opcode)
+    }
+
+    /// Returns the opcode with the given name, see `name`.
+    pub fn from_name(name: &str) -> Option<Opcode> {
+        INFO.iter().find(|info| info.name == name).map(|info| info.opcode)
+    }
+
+    /// Returns the name of the opcode as in ioquake3, e.g. `"BLOCK_COPY"`.
+    pub fn name(&self) -> &'static str {
+        self.info().name
+    }
+
+    /// Returns the kind of operand that follows the opcode.
+    pub fn operand(&self) -> OperandKind {
+        self.info().operand
+    }
+
+    /// Returns the number of octets of an encoded instruction.
+    pub fn size(&self) -> usize {
+        1 + self.operand().size()
+    }
+
+    /// Returns how the opcode affects the operand stack.
+    pub fn stack_effect(&self) -> StackEffect {
+        self.info().stack_effect
+    }
+
+    /// Returns how the opcode affects control flow.
+    pub fn control_flow(&self) -> ControlFlow {
+        self.info().control_flow
+    }
+
+    /// Returns whether the operands are floats, e.g. `ADDF` or `CVFI`.
+    pub fn has_float_operands(&self) -> bool {
+        self.info().float_operands
+    }
+
+    /// Returns whether this is a conditional branch.
+    pub fn is_branch(&self) -> bool {
+        self.control_flow() == ControlFlow::Branch
+    }
+
+    /// Returns whether this is a procedure or system call.
+    pub fn is_call(&self) -> bool {
+        self.control_flow() == ControlFlow::Call
+    }
+
+    /// Returns whether execution never continues with the next instruction,
+    /// i.e. `JUMP` and `LEAVE`.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self.control_flow(), ControlFlow::Jump | ControlFlow::Return)
+    }
+}
+
+/// An octet that is not a known opcode.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UnknownOpcode(pub u8);
+
+impl fmt::Display for UnknownOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown opcode {:#04x}", self.0)
+    }
+}
+
+impl error::Error for UnknownOpcode {}
+
+impl TryFrom<u8> for Opcode {
+    type Error = UnknownOpcode;
+
+    fn try_from(octet: u8) -> Result<Opcode, UnknownOpcode> {
+        Opcode::from_u8(octet).ok_or(UnknownOpcode(octet))
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> u8 {
+        opcode as u8
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{ControlFlow, Opcode, OperandKind, StackEffect, UnknownOpcode, INFO};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_info_order() {
+        for (octet, info) in INFO.iter().enumerate() {
+            assert_eq!(info.opcode as usize, octet);
+        }
+    }
+
+    #[test]
+    fn test_try_from_u8() {
+        assert_eq!(Opcode::try_from(3), Ok(Opcode::ENTER));
+        assert_eq!(Opcode::try_from(59), Ok(Opcode::CVFI));
+        assert_eq!(Opcode::try_from(60), Err(UnknownOpcode(60)));
+        assert_eq!(u8::from(Opcode::BLOCK_COPY), 34);
+    }
+
+    #[test]
+    fn test_metadata() {
+        assert_eq!(Opcode::ARG.name(), "ARG");
+        assert_eq!(Opcode::from_name("BLOCK_COPY"), Some(Opcode::BLOCK_COPY));
+        assert_eq!(Opcode::from_name("block_copy"), None);
+        assert_eq!(Opcode::ARG.operand(), OperandKind::ArgOffset);
+        assert_eq!(Opcode::ARG.size(), 2);
+        assert_eq!(Opcode::CONST.size(), 5);
+        assert_eq!(Opcode::ADD.size(), 1);
+        assert_eq!(Opcode::STORE4.stack_effect(),
+                   StackEffect { pops: 2, pushes: 0 });
+        assert_eq!(Opcode::CALL.stack_effect().delta(), 0);
+        assert_eq!(Opcode::LTF.control_flow(), ControlFlow::Branch);
+        assert!(Opcode::LTF.is_branch());
+        assert!(Opcode::CALL.is_call());
+        assert!(Opcode::JUMP.is_terminator());
+        assert!(Opcode::LEAVE.is_terminator());
+        assert!(!Opcode::ENTER.is_terminator());
+        assert!(Opcode::LTF.has_float_operands());
+        assert!(Opcode::CVFI.has_float_operands());
+        assert!(!Opcode::CVIF.has_float_operands());
+        assert!(!Opcode::ADD.has_float_operands());
+    }
+}
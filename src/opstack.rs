@@ -0,0 +1,319 @@
+//! Operand stack depth analysis.
+//!
+//! Every instruction pops and pushes a fixed number of values, see
+//! `opcodes::StackEffect`, so the depth of the operand stack is known
+//! statically at every instruction. q3asm code keeps the operand stack empty
+//! between statements, i.e. at every branch and join, and leaves exactly the
+//! return value on it at `LEAVE`.
+//!
+//! Depths are relative to the start of the procedure; a `CALL` pops the
+//! target and pushes the return value.
+
+use super::QVM;
+use bytecode::Address;
+use cfg::{BlockId, Cfg};
+use interpreter::OPSTACK_SIZE;
+use opcodes::ControlFlow;
+use std::fmt;
+
+/// An operand stack error found by `analyze`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StackError {
+    /// An instruction pops more values than are on the stack.
+    Underflow {
+        /// Index of the instruction.
+        index: Address,
+        /// Depth before the instruction.
+        depth: u32,
+    },
+    /// An instruction pushes more values than fit on the stack.
+    Overflow {
+        /// Index of the instruction.
+        index: Address,
+        /// Depth after the instruction.
+        depth: u32,
+    },
+    /// Control flow joins with different depths.
+    InconsistentMerge {
+        /// Index of the first instruction of the joined block.
+        index: Address,
+        /// Depth of the first path reaching the block.
+        expected: u32,
+        /// Depth of the conflicting path.
+        actual: u32,
+    },
+    /// A branch, `JUMP` or `LEAVE` does not leave the stack as expected.
+    UnexpectedDepth {
+        /// Index of the instruction.
+        index: Address,
+        /// Expected depth before the instruction.
+        expected: u32,
+        /// Depth before the instruction.
+        actual: u32,
+    },
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StackError::Underflow { index, depth } => {
+                write!(f, "instruction {}: operand stack underflow at depth {}", index, depth)
+            }
+            StackError::Overflow { index, depth } => {
+                write!(f, "instruction {}: operand stack overflow at depth {}", index, depth)
+            }
+            StackError::InconsistentMerge {
+                index,
+                expected,
+                actual,
+            } => {
+                write!(f,
+                       "instruction {}: operand stack depth {} does not match {}",
+                       index,
+                       actual,
+                       expected)
+            }
+            StackError::UnexpectedDepth {
+                index,
+                expected,
+                actual,
+            } => {
+                write!(f,
+                       "instruction {}: expected operand stack depth {}, got {}",
+                       index,
+                       expected,
+                       actual)
+            }
+        }
+    }
+}
+
+/// The operand stack depths of a procedure.
+#[derive(Debug, Clone)]
+pub struct StackDepths {
+    start: Address,
+    depths: Vec<Option<u32>>,
+    max_depth: u32,
+    errors: Vec<StackError>,
+}
+
+impl StackDepths {
+    /// Returns the depth before the instruction at `index`.
+    ///
+    /// This is `None` for unreachable instructions, instructions outside of
+    /// the procedure and after stack errors.
+    pub fn depth_at(&self, index: Address) -> Option<u32> {
+        index
+            .checked_sub(self.start)
+            .and_then(|i| self.depths.get(i as usize))
+            .cloned()
+            .and_then(|depth| depth)
+    }
+
+    /// Returns the maximum depth within the procedure.
+    pub fn max_depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    /// Returns all errors, ordered by the analysis.
+    pub fn errors(&self) -> &[StackError] {
+        &self.errors
+    }
+
+    /// Returns whether there are no errors.
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Returns the expected depth before an instruction that ends a statement.
+fn expected_depth(control_flow: ControlFlow) -> Option<u32> {
+    match control_flow {
+        ControlFlow::Branch => Some(2),
+        ControlFlow::Jump | ControlFlow::Return => Some(1),
+        ControlFlow::Next | ControlFlow::Call => None,
+    }
+}
+
+/// Computes the operand stack depth at every instruction of a procedure.
+///
+/// Blocks are only analyzed once, with the depth of the first path reaching
+/// them; other paths are checked against it.
+pub fn analyze(cfg: &Cfg) -> StackDepths {
+    let procedure = cfg.procedure();
+    let mut result = StackDepths {
+        start: procedure.start(),
+        depths: vec![None; (procedure.end() - procedure.start()) as usize],
+        max_depth: 0,
+        errors: vec![],
+    };
+    let mut entries: Vec<Option<u32>> = vec![None; cfg.blocks().len()];
+    entries[0] = Some(0);
+    let mut pending: Vec<BlockId> = vec![0];
+
+    while let Some(id) = pending.pop() {
+        let mut depth = entries[id].expect("pending blocks have an entry depth");
+        let block = cfg.block(id);
+        let mut failed = false;
+        for (index, instruction) in (block.start()..).zip(cfg.instructions(id)) {
+            result.depths[(index - result.start) as usize] = Some(depth);
+            if let Some(expected) = expected_depth(instruction.control_flow()) {
+                if depth != expected {
+                    result
+                        .errors
+                        .push(StackError::UnexpectedDepth {
+                                  index,
+                                  expected,
+                                  actual: depth,
+                              });
+                }
+            }
+            let effect = instruction.stack_effect();
+            if depth < effect.pops as u32 {
+                result.errors.push(StackError::Underflow { index, depth });
+                failed = true;
+                break;
+            }
+            depth = depth - effect.pops as u32 + effect.pushes as u32;
+            if depth as usize > OPSTACK_SIZE {
+                result.errors.push(StackError::Overflow { index, depth });
+                failed = true;
+                break;
+            }
+            result.max_depth = result.max_depth.max(depth);
+        }
+        if failed {
+            continue;
+        }
+
+        for edge in cfg.successors(id) {
+            match entries[edge.target] {
+                Some(expected) if expected != depth => {
+                    result
+                        .errors
+                        .push(StackError::InconsistentMerge {
+                                  index: cfg.block(edge.target).start(),
+                                  expected,
+                                  actual: depth,
+                              })
+                }
+                Some(_) => {}
+                None => {
+                    entries[edge.target] = Some(depth);
+                    pending.push(edge.target);
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Computes the operand stack depths of all procedures of a VM.
+pub fn analyze_qvm(qvm: &QVM) -> Vec<StackDepths> {
+    Cfg::for_qvm(qvm).iter().map(analyze).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, analyze_qvm, StackDepths, StackError};
+    use bytecode::Instruction;
+    use cfg::{procedures, Cfg};
+    use parser::parse_qvm;
+
+    fn analyze_code(code: &[Instruction]) -> StackDepths {
+        let procedure = procedures(code)[0];
+        analyze(&Cfg::new(code, &procedure, None))
+    }
+
+    #[test]
+    fn test_analyze() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::LOCAL(16),
+                    Instruction::LOAD4,
+                    Instruction::CONST(0),
+                    Instruction::EQ(9),
+                    Instruction::LOCAL(4),
+                    Instruction::CONST(0x10),
+                    Instruction::CALL,
+                    Instruction::STORE4,
+                    Instruction::CONST(1),
+                    Instruction::LEAVE(8)];
+        let depths = analyze_code(&code);
+        assert!(depths.is_valid(), "{:?}", depths.errors());
+        assert_eq!(depths.max_depth(), 2);
+        let expected = [0, 0, 1, 1, 2, 0, 1, 2, 2, 0, 1];
+        for (index, &depth) in expected.iter().enumerate() {
+            assert_eq!(depths.depth_at(index as u32), Some(depth));
+        }
+        assert_eq!(depths.depth_at(11), None);
+    }
+
+    #[test]
+    fn test_analyze_underflow() {
+        let code = [Instruction::ENTER(8), Instruction::ADD, Instruction::LEAVE(8)];
+        assert_eq!(analyze_code(&code).errors(),
+                   &[StackError::Underflow { index: 1, depth: 0 }]);
+    }
+
+    #[test]
+    fn test_analyze_overflow() {
+        let mut code = vec![Instruction::ENTER(8)];
+        code.extend((0..300).map(|_| Instruction::PUSH));
+        code.push(Instruction::LEAVE(8));
+        assert_eq!(analyze_code(&code).errors(),
+                   &[StackError::Overflow {
+                         index: 257,
+                         depth: 257,
+                     }]);
+    }
+
+    #[test]
+    fn test_analyze_inconsistent_merge() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::CONST(0),
+                    Instruction::CONST(0),
+                    Instruction::EQ(5),
+                    Instruction::PUSH,
+                    Instruction::PUSH,
+                    Instruction::LEAVE(8)];
+        let depths = analyze_code(&code);
+        assert_eq!(depths.errors(),
+                   &[StackError::InconsistentMerge {
+                         index: 5,
+                         expected: 0,
+                         actual: 1,
+                     }]);
+    }
+
+    #[test]
+    fn test_analyze_unexpected_depth() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::PUSH,
+                    Instruction::PUSH,
+                    Instruction::LEAVE(8)];
+        assert_eq!(analyze_code(&code).errors(),
+                   &[StackError::UnexpectedDepth {
+                         index: 3,
+                         expected: 1,
+                         actual: 2,
+                     }]);
+        assert_eq!(StackError::UnexpectedDepth {
+                           index: 3,
+                           expected: 1,
+                           actual: 2,
+                       }
+                       .to_string(),
+                   "instruction 3: expected operand stack depth 1, got 2");
+    }
+
+    #[test]
+    fn test_analyze_ioq3_qagame() {
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        let depths = analyze_qvm(&qvm);
+        assert!(depths.iter().all(|depths| depths.is_valid()));
+        let max_depth = depths.iter().map(|depths| depths.max_depth()).max();
+        assert!(max_depth.unwrap() > 2);
+    }
+}
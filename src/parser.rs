@@ -1,251 +1,692 @@
 //! Parsers for the different QVM and related formats.
 
-use super::{Instruction, QVM, VM_MAGIC};
+use super::{Instruction, QVM, Segment, Version, VM_MAGIC, VM_MAGIC_VER2, HEADER_LENGTH_V1,
+            HEADER_LENGTH_V2};
 use opcodes::Opcode;
-use super::errors::*;
+use symbols::{Symbol, SymbolMap};
+use errors::Result;
 use nom;
-use nom::{le_u32, le_u8};
+use nom::{hex_u32, space, line_ending};
+use std::error;
+use std::fmt;
+use std::str;
 
 type Input = u8;
 type InputSlice<'a> = &'a [Input];
 
-/// Creates a named parser for an instruction that only consists of an opcode
-macro_rules! instruction {
-    ($name:ident, $opcode:path, $instruction:path) => {
-        named!($name<InputSlice,Instruction>,
-            value!($instruction, tag!([$opcode as Input]))
-        );
+/// Why an instruction could not be decoded.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DecodeError {
+    /// There is no input left.
+    Eof,
+    /// The opcode octet is unknown.
+    UnknownOpcode(u8),
+    /// The input ends within the operand.
+    Truncated,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Eof => write!(f, "no instruction left"),
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#04x}", opcode),
+            DecodeError::Truncated => write!(f, "truncated operand"),
+        }
     }
 }
 
-/// Creates a named parser for an instruction that has an operand
-macro_rules! instruction_with_operand {
-    ($name:ident, $opcode:path, $operand_fn:ident, $instruction:path) => {
-        named!($name<InputSlice,Instruction>,
-            do_parse!(
-                tag!([$opcode as Input]) >>
-                operand: $operand_fn      >>
-                ($instruction(operand))
-            )
-        );
+impl error::Error for DecodeError {}
+
+/// Decodes the instruction at the start of `input`.
+///
+/// Returns the instruction and the number of octets it occupies, i.e. the
+/// offset of the next instruction.
+pub fn decode_instruction(input: InputSlice)
+                      -> ::std::result::Result<(Instruction, usize), DecodeError> {
+    let octet = match input.first() {
+        Some(&octet) => octet,
+        None => return Err(DecodeError::Eof),
+    };
+    let opcode = match Opcode::from_u8(octet) {
+        Some(opcode) => opcode,
+        None => return Err(DecodeError::UnknownOpcode(octet)),
+    };
+    let size = opcode.size();
+    let operand = match (opcode.operand().size(), input.get(1..size)) {
+        (0, _) => 0,
+        (1, Some(operand)) => operand[0] as u32,
+        (_, Some(operand)) => le_u32(operand),
+        (_, None) => return Err(DecodeError::Truncated),
+    };
+    Ok((Instruction::new(opcode, operand), size))
+}
+
+/// Decodes a little-endian `u32` from the first four octets of `bytes`.
+fn le_u32(bytes: InputSlice) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// Limits for parsing QVMs from untrusted sources.
+///
+/// Segment lengths are always limited by the file length, but the number of
+/// instructions and the BSS segment are not backed by data in the file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of instructions in the code segment.
+    pub max_instructions: u32,
+    /// Maximum size of the memory image, i.e. data, lit and bss segments.
+    pub max_memory_size: u64,
+}
+
+impl Default for Limits {
+    /// Limits well above the ioquake3 baseq3 VMs.
+    fn default() -> Limits {
+        Limits {
+            max_instructions: 0x100000,
+            max_memory_size: 0x4000000,
+        }
     }
 }
 
-/// Creates a named parser for an instruction with `u32` operand
-macro_rules! instruction_u32 {
-    ($name:ident, $opcode:path, $instruction:path) => {
-        instruction_with_operand!($name, $opcode, le_u32, $instruction);
+/// A field of the QVM file header.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HeaderField {
+    /// `vmMagic`, i.e. the file format version.
+    Magic,
+    /// `instructionCount`
+    InstructionCount,
+    /// `codeOffset`
+    CodeOffset,
+    /// `codeLength`
+    CodeLength,
+    /// `dataOffset`
+    DataOffset,
+    /// `dataLength`
+    DataLength,
+    /// `litLength`
+    LitLength,
+    /// `bssLength`
+    BssLength,
+    /// `jtrgLength`, only present in version 2.
+    JtrgLength,
+}
+
+impl HeaderField {
+    /// Returns the byte offset of the field within the file.
+    pub fn offset(&self) -> usize {
+        match *self {
+            HeaderField::Magic => 0,
+            HeaderField::InstructionCount => 4,
+            HeaderField::CodeOffset => 8,
+            HeaderField::CodeLength => 12,
+            HeaderField::DataOffset => 16,
+            HeaderField::DataLength => 20,
+            HeaderField::LitLength => 24,
+            HeaderField::BssLength => 28,
+            HeaderField::JtrgLength => 32,
+        }
+    }
+
+    /// Returns the name of the field as in ioquake3's `vmHeader_t`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HeaderField::Magic => "vmMagic",
+            HeaderField::InstructionCount => "instructionCount",
+            HeaderField::CodeOffset => "codeOffset",
+            HeaderField::CodeLength => "codeLength",
+            HeaderField::DataOffset => "dataOffset",
+            HeaderField::DataLength => "dataLength",
+            HeaderField::LitLength => "litLength",
+            HeaderField::BssLength => "bssLength",
+            HeaderField::JtrgLength => "jtrgLength",
+        }
     }
 }
 
-/// Creates a named parser for an instruction with `u8` operand
-macro_rules! instruction_u8 {
-    ($name:ident, $opcode:path, $instruction:path) => {
-        instruction_with_operand!($name, $opcode, le_u8, $instruction);
+/// An error while parsing a QVM or symbol map, with the location of the issue.
+///
+/// Offsets are in octets from the start of the file.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ParseError {
+    /// The file ends before a header field.
+    UnexpectedEof {
+        /// The missing field.
+        field: HeaderField,
+        /// The length of the file.
+        length: usize,
+    },
+    /// The file starts with an unknown magic.
+    UnknownMagic([u8; 4]),
+    /// A header field is inconsistent with other fields.
+    InvalidField {
+        /// The invalid field.
+        field: HeaderField,
+        /// The value of the field.
+        value: u32,
+        /// Why the value is invalid.
+        reason: &'static str,
+    },
+    /// A header field exceeds the configured `Limits`.
+    LimitExceeded {
+        /// The field that exceeds the limit.
+        field: HeaderField,
+        /// The configured limit.
+        limit: u64,
+        /// The actual value.
+        actual: u64,
+    },
+    /// A segment extends beyond the end of the file.
+    SegmentOutOfBounds {
+        /// The segment.
+        segment: Segment,
+        /// The offset of the segment.
+        offset: u64,
+        /// The length of the segment.
+        length: u32,
+        /// The length of the file.
+        file_length: usize,
+    },
+    /// The file is longer than its segments.
+    TrailingData {
+        /// The expected length of the file.
+        expected: u64,
+        /// The actual length of the file.
+        actual: usize,
+    },
+    /// An instruction has an unknown opcode.
+    UnknownOpcode {
+        /// The index of the instruction.
+        index: usize,
+        /// The offset of the instruction.
+        offset: usize,
+        /// The opcode octet.
+        opcode: u8,
+    },
+    /// The operand of an instruction extends beyond the code segment.
+    TruncatedInstruction {
+        /// The index of the instruction.
+        index: usize,
+        /// The offset of the instruction.
+        offset: usize,
+    },
+    /// The code segment ends before `instructionCount` instructions.
+    MissingInstructions {
+        /// The instruction count of the header.
+        expected: u32,
+        /// The number of instructions in the code segment.
+        actual: usize,
+    },
+    /// A line of a symbol map is malformed.
+    InvalidSymbol {
+        /// The line number, starting at 1.
+        line: usize,
+        /// The offset of the line.
+        offset: usize,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnexpectedEof { field, length } => {
+                write!(f,
+                       "file of length {} ends before header field {} at offset {}",
+                       length,
+                       field.name(),
+                       field.offset())
+            }
+            ParseError::UnknownMagic(magic) => write!(f, "unknown magic {:?}", magic),
+            ParseError::InvalidField {
+                field,
+                value,
+                reason,
+            } => {
+                write!(f,
+                       "header field {} at offset {} with value {:#x} {}",
+                       field.name(),
+                       field.offset(),
+                       value,
+                       reason)
+            }
+            ParseError::LimitExceeded {
+                field,
+                limit,
+                actual,
+            } => {
+                write!(f,
+                       "header field {} at offset {} exceeds limit {:#x} with {:#x}",
+                       field.name(),
+                       field.offset(),
+                       limit,
+                       actual)
+            }
+            ParseError::SegmentOutOfBounds {
+                segment,
+                offset,
+                length,
+                file_length,
+            } => {
+                write!(f,
+                       "{:?} segment at offset {} with length {} exceeds file length {}",
+                       segment,
+                       offset,
+                       length,
+                       file_length)
+            }
+            ParseError::TrailingData { expected, actual } => {
+                write!(f,
+                       "trailing data after segments, expected file length {} but got {}",
+                       expected,
+                       actual)
+            }
+            ParseError::UnknownOpcode {
+                index,
+                offset,
+                opcode,
+            } => {
+                write!(f,
+                       "unknown opcode {:#04x} of instruction {} at offset {}",
+                       opcode,
+                       index,
+                       offset)
+            }
+            ParseError::TruncatedInstruction { index, offset } => {
+                write!(f,
+                       "truncated operand of instruction {} at offset {}",
+                       index,
+                       offset)
+            }
+            ParseError::MissingInstructions { expected, actual } => {
+                write!(f,
+                       "code segment ends after {} of {} instructions",
+                       actual,
+                       expected)
+            }
+            ParseError::InvalidSymbol { line, offset } => {
+                write!(f, "invalid symbol in line {} at offset {}", line, offset)
+            }
+        }
     }
 }
 
+impl error::Error for ParseError {}
 
-instruction!(instruction_undef, Opcode::UNDEF, Instruction::UNDEF);
-
-instruction!(instruction_ignore, Opcode::IGNORE, Instruction::IGNORE);
-
-instruction!(instruction_break, Opcode::BREAK, Instruction::BREAK);
-
-instruction_u32!(instruction_enter, Opcode::ENTER, Instruction::ENTER);
-instruction_u32!(instruction_leave, Opcode::LEAVE, Instruction::LEAVE);
-instruction!(instruction_call, Opcode::CALL, Instruction::CALL);
-instruction!(instruction_push, Opcode::PUSH, Instruction::PUSH);
-instruction!(instruction_pop, Opcode::POP, Instruction::POP);
-
-instruction_u32!(instruction_const, Opcode::CONST, Instruction::CONST);
-instruction_u32!(instruction_local, Opcode::LOCAL, Instruction::LOCAL);
-
-instruction!(instruction_jump, Opcode::JUMP, Instruction::JUMP);
-
-instruction_u32!(instruction_eq, Opcode::EQ, Instruction::EQ);
-instruction_u32!(instruction_ne, Opcode::NE, Instruction::NE);
-
-instruction_u32!(instruction_lti, Opcode::LTI, Instruction::LTI);
-instruction_u32!(instruction_lei, Opcode::LEI, Instruction::LEI);
-instruction_u32!(instruction_gti, Opcode::GTI, Instruction::GTI);
-instruction_u32!(instruction_gei, Opcode::GEI, Instruction::GEI);
-
-instruction_u32!(instruction_ltu, Opcode::LTU, Instruction::LTU);
-instruction_u32!(instruction_leu, Opcode::LEU, Instruction::LEU);
-instruction_u32!(instruction_gtu, Opcode::GTU, Instruction::GTU);
-instruction_u32!(instruction_geu, Opcode::GEU, Instruction::GEU);
-
-instruction_u32!(instruction_eqf, Opcode::EQF, Instruction::EQF);
-instruction_u32!(instruction_nef, Opcode::NEF, Instruction::NEF);
-
-instruction_u32!(instruction_ltf, Opcode::LTF, Instruction::LTF);
-instruction_u32!(instruction_lef, Opcode::LEF, Instruction::LEF);
-instruction_u32!(instruction_gtf, Opcode::GTF, Instruction::GTF);
-instruction_u32!(instruction_gef, Opcode::GEF, Instruction::GEF);
-
-instruction!(instruction_load1, Opcode::LOAD1, Instruction::LOAD1);
-instruction!(instruction_load2, Opcode::LOAD2, Instruction::LOAD2);
-instruction!(instruction_load4, Opcode::LOAD4, Instruction::LOAD4);
-instruction!(instruction_store1, Opcode::STORE1, Instruction::STORE1);
-instruction!(instruction_store2, Opcode::STORE2, Instruction::STORE2);
-instruction!(instruction_store4, Opcode::STORE4, Instruction::STORE4);
-instruction_u8!(instruction_arg, Opcode::ARG, Instruction::ARG);
-
-instruction_u32!(instruction_block_copy,
-                 Opcode::BLOCK_COPY,
-                 Instruction::BLOCK_COPY);
-
-instruction!(instruction_sex8, Opcode::SEX8, Instruction::SEX8);
-instruction!(instruction_sex16, Opcode::SEX16, Instruction::SEX16);
-
-instruction!(instruction_negi, Opcode::NEGI, Instruction::NEGI);
-instruction!(instruction_add, Opcode::ADD, Instruction::ADD);
-instruction!(instruction_sub, Opcode::SUB, Instruction::SUB);
-instruction!(instruction_divi, Opcode::DIVI, Instruction::DIVI);
-instruction!(instruction_divu, Opcode::DIVU, Instruction::DIVU);
-instruction!(instruction_modi, Opcode::MODI, Instruction::MODI);
-instruction!(instruction_modu, Opcode::MODU, Instruction::MODU);
-instruction!(instruction_muli, Opcode::MULI, Instruction::MULI);
-instruction!(instruction_mulu, Opcode::MULU, Instruction::MULU);
-
-instruction!(instruction_band, Opcode::BAND, Instruction::BAND);
-instruction!(instruction_bor, Opcode::BOR, Instruction::BOR);
-instruction!(instruction_bxor, Opcode::BXOR, Instruction::BXOR);
-instruction!(instruction_bcom, Opcode::BCOM, Instruction::BCOM);
-
-instruction!(instruction_lsh, Opcode::LSH, Instruction::LSH);
-instruction!(instruction_rshi, Opcode::RSHI, Instruction::RSHI);
-instruction!(instruction_rshu, Opcode::RSHU, Instruction::RSHU);
-
-instruction!(instruction_negf, Opcode::NEGF, Instruction::NEGF);
-instruction!(instruction_addf, Opcode::ADDF, Instruction::ADDF);
-instruction!(instruction_subf, Opcode::SUBF, Instruction::SUBF);
-instruction!(instruction_divf, Opcode::DIVF, Instruction::DIVF);
-instruction!(instruction_mulf, Opcode::MULF, Instruction::MULF);
-
-instruction!(instruction_cvif, Opcode::CVIF, Instruction::CVIF);
-instruction!(instruction_cvfi, Opcode::CVFI, Instruction::CVFI);
-
-
-named!(ins<InputSlice,Instruction>,
-    alt!(instruction_undef
-        | instruction_ignore
-        | instruction_break
-        | instruction_enter | instruction_leave | instruction_call | instruction_push | instruction_pop
-        | instruction_const | instruction_local
-        | instruction_jump
-        | instruction_eq | instruction_ne
-        | instruction_lti | instruction_lei | instruction_gti | instruction_gei
-        | instruction_ltu | instruction_leu | instruction_gtu | instruction_geu
-        | instruction_eqf | instruction_nef
-        | instruction_ltf | instruction_lef | instruction_gtf | instruction_gef
-        | instruction_load1 | instruction_load2 | instruction_load4 | instruction_store1 | instruction_store2 | instruction_store4 | instruction_arg
-        | instruction_block_copy
-        | instruction_sex8 | instruction_sex16
-        | instruction_negi | instruction_add | instruction_sub | instruction_divi | instruction_divu | instruction_modi | instruction_modu | instruction_muli | instruction_mulu
-        | instruction_band | instruction_bor | instruction_bxor | instruction_bcom
-        | instruction_lsh  | instruction_rshi | instruction_rshu
-        | instruction_negf | instruction_addf | instruction_subf | instruction_divf | instruction_mulf
-        | instruction_cvif | instruction_cvfi
-    )
-);
+/// The raw header fields of a QVM file.
+#[derive(Debug, PartialEq)]
+struct Header {
+    version: Version,
+    instruction_count: u32,
+    code_offset: u32,
+    code_length: u32,
+    data_offset: u32,
+    data_length: u32,
+    lit_length: u32,
+    bss_length: u32,
+    jtrg_length: Option<u32>,
+}
 
-macro_rules! length_size(
-    ($i:expr, $s:expr, $submac:ident!( $($args:tt)* )) => (
-    {
-        match take!($i, $s as usize) {
-            nom::IResult::Error(e)                         => nom::IResult::Error(e),
-            nom::IResult::Incomplete(nom::Needed::Unknown) => nom::IResult::Incomplete(nom::Needed::Unknown),
-            nom::IResult::Incomplete(nom::Needed::Size(n)) => {
-                nom::IResult::Incomplete(nom::Needed::Size(
-                    n + nom::InputLength::input_len(&($i)) - ($s)
-                ))
-            },
-            nom::IResult::Done(i2, o2)  => {
-                match complete!(o2, $submac!($($args)*)) {
-                    nom::IResult::Error(e)      => nom::IResult::Error(e),
-                    nom::IResult::Incomplete(i) => nom::IResult::Incomplete(i),
-                    nom::IResult::Done(_, o3)   => nom::IResult::Done(i2, o3)
-                }
-            }
+/// The result of parsing.
+pub type ParseResult<T> = ::std::result::Result<T, ParseError>;
+
+fn header_field(data: InputSlice, field: HeaderField) -> ParseResult<u32> {
+    let offset = field.offset();
+    match data.get(offset..offset + 4) {
+        Some(bytes) => Ok(le_u32(bytes)),
+        None => {
+            Err(ParseError::UnexpectedEof {
+                    field,
+                    length: data.len(),
+                })
         }
     }
-    );
+}
 
-  ($i:expr, $submac:ident!( $($args:tt)* ), $g:expr) => (
-    length_value!($i, $submac!($($args)*), call!($g));
-  );
+fn header(data: InputSlice) -> ParseResult<Header> {
+    let version = match data.get(0..4) {
+        Some(magic) if magic == VM_MAGIC => Version::V1,
+        Some(magic) if magic == VM_MAGIC_VER2 => Version::V2,
+        Some(magic) => {
+            return Err(ParseError::UnknownMagic([magic[0], magic[1], magic[2], magic[3]]))
+        }
+        None => {
+            return Err(ParseError::UnexpectedEof {
+                           field: HeaderField::Magic,
+                           length: data.len(),
+                       })
+        }
+    };
+    Ok(Header {
+           version,
+           instruction_count: header_field(data, HeaderField::InstructionCount)?,
+           code_offset: header_field(data, HeaderField::CodeOffset)?,
+           code_length: header_field(data, HeaderField::CodeLength)?,
+           data_offset: header_field(data, HeaderField::DataOffset)?,
+           data_length: header_field(data, HeaderField::DataLength)?,
+           lit_length: header_field(data, HeaderField::LitLength)?,
+           bss_length: header_field(data, HeaderField::BssLength)?,
+           jtrg_length: match version {
+               Version::V1 => None,
+               Version::V2 => Some(header_field(data, HeaderField::JtrgLength)?),
+           },
+       })
+}
 
-  ($i:expr, $f:expr, $submac:ident!( $($args:tt)* )) => (
-    length_value!($i, call!($f), $submac!($($args)*));
-  );
+/// Checks the header fields against each other, the file length and limits.
+///
+/// All arithmetic is done in `u64`, so crafted fields can not overflow.
+fn check_header(header: &Header, file_length: usize, limits: &Limits) -> ParseResult<()> {
+    let header_length = match header.version {
+        Version::V1 => HEADER_LENGTH_V1,
+        Version::V2 => HEADER_LENGTH_V2,
+    };
+    if header.code_offset < header_length {
+        return Err(ParseError::InvalidField {
+                       field: HeaderField::CodeOffset,
+                       value: header.code_offset,
+                       reason: "overlaps header",
+                   });
+    }
+    if (header.data_offset as u64) < header.code_offset as u64 + header.code_length as u64 {
+        return Err(ParseError::InvalidField {
+                       field: HeaderField::DataOffset,
+                       value: header.data_offset,
+                       reason: "overlaps code segment",
+                   });
+    }
 
-  ($i:expr, $f:expr, $g:expr) => (
-    length_value!($i, call!($f), call!($g));
-  );
-);
+    let data_offset = header.data_offset as u64;
+    let lit_offset = data_offset + header.data_length as u64;
+    let jtrg_offset = lit_offset + header.lit_length as u64;
+    let segments = [(Segment::CODE, header.code_offset as u64, header.code_length),
+                    (Segment::DATA, data_offset, header.data_length),
+                    (Segment::LIT, lit_offset, header.lit_length),
+                    (Segment::JTRG, jtrg_offset, header.jtrg_length.unwrap_or(0))];
+    for &(segment, offset, length) in &segments {
+        if offset + length as u64 > file_length as u64 {
+            return Err(ParseError::SegmentOutOfBounds {
+                           segment,
+                           offset,
+                           length,
+                           file_length,
+                       });
+        }
+    }
+    let file_end = jtrg_offset + header.jtrg_length.unwrap_or(0) as u64;
+    if file_end < file_length as u64 {
+        return Err(ParseError::TrailingData {
+                       expected: file_end,
+                       actual: file_length,
+                   });
+    }
 
-const HEADER_LENGTH_V1: u32 = 32;
+    if header.data_length % 4 != 0 {
+        return Err(ParseError::InvalidField {
+                       field: HeaderField::DataLength,
+                       value: header.data_length,
+                       reason: "is not a multiple of 4",
+                   });
+    }
+    if let Some(jtrg_length) = header.jtrg_length {
+        if jtrg_length % 4 != 0 {
+            return Err(ParseError::InvalidField {
+                           field: HeaderField::JtrgLength,
+                           value: jtrg_length,
+                           reason: "is not a multiple of 4",
+                       });
+        }
+    }
+    // Every instruction is encoded in at least one octet
+    if header.instruction_count > header.code_length {
+        return Err(ParseError::InvalidField {
+                       field: HeaderField::InstructionCount,
+                       value: header.instruction_count,
+                       reason: "exceeds code segment length",
+                   });
+    }
+    if header.instruction_count > limits.max_instructions {
+        return Err(ParseError::LimitExceeded {
+                       field: HeaderField::InstructionCount,
+                       limit: limits.max_instructions as u64,
+                       actual: header.instruction_count as u64,
+                   });
+    }
+    let memory_size = header.data_length as u64 + header.lit_length as u64 +
+                      header.bss_length as u64;
+    if memory_size > limits.max_memory_size {
+        return Err(ParseError::LimitExceeded {
+                       field: HeaderField::BssLength,
+                       limit: limits.max_memory_size,
+                       actual: memory_size,
+                   });
+    }
+    Ok(())
+}
 
-named!(qvm<InputSlice, QVM>,
-    do_parse!(
-        tag!(VM_MAGIC)                                  >>
-//        magic: le_u32                                   >>
-        instruction_count: le_u32                       >>
-        code_offset: le_u32                             >>
-        code_length: le_u32                             >>
-        data_offset: le_u32                             >>
-        data_length: le_u32                             >>
-        lit_length: le_u32                              >>
-        bss_length: le_u32                              >>
-        // Read padding between header and code segment
-        take!(code_offset - HEADER_LENGTH_V1)           >>
-        code: length_size!(
-            code_length as usize,
-            count!(ins, instruction_count as usize)
-        )                                               >>
-        // Read padding between code and data segment
-        take!(data_offset - code_offset - code_length)  >>
-        data: length_size!(
-            data_length as usize,
-            count!(le_u32, data_length as usize / 4)
-        )                                               >>
-        // lit segment is always aligned, no padding here
-        lit: length_size!(
-            lit_length as usize,
-            count!(le_u8, lit_length as usize)
-        )                                               >>
-        eof!()                                          >>
-        (
-            QVM {
-                code: code,
-                data: data,
-                lit: lit,
-                bss_length: bss_length,
+/// A lazy decoder for the instructions of a code segment.
+///
+/// Yields the index, file offset and instruction, or the first error after
+/// which iteration stops.
+#[derive(Debug, Clone)]
+pub struct Instructions<'a> {
+    code: InputSlice<'a>,
+    offset: usize,
+    position: usize,
+    index: usize,
+    count: Option<usize>,
+    done: bool,
+}
+
+impl<'a> Instructions<'a> {
+    /// Creates a decoder for all instructions in `code`.
+    ///
+    /// `offset` is the file offset of `code`, which is added to the offsets
+    /// of the instructions.
+    pub fn new(code: InputSlice<'a>, offset: usize) -> Instructions<'a> {
+        Instructions {
+            code,
+            offset,
+            position: 0,
+            index: 0,
+            count: None,
+            done: false,
+        }
+    }
+
+    /// Creates a decoder for exactly `count` instructions in `code`.
+    ///
+    /// Fails with `ParseError::MissingInstructions` if `code` ends early.
+    pub fn with_count(code: InputSlice<'a>, offset: usize, count: u32) -> Instructions<'a> {
+        Instructions {
+            count: Some(count as usize),
+            ..Instructions::new(code, offset)
+        }
+    }
+
+    /// Returns the file offset of the next instruction.
+    pub fn offset(&self) -> usize {
+        self.offset + self.position
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = ::std::result::Result<(usize, usize, Instruction), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || Some(self.index) == self.count {
+            return None;
+        }
+        let index = self.index;
+        let offset = self.offset();
+        let error = match decode_instruction(&self.code[self.position..]) {
+            Ok((instruction, size)) => {
+                self.position += size;
+                self.index += 1;
+                return Some(Ok((index, offset, instruction)));
             }
-        )
-    )
-);
+            Err(DecodeError::Eof) => {
+                // Without an instruction count, the end of the code is not an error
+                let count = self.count?;
+                ParseError::MissingInstructions {
+                    expected: count as u32,
+                    actual: index,
+                }
+            }
+            Err(DecodeError::UnknownOpcode(opcode)) => {
+                ParseError::UnknownOpcode {
+                    index,
+                    offset,
+                    opcode,
+                }
+            }
+            Err(DecodeError::Truncated) => ParseError::TruncatedInstruction { index, offset },
+        };
+        self.done = true;
+        Some(Err(error))
+    }
+}
+
+/// Decodes `count` instructions from the code segment at `offset`.
+fn instructions(code: InputSlice, offset: usize, count: u32) -> ParseResult<Vec<Instruction>> {
+    let mut instructions = Vec::with_capacity(count as usize);
+    for item in Instructions::with_count(code, offset, count) {
+        let (_, _, instruction) = item?;
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+/// Decodes little-endian words, ignoring any incomplete last word.
+fn words(data: InputSlice) -> Vec<u32> {
+    data.chunks(4)
+        .filter(|word| word.len() == 4)
+        .map(le_u32)
+        .collect()
+}
+
+/// Parses a QVM without semantic validation.
+fn qvm(data: InputSlice, limits: &Limits) -> ParseResult<QVM> {
+    let header = header(data)?;
+    check_header(&header, data.len(), limits)?;
+
+    // `check_header` ensures that all of these ranges are within `data`
+    let code_start = header.code_offset as usize;
+    let code_end = code_start + header.code_length as usize;
+    let data_start = header.data_offset as usize;
+    let lit_start = data_start + header.data_length as usize;
+    let jtrg_start = lit_start + header.lit_length as usize;
+
+    Ok(QVM {
+           code: instructions(&data[code_start..code_end],
+                              code_start,
+                              header.instruction_count)?,
+           data: words(&data[data_start..lit_start]),
+           lit: data[lit_start..jtrg_start].to_vec(),
+           bss_length: header.bss_length,
+           jtrg: header.jtrg_length.map(|_| words(&data[jtrg_start..])),
+           symbols: None,
+       })
+}
+
+/// Returns a lazy decoder for the code segment of a QVM file.
+///
+/// Unlike `parse_qvm`, this only checks the header and does not decode or
+/// validate anything up front, e.g. to scan huge VMs or map between
+/// instruction indices and file offsets.
+pub fn code_instructions(data: InputSlice) -> ParseResult<Instructions> {
+    let header = header(data)?;
+    check_header(&header, data.len(), &Limits::default())?;
+    let code_start = header.code_offset as usize;
+    let code_end = code_start + header.code_length as usize;
+    Ok(Instructions::with_count(&data[code_start..code_end],
+                                code_start,
+                                header.instruction_count))
+}
 
 
 /// Tries to parse a QVM from a byte slice.
+///
+/// The parsed VM is validated like in `QVM::new`.
 pub fn parse_qvm(data: InputSlice) -> Result<QVM> {
-    match qvm(data).to_full_result() {
-        Ok(v) => Ok(v),
-        Err(e) => Err(ErrorKind::Parser(e).into()),
+    parse_qvm_with_limits(data, &Limits::default())
+}
+
+/// Tries to parse a QVM from a byte slice, with custom limits.
+///
+/// This never panics, no matter how malformed `data` is.
+///
+/// # Errors
+/// Returns `Error::Parser` with the location of the first issue, or
+/// `Error::Invalid` if the VM fails validation.
+pub fn parse_qvm_with_limits(data: InputSlice, limits: &Limits) -> Result<QVM> {
+    qvm(data, limits)?.validated()
+}
+
+
+named!(segment<InputSlice, Segment>,
+    alt!(
+        value!(Segment::CODE, char!('0'))
+      | value!(Segment::DATA, char!('1'))
+      | value!(Segment::LIT, char!('2'))
+      | value!(Segment::BSS, char!('3'))
+      | value!(Segment::JTRG, char!('4'))
+    )
+);
+
+// q3asm writes `"%d %8x %s\n"`, i.e. the address is padded with spaces
+named!(symbol<InputSlice, Symbol>,
+    do_parse!(
+        segment: segment                                >>
+        space                                           >>
+        address: hex_u32                                >>
+        space                                           >>
+        name: map_res!(is_not!(" \t\r\n"), str::from_utf8) >>
+        opt!(complete!(line_ending))                    >>
+        (Symbol::new(segment, address, name.to_string()))
+    )
+);
+
+/// Tries to parse a q3asm symbol map from a byte slice.
+///
+/// These are the `.map` files written by `q3asm -m`.
+///
+/// # Errors
+/// Returns `ParseError::InvalidSymbol` for the first malformed line.
+pub fn parse_symbol_map(data: InputSlice) -> ParseResult<SymbolMap> {
+    let mut map = SymbolMap::new();
+    let mut input = data;
+    let mut line = 1;
+    while !input.is_empty() {
+        match symbol(input) {
+            nom::IResult::Done(rest, symbol) => {
+                map.insert(symbol);
+                input = rest;
+                line += 1;
+            }
+            _ => {
+                return Err(ParseError::InvalidSymbol {
+                               line,
+                               offset: data.len() - input.len(),
+                           })
+            }
+        }
     }
+    Ok(map)
 }
 
 
 #[cfg(test)]
+// The fixture tests spell out LIT bytes as `'H' as u8`
+#[allow(clippy::char_lit_as_u8)]
 mod tests {
-    use super::{instruction_break, instruction_enter, instruction_arg, ins, qvm, parse_qvm,
-                InputSlice};
+    use super::{decode_instruction, instructions, code_instructions, qvm, parse_qvm,
+                parse_qvm_with_limits, symbol, parse_symbol_map, DecodeError, HeaderField,
+                Instructions, Limits, ParseError};
+    use errors::Error;
+    use symbols::Symbol;
+    use validation::Violation;
     use bytecode::Instruction;
     use nom::IResult;
-    use nom;
-    use QVM;
+    use {QVM, Segment, Version};
 
     /// q3asm reserves the stack in the BSS segment
     const Q3ASM_STACK_SIZE: usize = 0x10000;
@@ -253,79 +694,121 @@ mod tests {
     #[test]
     fn test_instruction_break_exact_match() {
         let data = [0x2];
-        let result = instruction_break(&data);
-        assert_eq!(result, IResult::Done(&b""[..], Instruction::BREAK));
+        let result = decode_instruction(&data);
+        assert_eq!(result, Ok((Instruction::BREAK, 1)));
     }
 
     #[test]
-    fn test_instruction_break_tag_mismatch() {
-        let data = [0x0];
-        let result = instruction_break(&data);
-        assert_eq!(result, IResult::Error(nom::ErrorKind::Tag));
+    fn test_instruction_unknown_opcode() {
+        let data = [0x3c];
+        let result = decode_instruction(&data);
+        assert_eq!(result, Err(DecodeError::UnknownOpcode(0x3c)));
     }
 
     #[test]
     fn test_instruction_enter_exact_match() {
         let data = [0x3, 0x42, 0x0, 0x0, 0x0];
-        let result = instruction_enter(&data);
-        assert_eq!(result, IResult::Done(&b""[..], Instruction::ENTER(0x42)));
+        let result = decode_instruction(&data);
+        assert_eq!(result, Ok((Instruction::ENTER(0x42), 5)));
+    }
+
+    #[test]
+    fn test_instruction_enter_truncated() {
+        let data = [0x3, 0x42, 0x0, 0x0];
+        let result = decode_instruction(&data);
+        assert_eq!(result, Err(DecodeError::Truncated));
     }
 
     #[test]
     fn test_instruction_arg_exact_match() {
         let data = [0x21, 0x42];
-        let result = instruction_arg(&data);
-        assert_eq!(result, IResult::Done(&b""[..], Instruction::ARG(0x42)));
+        let result = decode_instruction(&data);
+        assert_eq!(result, Ok((Instruction::ARG(0x42), 2)));
     }
 
     #[test]
-    fn test_ins_enter_exact_match() {
-        let data = [0x3, 0x42, 0x0, 0x0, 0x0];
-        let result = ins(&data);
-        assert_eq!(result, IResult::Done(&b""[..], Instruction::ENTER(0x42)));
+    fn test_instruction_trailing_input() {
+        let data = [0x3, 0x42, 0x0, 0x0, 0x0, 0x2];
+        let result = decode_instruction(&data);
+        assert_eq!(result, Ok((Instruction::ENTER(0x42), 5)));
+    }
+
+    #[test]
+    fn test_instructions_offsets() {
+        let data = include_bytes!("../assets/mod-syscall.qvm");
+        let mut instructions = code_instructions(data).unwrap();
+        assert_eq!(instructions.next(), Some(Ok((0, 32, Instruction::ENTER(12)))));
+        assert_eq!(instructions.next(), Some(Ok((1, 37, Instruction::CONST(4)))));
+        assert_eq!(instructions.next(), Some(Ok((2, 42, Instruction::ARG(8)))));
+        assert_eq!(instructions.offset(), 44);
+        assert_eq!(instructions.count(), 7);
+    }
+
+    #[test]
+    fn test_instructions_stop_at_error() {
+        let code = [0x2, 0x3, 0x8];
+        let result: Vec<_> = Instructions::new(&code, 100).collect();
+        assert_eq!(result,
+                   vec![Ok((0, 100, Instruction::BREAK)),
+                        Err(ParseError::TruncatedInstruction {
+                                index: 1,
+                                offset: 101,
+                            })]);
+        let code = [0x2];
+        let result: Vec<_> = Instructions::with_count(&code, 0, 2).collect();
+        assert_eq!(result,
+                   vec![Ok((0, 0, Instruction::BREAK)),
+                        Err(ParseError::MissingInstructions {
+                                expected: 2,
+                                actual: 1,
+                            })]);
     }
 
     #[test]
     fn test_qvm_file_minimal() {
         let data = include_bytes!("../assets/mod-minimal.qvm");
-        let result = qvm(data);
+        let result = qvm(data, &Limits::default()).unwrap();
         let expected = QVM {
             code: vec![Instruction::ENTER(8),
-                       Instruction::CONST(4294967295), // TODO: This is actually -1, need to rethink types!
+                       Instruction::const_i32(-1),
                        Instruction::LEAVE(8),
                        Instruction::PUSH,
                        Instruction::LEAVE(8)],
             data: vec![0],
             lit: vec![],
             bss_length: Q3ASM_STACK_SIZE as u32,
+            jtrg: None,
+            symbols: None,
         };
-        assert_eq!(result, IResult::Done(&b""[..], expected));
+        assert_eq!(result, expected);
     }
 
     #[test]
     fn test_qvm_file_bss() {
         let data = include_bytes!("../assets/mod-bss.qvm");
-        let result = qvm(data);
+        let result = qvm(data, &Limits::default()).unwrap();
         let expected = QVM {
             code: vec![Instruction::ENTER(8),
-                       Instruction::CONST(4294967295), // TODO: This is actually -1, need to rethink types!
+                       Instruction::const_i32(-1),
                        Instruction::LEAVE(8),
                        Instruction::PUSH,
                        Instruction::LEAVE(8)],
             data: vec![0],
             lit: vec![],
             bss_length: Q3ASM_STACK_SIZE as u32 + 4,
+            jtrg: None,
+            symbols: None,
         };
-        assert_eq!(result, IResult::Done(&b""[..], expected));
+        assert_eq!(result, expected);
     }
 
     #[test]
     fn test_qvm_file_data() {
         let data = include_bytes!("../assets/mod-data.qvm");
-        let result = qvm(data);
+        let result = qvm(data, &Limits::default()).unwrap();
         let expected = QVM {
             code: vec![Instruction::ENTER(8),
-                       Instruction::CONST(4294967295), // TODO: This is actually -1, need to rethink types!
+                       Instruction::const_i32(-1),
                        Instruction::LEAVE(8),
                        Instruction::PUSH,
                        Instruction::LEAVE(8)],
@@ -335,18 +818,20 @@ mod tests {
             ],
             lit: vec![],
             bss_length: Q3ASM_STACK_SIZE as u32,
+            jtrg: None,
+            symbols: None,
         };
-        assert_eq!(result, IResult::Done(&b""[..], expected));
+        assert_eq!(result, expected);
     }
 
     #[test]
     fn test_qvm_file_lit() {
         let data = include_bytes!("../assets/mod-lit.qvm");
-        let result = qvm(data);
+        let result = qvm(data, &Limits::default()).unwrap();
         let expected = QVM {
             code: vec![
                 Instruction::ENTER(8),
-                Instruction::CONST(4294967295), // TODO: This is actually -1, need to rethink types!
+                Instruction::const_i32(-1),
                 Instruction::LEAVE(8),
                 Instruction::PUSH,
                 Instruction::LEAVE(8),
@@ -357,23 +842,25 @@ mod tests {
                 0, 0,
             ],
             bss_length: Q3ASM_STACK_SIZE as u32,
+            jtrg: None,
+            symbols: None,
         };
-        assert_eq!(result, IResult::Done(&b""[..], expected));
+        assert_eq!(result, expected);
     }
 
     #[test]
     fn test_qvm_file_syscall() {
         let data = include_bytes!("../assets/mod-syscall.qvm");
-        let result = qvm(data);
+        let result = qvm(data, &Limits::default()).unwrap();
         let expected = QVM {
             code: vec![
                 Instruction::ENTER(12),
                 Instruction::CONST(4),
                 Instruction::ARG(8),
-                Instruction::CONST(4294966630), // TODO: This is actually -666, need to rethink types!
+                Instruction::const_i32(-666),
                 Instruction::CALL,
                 Instruction::POP,
-                Instruction::CONST(4294967295), // TODO: This is actually -1, need to rethink types!
+                Instruction::const_i32(-1),
                 Instruction::LEAVE(12),
                 Instruction::PUSH,
                 Instruction::LEAVE(12),
@@ -398,32 +885,316 @@ mod tests {
                 0,
             ],
             bss_length: Q3ASM_STACK_SIZE as u32,
+            jtrg: None,
+            symbols: None,
         };
-        assert_eq!(result, IResult::Done(&b""[..], expected));
+        assert_eq!(result, expected);
     }
 
 
     #[test]
     fn test_ins_file() {
         let data = include_bytes!("../assets/mod-minimal.qvm");
-        named!(ins5<InputSlice,Vec<Instruction>>, count!(ins, 5));
-        let result = ins5(&data[32..53]);
+        let result = instructions(&data[32..53], 32, 5);
         let expected = vec![Instruction::ENTER(8),
-                            Instruction::CONST(4294967295), // TODO: This is actually -1, need to rethink types!
+                            Instruction::const_i32(-1),
                             Instruction::LEAVE(8),
                             Instruction::PUSH,
                             Instruction::LEAVE(8)];
-        assert_eq!(result, IResult::Done(&b""[..], expected));
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn test_qvm_v2() {
+        let data = [
+            0x45, 0x14, 0x72, 0x12, // magic
+            0x02, 0x00, 0x00, 0x00, // instruction_count
+            0x24, 0x00, 0x00, 0x00, // code_offset
+            0x04, 0x00, 0x00, 0x00, // code_length
+            0x28, 0x00, 0x00, 0x00, // data_offset
+            0x04, 0x00, 0x00, 0x00, // data_length
+            0x00, 0x00, 0x00, 0x00, // lit_length
+            0x00, 0x00, 0x00, 0x00, // bss_length
+            0x08, 0x00, 0x00, 0x00, // jtrg_length
+            0x07, 0x07, 0x00, 0x00, // code
+            0x00, 0x00, 0x00, 0x00, // data
+            0x00, 0x00, 0x00, 0x00, // jtrg
+            0x01, 0x00, 0x00, 0x00,
+        ];
+        let result = qvm(&data, &Limits::default()).unwrap();
+        let expected = QVM {
+            code: vec![Instruction::POP, Instruction::POP],
+            data: vec![0],
+            lit: vec![],
+            bss_length: 0,
+            jtrg: Some(vec![0, 1]),
+            symbols: None,
+        };
+        assert_eq!(result, expected);
     }
 
     // TODO: This is more of an integration test
     #[test]
-    // TODO: This test won't work due to v2 magic, which is unimplemented
-    #[ignore]
     fn test_parse_qvm_ioq3_qagame() {
         let data = include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm");
         let result = parse_qvm(data).unwrap();
-        // TODO: What to assert here?
+        assert_eq!(result.version(), Version::V1);
+        assert_eq!(result.instructions().len(), 0x24d44);
+        assert_eq!(result.jump_targets(), None);
+    }
+
+    fn header(fields: [u32; 7]) -> Vec<u8> {
+        let mut data = vec![0x44, 0x14, 0x72, 0x12];
+        for field in &fields {
+            data.extend_from_slice(&[*field as u8,
+                                     (*field >> 8) as u8,
+                                     (*field >> 16) as u8,
+                                     (*field >> 24) as u8]);
+        }
+        data
+    }
+
+    fn parse_error(data: &[u8], limits: &Limits) -> ParseError {
+        match parse_qvm_with_limits(data, limits) {
+            Err(Error::Parser(e)) => e,
+            result => panic!("expected parse error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_qvm_invalid_header() {
+        let limits = Limits::default();
+        // instructionCount, codeOffset, codeLength, dataOffset, dataLength, litLength, bssLength
+        assert_eq!(parse_error(&header([0, 0, 0, 32, 0, 0, 0]), &limits),
+                   ParseError::InvalidField {
+                       field: HeaderField::CodeOffset,
+                       value: 0,
+                       reason: "overlaps header",
+                   });
+        assert_eq!(parse_error(&header([0, 32, 4, 32, 0, 0, 0]), &limits),
+                   ParseError::InvalidField {
+                       field: HeaderField::DataOffset,
+                       value: 32,
+                       reason: "overlaps code segment",
+                   });
+        assert_eq!(parse_error(&header([0, 32, 0, 0xffffffff, 0xffffffff, 0xffffffff, 0]),
+                               &limits),
+                   ParseError::SegmentOutOfBounds {
+                       segment: Segment::DATA,
+                       offset: 0xffffffff,
+                       length: 0xffffffff,
+                       file_length: 32,
+                   });
+        let mut data = header([0, 32, 0, 32, 0, 0, 0]);
+        data.push(0);
+        assert_eq!(parse_error(&data, &limits),
+                   ParseError::TrailingData {
+                       expected: 32,
+                       actual: 33,
+                   });
+        let mut data = header([0, 32, 0, 32, 2, 0, 0]);
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(parse_error(&data, &limits),
+                   ParseError::InvalidField {
+                       field: HeaderField::DataLength,
+                       value: 2,
+                       reason: "is not a multiple of 4",
+                   });
+        let mut data = header([0xffffffff, 32, 4, 36, 0, 0, 0]);
+        data.extend_from_slice(&[0x3, 0x8, 0, 0]);
+        assert_eq!(parse_error(&data, &limits),
+                   ParseError::InvalidField {
+                       field: HeaderField::InstructionCount,
+                       value: 0xffffffff,
+                       reason: "exceeds code segment length",
+                   });
+        assert_eq!(parse_error(&header([0, 32, 0, 32, 0, 0, 0xffffffff]), &limits),
+                   ParseError::LimitExceeded {
+                       field: HeaderField::BssLength,
+                       limit: limits.max_memory_size,
+                       actual: 0xffffffff,
+                   });
+    }
+
+    #[test]
+    fn test_parse_qvm_invalid() {
+        match parse_qvm(&header([0, 32, 0, 32, 0, 0, 0])) {
+            Err(Error::Invalid(violations)) => {
+                assert_eq!(violations, vec![Violation::NoProcedure])
+            }
+            result => panic!("expected invalid VM, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_qvm_unexpected_eof() {
+        assert_eq!(parse_error(b"", &Limits::default()),
+                   ParseError::UnexpectedEof {
+                       field: HeaderField::Magic,
+                       length: 0,
+                   });
+        let data = &include_bytes!("../assets/mod-minimal.qvm")[..22];
+        assert_eq!(parse_error(data, &Limits::default()),
+                   ParseError::UnexpectedEof {
+                       field: HeaderField::DataLength,
+                       length: 22,
+                   });
+        assert_eq!(parse_error(b"\x7fELF", &Limits::default()),
+                   ParseError::UnknownMagic([0x7f, b'E', b'L', b'F']));
     }
 
+    #[test]
+    fn test_parse_qvm_invalid_code() {
+        let mut data = include_bytes!("../assets/mod-minimal.qvm").to_vec();
+        // ENTER(8) CONST(-1) LEAVE(8) ...
+        data[32 + 10] = 0xff;
+        assert_eq!(parse_error(&data, &Limits::default()),
+                   ParseError::UnknownOpcode {
+                       index: 2,
+                       offset: 42,
+                       opcode: 0xff,
+                   });
+        // 5 instructions in 21 octets, cut the last LEAVE(8) short
+        let mut data = header([5, 32, 18, 52, 0, 0, 0]);
+        data.extend_from_slice(&include_bytes!("../assets/mod-minimal.qvm")[32..50]);
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(parse_error(&data, &Limits::default()),
+                   ParseError::TruncatedInstruction {
+                       index: 4,
+                       offset: 48,
+                   });
+        let mut data = header([5, 32, 16, 48, 0, 0, 0]);
+        data.extend_from_slice(&include_bytes!("../assets/mod-minimal.qvm")[32..48]);
+        assert_eq!(parse_error(&data, &Limits::default()),
+                   ParseError::MissingInstructions {
+                       expected: 5,
+                       actual: 4,
+                   });
+    }
+
+    #[test]
+    fn test_parse_qvm_with_limits() {
+        let data = include_bytes!("../assets/mod-minimal.qvm");
+        let limits = Limits {
+            max_instructions: 4,
+            ..Limits::default()
+        };
+        assert_eq!(parse_error(data, &limits),
+                   ParseError::LimitExceeded {
+                       field: HeaderField::InstructionCount,
+                       limit: 4,
+                       actual: 5,
+                   });
+        let limits = Limits {
+            max_memory_size: 0x10000,
+            ..Limits::default()
+        };
+        assert_eq!(parse_error(data, &limits),
+                   ParseError::LimitExceeded {
+                       field: HeaderField::BssLength,
+                       limit: 0x10000,
+                       actual: 0x10004,
+                   });
+    }
+
+    #[test]
+    fn test_parse_error_display() {
+        let error = ParseError::UnknownOpcode {
+            index: 2,
+            offset: 42,
+            opcode: 0xff,
+        };
+        assert_eq!(error.to_string(),
+                   "unknown opcode 0xff of instruction 2 at offset 42");
+        let error = ParseError::InvalidField {
+            field: HeaderField::CodeOffset,
+            value: 0,
+            reason: "overlaps header",
+        };
+        assert_eq!(error.to_string(),
+                   "header field codeOffset at offset 8 with value 0x0 overlaps header");
+    }
+
+    #[test]
+    fn test_parse_qvm_truncated() {
+        let data = include_bytes!("../assets/mod-syscall.qvm");
+        for length in 0..data.len() {
+            assert!(parse_qvm(&data[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_qvm_corrupt_never_panics() {
+        let original = include_bytes!("../assets/mod-syscall.qvm");
+        // A simple LCG is good enough to flip bits in the header and code
+        let mut state: u32 = 0x5eed;
+        for _ in 0..10000 {
+            let mut data = original.to_vec();
+            for _ in 0..4 {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                let index = (state >> 16) as usize % data.len();
+                data[index] ^= 1 << (state % 8);
+            }
+            let _ = parse_qvm(&data);
+        }
+    }
+
+    #[test]
+    fn test_symbol_padded_address() {
+        let data = b"0        0 vmMain\n";
+        let result = symbol(data);
+        let expected = Symbol::new(Segment::CODE, 0, "vmMain".to_string());
+        assert_eq!(result, IResult::Done(&b""[..], expected));
+    }
+
+    #[test]
+    fn test_symbol_syscall() {
+        let data = b"0 fffffd66 trap_Print\n";
+        let result = symbol(data);
+        let expected = Symbol::new(Segment::CODE, 0xfffffd66, "trap_Print".to_string());
+        assert_eq!(result, IResult::Done(&b""[..], expected));
+    }
+
+    #[test]
+    fn test_parse_symbol_map_file_bss() {
+        let data = include_bytes!("../assets/mod-bss.map");
+        let result = parse_symbol_map(data).unwrap();
+        assert_eq!(result.len(), 4);
+        assert_eq!(result.resolve("uninitialized"), Some((Segment::BSS, 0)));
+        assert_eq!(result.resolve("_stackEnd"), Some((Segment::BSS, 0x10004)));
+        assert_eq!(result.name_at(Segment::CODE, 0), Some("vmMain"));
+    }
+
+    #[test]
+    fn test_parse_symbol_map_file_syscall() {
+        let data = include_bytes!("../assets/mod-syscall.map");
+        let result = parse_symbol_map(data).unwrap();
+        assert_eq!(result.syscall_name(-666), Some("trap_Print"));
+        assert_eq!(result.get("trap_Print").unwrap().syscall(), Some(-666));
+        assert_eq!(result.name_at(Segment::CODE, 0), Some("vmMain"));
+    }
+
+    #[test]
+    fn test_parse_symbol_map_without_trailing_newline() {
+        let result = parse_symbol_map(b"1        4 initialized").unwrap();
+        assert_eq!(result.resolve("initialized"), Some((Segment::DATA, 4)));
+    }
+
+    #[test]
+    fn test_parse_symbol_map_invalid() {
+        assert!(parse_symbol_map(b"5 0 bogus\n").is_err());
+        match parse_symbol_map(b"0 0 vmMain\n1 bogus\n") {
+            Err(ParseError::InvalidSymbol { line: 2, offset: 11 }) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_symbol_map_ioq3_qagame() {
+        let data = include_bytes!("../assets/ioq3/baseq3/vm/qagame.map");
+        let result = parse_symbol_map(data).unwrap();
+        assert_eq!(result.len(), 1248);
+        assert_eq!(result.resolve("G_InitGame"), Some((Segment::CODE, 0x2cb)));
+        assert_eq!(result.syscall_name(-1), Some("trap_Print"));
+    }
 }
@@ -0,0 +1,844 @@
+//! An assembler for LCC bytecode, compatible with ioquake3's q3asm.
+//!
+//! LCC compiles C sources to a textual stack machine bytecode (`.asm`),
+//! which q3asm then assembles and links into a single QVM.
+//! See `tools/asm/q3asm.c` and `tools/asm/opstrings.h` in ioquake3.
+
+use super::{QVM, Segment, Version};
+use bytecode::{Instruction, Literal};
+use symbols::{Symbol, SymbolMap};
+use super::errors::*;
+use std::collections::HashMap;
+
+/// Size of the program stack that q3asm reserves in the BSS segment.
+pub const DEFAULT_STACK_SIZE: u32 = 0x10000;
+
+const NUM_SEGMENTS: usize = 5;
+
+/// How q3asm translates an LCC operation.
+#[derive(Clone, Copy)]
+enum SourceOp {
+    /// Conversions that are no-ops on the QVM.
+    Ignore,
+    /// Operations that can not be expressed on the QVM.
+    Undefined,
+    /// `CVII4` needs to check the source size for `SEX8` or `SEX16`.
+    SignExtend,
+    /// An instruction without operand; a trailing source size is dropped.
+    Plain(Instruction),
+    /// An instruction with an operand expression.
+    Operand(fn(Literal) -> Instruction),
+    /// `ASGNB`, whose block size is rounded up to full words.
+    BlockCopy,
+}
+
+/// See ioquake3's `tools/asm/opstrings.h`
+fn source_op(name: &str) -> Option<SourceOp> {
+    use self::SourceOp::*;
+
+    let op = match name {
+        "BREAK" => Plain(Instruction::BREAK),
+
+        "CNSTF4" | "CNSTI4" | "CNSTP4" | "CNSTU4" | "CNSTI2" | "CNSTU2" | "CNSTI1" |
+        "CNSTU1" => Operand(Instruction::CONST),
+
+        "ASGNB" => BlockCopy,
+        "ASGNF4" | "ASGNI4" | "ASGNP4" | "ASGNU4" => Plain(Instruction::STORE4),
+        "ASGNI2" | "ASGNU2" => Plain(Instruction::STORE2),
+        "ASGNI1" | "ASGNU1" => Plain(Instruction::STORE1),
+
+        // block copy deals with this
+        "INDIRB" => Ignore,
+        "INDIRF4" | "INDIRI4" | "INDIRP4" | "INDIRU4" => Plain(Instruction::LOAD4),
+        "INDIRI2" | "INDIRU2" => Plain(Instruction::LOAD2),
+        "INDIRI1" | "INDIRU1" => Plain(Instruction::LOAD1),
+
+        "CVFF4" => Undefined,
+        "CVFI4" => Plain(Instruction::CVFI),
+        "CVIF4" => Plain(Instruction::CVIF),
+        "CVII4" => SignExtend,
+        "CVII1" | "CVII2" | "CVIU4" | "CVPU4" | "CVUI4" | "CVUP4" | "CVUU4" | "CVUU1" => Ignore,
+
+        "NEGF4" => Plain(Instruction::NEGF),
+        "NEGI4" => Plain(Instruction::NEGI),
+
+        "ADDRGP4" => Operand(Instruction::CONST),
+
+        "ADDF4" => Plain(Instruction::ADDF),
+        "ADDI4" | "ADDP4" | "ADDP" | "ADDU4" => Plain(Instruction::ADD),
+
+        "SUBF4" => Plain(Instruction::SUBF),
+        "SUBI4" | "SUBP4" | "SUBU4" => Plain(Instruction::SUB),
+
+        "LSHI4" | "LSHU4" => Plain(Instruction::LSH),
+
+        "MODI4" => Plain(Instruction::MODI),
+        "MODU4" => Plain(Instruction::MODU),
+
+        "RSHI4" => Plain(Instruction::RSHI),
+        "RSHU4" => Plain(Instruction::RSHU),
+
+        "BANDI4" | "BANDU4" => Plain(Instruction::BAND),
+        "BCOMI4" | "BCOMU4" => Plain(Instruction::BCOM),
+        "BORI4" | "BORU4" => Plain(Instruction::BOR),
+        "BXORI4" | "BXORU4" => Plain(Instruction::BXOR),
+
+        "DIVF4" => Plain(Instruction::DIVF),
+        "DIVI4" => Plain(Instruction::DIVI),
+        "DIVU4" => Plain(Instruction::DIVU),
+
+        "MULF4" => Plain(Instruction::MULF),
+        "MULI4" => Plain(Instruction::MULI),
+        "MULU4" => Plain(Instruction::MULU),
+
+        "EQF4" => Operand(Instruction::EQF),
+        "EQI4" | "EQU4" => Operand(Instruction::EQ),
+
+        "GEF4" => Operand(Instruction::GEF),
+        "GEI4" => Operand(Instruction::GEI),
+        "GEU4" => Operand(Instruction::GEU),
+
+        "GTF4" => Operand(Instruction::GTF),
+        "GTI4" => Operand(Instruction::GTI),
+        "GTU4" => Operand(Instruction::GTU),
+
+        "LEF4" => Operand(Instruction::LEF),
+        "LEI4" => Operand(Instruction::LEI),
+        "LEU4" => Operand(Instruction::LEU),
+
+        "LTF4" => Operand(Instruction::LTF),
+        "LTI4" => Operand(Instruction::LTI),
+        "LTU4" => Operand(Instruction::LTU),
+
+        "NEF4" => Operand(Instruction::NEF),
+        "NEI4" | "NEU4" => Operand(Instruction::NE),
+
+        "JUMPV" => Plain(Instruction::JUMP),
+
+        "LOADB4" | "LOADF4" | "LOADI4" | "LOADP4" | "LOADU4" => Undefined,
+
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Parses a number like q3asm's `atoiNoCap`, i.e. wrapping to 32 bits.
+fn parse_number(token: &str) -> Option<i32> {
+    token.parse::<i64>().ok().map(|v| v as i32)
+}
+
+/// A symbol during assembly, with an address relative to its segment.
+struct AsmSymbol {
+    name: String,
+    segment: Segment,
+    value: i32,
+}
+
+/// The state of q3asm's two passes.
+struct State {
+    pass: usize,
+    code: Vec<Instruction>,
+    images: [Vec<u8>; NUM_SEGMENTS],
+    bases: [i32; NUM_SEGMENTS],
+    current: Segment,
+
+    symbols: Vec<AsmSymbol>,
+    index: HashMap<String, usize>,
+    last_symbol: Option<usize>,
+
+    // bytes of locals needed by the current procedure
+    current_locals: i32,
+    // bytes of largest argument list called from the current procedure
+    current_args: i32,
+    // byte offset in current_args to store next arg, reset each call
+    current_arg_offset: i32,
+
+    file_index: usize,
+    file_name: String,
+    line: usize,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            pass: 0,
+            code: vec![],
+            images: [vec![], vec![], vec![], vec![], vec![]],
+            bases: [0; NUM_SEGMENTS],
+            current: Segment::CODE,
+            symbols: vec![],
+            index: HashMap::new(),
+            last_symbol: None,
+            current_locals: 0,
+            current_args: 0,
+            current_arg_offset: 0,
+            file_index: 0,
+            file_name: String::new(),
+            line: 0,
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T> {
+        Err(Error::Assembler(self.file_name.clone(), self.line, message))
+    }
+
+    fn image(&mut self) -> &mut Vec<u8> {
+        &mut self.images[self.current as usize]
+    }
+
+    fn image_used(&self, segment: Segment) -> i32 {
+        match segment {
+            Segment::CODE => self.code.len() as i32,
+            _ => self.images[segment as usize].len() as i32,
+        }
+    }
+
+    /// Prepares the segments for the next pass.
+    fn begin_pass(&mut self, pass: usize) {
+        self.pass = pass;
+        self.bases[Segment::LIT as usize] = self.image_used(Segment::DATA);
+        self.bases[Segment::BSS as usize] = self.bases[Segment::LIT as usize] +
+                                            self.image_used(Segment::LIT);
+        self.bases[Segment::JTRG as usize] = self.bases[Segment::BSS as usize] +
+                                             self.image_used(Segment::BSS);
+        self.code.clear();
+        for image in &mut self.images {
+            image.clear();
+        }
+        // skip the 0 word, so NULL pointers are fixed up properly
+        self.images[Segment::DATA as usize].extend_from_slice(&[0; 4]);
+        self.current = Segment::CODE;
+    }
+
+    /// Aligns all segments at the end of a pass.
+    fn end_pass(&mut self) {
+        for image in &mut self.images {
+            while image.len() % 4 != 0 {
+                image.push(0);
+            }
+        }
+        if self.pass == 0 {
+            self.sort_symbols();
+        }
+    }
+
+    fn sort_symbols(&mut self) {
+        self.symbols.sort_by_key(|symbol| symbol.value);
+        self.index.clear();
+        for (i, symbol) in self.symbols.iter().enumerate() {
+            self.index.insert(symbol.name.clone(), i);
+        }
+        self.last_symbol = None;
+    }
+
+    /// Local labels are only unique within their file.
+    fn expand(&self, name: &str) -> String {
+        if name.starts_with('$') {
+            format!("{}_{}", name, self.file_index)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn define_symbol(&mut self, name: &str, value: i32) -> Result<()> {
+        // as a security, bail out if vmMain entry point is not first
+        if name.eq_ignore_ascii_case("vmMain") && value != 0 {
+            return self.error(format!("vmMain must be the first symbol in the qvm (got offset {})",
+                                      value));
+        }
+        if self.pass == 1 {
+            return Ok(());
+        }
+        let name = self.expand(name);
+        if self.index.contains_key(&name) {
+            return self.error(format!("Multiple definitions for {}", name));
+        }
+        self.index.insert(name.clone(), self.symbols.len());
+        self.last_symbol = Some(self.symbols.len());
+        self.symbols
+            .push(AsmSymbol {
+                      name,
+                      segment: self.current,
+                      value,
+                  });
+        Ok(())
+    }
+
+    fn lookup_symbol(&self, name: &str) -> Result<i32> {
+        match self.index.get(&self.expand(name)) {
+            Some(&i) => {
+                let symbol = &self.symbols[i];
+                Ok(self.bases[symbol.segment as usize].wrapping_add(symbol.value))
+            }
+            None if self.pass == 1 => self.error(format!("symbol {} undefined", name)),
+            None => Ok(0),
+        }
+    }
+
+    /// Switches segments for data that q3asm does not allow elsewhere.
+    ///
+    /// The most recently defined symbol moves along, since it labels this data.
+    fn hack_to_segment(&mut self, segment: Segment) {
+        if self.current == segment {
+            return;
+        }
+        self.current = segment;
+        if self.pass == 0 {
+            let value = self.image_used(segment);
+            if let Some(i) = self.last_symbol {
+                self.symbols[i].segment = segment;
+                self.symbols[i].value = value;
+            }
+        }
+    }
+
+    fn parse_value(&self, token: Option<&str>) -> Result<i32> {
+        match token {
+            Some(token) => {
+                match parse_number(token) {
+                    Some(v) => Ok(v),
+                    None => self.error(format!("Invalid number: {}", token)),
+                }
+            }
+            None => self.error("Missing value".to_string()),
+        }
+    }
+
+    /// Parses a symbol or number, followed by any number of `+`/`-` offsets.
+    fn parse_expression(&self, token: Option<&str>) -> Result<i32> {
+        let token = match token {
+            Some(token) => token,
+            None => return self.error("Missing expression".to_string()),
+        };
+        // skip over a leading minus
+        let start = if token.starts_with('-') { 1 } else { 0 };
+        let end = token[start..]
+            .find(['+', '-'])
+            .map(|i| i + start)
+            .unwrap_or_else(|| token.len());
+        let (sym, mut rest) = token.split_at(end);
+
+        let mut v = match sym.chars().next() {
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_value(Some(sym))?,
+            _ => self.lookup_symbol(sym)?,
+        };
+
+        // parse add / subtract offsets
+        while !rest.is_empty() {
+            let next = rest[1..]
+                .find(['+', '-'])
+                .map(|i| i + 1)
+                .unwrap_or_else(|| rest.len());
+            let offset = self.parse_value(Some(&rest[1..next]))?;
+            if rest.starts_with('+') {
+                v = v.wrapping_add(offset);
+            } else {
+                v = v.wrapping_sub(offset);
+            }
+            rest = &rest[next..];
+        }
+        Ok(v)
+    }
+
+    fn emit_instruction(&mut self, instruction: Instruction) {
+        self.code.push(instruction);
+    }
+
+    fn frame_size(&self) -> Literal {
+        (8 + self.current_locals + self.current_args) as Literal
+    }
+
+    fn assemble_line(&mut self, line: &str) -> Result<()> {
+        let mut tokens = line.split_whitespace();
+        let token = match tokens.next() {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        // call instructions reset current_arg_offset
+        if token.starts_with("CALL") {
+            self.emit_instruction(Instruction::CALL);
+            self.current_arg_offset = 0;
+            return Ok(());
+        }
+
+        // arg is converted to a reversed store
+        if token.starts_with("ARG") {
+            if 8 + self.current_arg_offset >= 256 {
+                return self.error("currentArgOffset >= 256".to_string());
+            }
+            let offset = 8 + self.current_arg_offset;
+            self.emit_instruction(Instruction::ARG(offset as u8));
+            self.current_arg_offset += 4;
+            return Ok(());
+        }
+
+        // ret just leaves something on the op stack
+        if token.starts_with("RET") {
+            let frame_size = self.frame_size();
+            self.emit_instruction(Instruction::LEAVE(frame_size));
+            return Ok(());
+        }
+
+        // pop is needed to discard the return value of a function
+        if token == "pop" {
+            self.emit_instruction(Instruction::POP);
+            return Ok(());
+        }
+
+        // address of a parameter is converted to OP_LOCAL
+        if token.starts_with("ADDRF") {
+            let v = self.parse_expression(tokens.next())?;
+            let v = 16 + self.current_args + self.current_locals + v;
+            self.emit_instruction(Instruction::LOCAL(v as Literal));
+            return Ok(());
+        }
+
+        // address of a local is converted to OP_LOCAL
+        if token.starts_with("ADDRL") {
+            let v = self.parse_expression(tokens.next())?;
+            let v = 8 + self.current_args + v;
+            self.emit_instruction(Instruction::LOCAL(v as Literal));
+            return Ok(());
+        }
+
+        // code labels are emitted as instruction counts, not byte offsets
+        if token.starts_with("LABEL") {
+            let name = match tokens.next() {
+                Some(name) => name,
+                None => return self.error("Missing label name".to_string()),
+            };
+            let value = self.image_used(self.current);
+            return self.define_symbol(name, value);
+        }
+
+        match token {
+            "proc" => {
+                let name = match tokens.next() {
+                    Some(name) => name,
+                    None => return self.error("Missing procedure name".to_string()),
+                };
+                let value = self.code.len() as i32;
+                self.define_symbol(name, value)?;
+
+                self.current_locals = (self.parse_value(tokens.next())? + 3) & !3;
+                self.current_args = (self.parse_value(tokens.next())? + 3) & !3;
+
+                if 8 + self.current_locals + self.current_args >= 32767 {
+                    return self.error(format!("Locals > 32k in {}", name));
+                }
+
+                let frame_size = self.frame_size();
+                self.emit_instruction(Instruction::ENTER(frame_size));
+            }
+            "endproc" => {
+                // all functions must leave something on the opstack
+                self.emit_instruction(Instruction::PUSH);
+                let frame_size = self.frame_size();
+                self.emit_instruction(Instruction::LEAVE(frame_size));
+            }
+            "address" => {
+                let token = tokens.next();
+                let v = self.parse_expression(token)?;
+
+                // addresses are 32 bits wide, and therefore go into data segment
+                self.hack_to_segment(Segment::DATA);
+                self.emit_int(v)?;
+                // crude test for labels
+                if self.pass == 1 && token.is_some_and(|t| t.starts_with('$')) {
+                    push_int(&mut self.images[Segment::JTRG as usize], v);
+                }
+            }
+            "export" | "import" | "line" | "file" => {}
+            "code" => self.current = Segment::CODE,
+            "bss" => self.current = Segment::BSS,
+            "data" => self.current = Segment::DATA,
+            "lit" => self.current = Segment::LIT,
+            "equ" => {
+                let name = match tokens.next() {
+                    Some(name) => name,
+                    None => return self.error("Missing symbol name".to_string()),
+                };
+                let value = self.parse_value(tokens.next())?;
+                self.define_symbol(name, value)?;
+            }
+            "align" => {
+                let v = self.parse_value(tokens.next())?;
+                if v <= 0 || (v & (v - 1)) != 0 {
+                    return self.error(format!("Invalid alignment: {}", v));
+                }
+                self.check_data_segment()?;
+                let image = self.image();
+                while image.len() % v as usize != 0 {
+                    image.push(0);
+                }
+            }
+            "skip" => {
+                let v = self.parse_value(tokens.next())?;
+                if v < 0 {
+                    return self.error(format!("Invalid skip: {}", v));
+                }
+                self.check_data_segment()?;
+                let image = self.image();
+                let used = image.len() + v as usize;
+                image.resize(used, 0);
+            }
+            "byte" => {
+                let size = self.parse_value(tokens.next())?;
+                let mut v = self.parse_value(tokens.next())?;
+
+                match size {
+                    // character (1-byte) values go into lit(eral) segment
+                    1 => self.hack_to_segment(Segment::LIT),
+                    // 32-bit (4-byte) values go into data segment
+                    4 => self.hack_to_segment(Segment::DATA),
+                    // and 16-bit (2-byte) values will cause q3asm to barf
+                    2 => return self.error("16 bit initialized data not supported".to_string()),
+                    _ => return self.error(format!("Invalid data size: {}", size)),
+                }
+
+                // emit little endian
+                for _ in 0..size {
+                    self.image().push(v as u8);
+                    v >>= 8;
+                }
+            }
+            _ => return self.assemble_source_op(token, tokens.next()),
+        }
+        Ok(())
+    }
+
+    fn assemble_source_op(&mut self, token: &str, operand: Option<&str>) -> Result<()> {
+        let instruction = match source_op(token) {
+            None => return self.error(format!("Unknown token: {}", token)),
+            Some(SourceOp::Undefined) => {
+                return self.error(format!("Undefined opcode: {}", token))
+            }
+            // we ignore most conversions
+            Some(SourceOp::Ignore) => return Ok(()),
+            // sign extensions need to check next parm
+            Some(SourceOp::SignExtend) => {
+                match operand {
+                    Some(size) if size.starts_with('1') => Instruction::SEX8,
+                    Some(size) if size.starts_with('2') => Instruction::SEX16,
+                    _ => {
+                        return self.error(format!("Bad sign extension: {}",
+                                                  operand.unwrap_or("")))
+                    }
+                }
+            }
+            Some(SourceOp::Plain(instruction)) => instruction,
+            Some(SourceOp::Operand(instruction)) => {
+                instruction(self.parse_expression(operand)? as Literal)
+            }
+            // code like this can generate non-dword block copies:
+            // auto char buf[2] = " ";
+            // we are just going to round up.
+            Some(SourceOp::BlockCopy) => {
+                let size = self.parse_expression(operand)?;
+                Instruction::BLOCK_COPY(((size + 3) & !3) as Literal)
+            }
+        };
+        self.emit_instruction(instruction);
+        Ok(())
+    }
+
+    fn check_data_segment(&self) -> Result<()> {
+        match self.current {
+            Segment::CODE => self.error("Data in code segment not supported".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn emit_int(&mut self, v: i32) -> Result<()> {
+        self.check_data_segment()?;
+        push_int(self.image(), v);
+        Ok(())
+    }
+}
+
+fn push_int(image: &mut Vec<u8>, v: i32) {
+    for i in 0..4 {
+        image.push((v >> (i * 8)) as u8);
+    }
+}
+
+/// Converts an aligned segment image to little endian words.
+fn words(image: &[u8]) -> Vec<u32> {
+    image
+        .chunks(4)
+        .map(|w| w[0] as u32 | (w[1] as u32) << 8 | (w[2] as u32) << 16 | (w[3] as u32) << 24)
+        .collect()
+}
+
+/// An assembler for LCC bytecode files.
+///
+/// Just like q3asm, all sources are linked into a single QVM, resolving
+/// `export`ed and `import`ed symbols across files.
+/// The resulting QVM has a `SymbolMap` attached that matches q3asm's `.map`
+/// output.
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    version: Version,
+    stack_size: u32,
+    sources: Vec<(String, String)>,
+}
+
+impl Default for Assembler {
+    fn default() -> Assembler {
+        Assembler::new()
+    }
+}
+
+impl Assembler {
+    /// Creates a new assembler without sources.
+    ///
+    /// It produces `Version::V2` VMs like ioquake3's q3asm does by default.
+    pub fn new() -> Assembler {
+        Assembler {
+            version: Version::V2,
+            stack_size: DEFAULT_STACK_SIZE,
+            sources: vec![],
+        }
+    }
+
+    /// Sets the version of the produced VM.
+    ///
+    /// `Version::V1` corresponds to q3asm's `-vq3` option.
+    pub fn set_version(&mut self, version: Version) {
+        self.version = version;
+    }
+
+    /// Sets the size of the program stack to reserve in the BSS segment.
+    pub fn set_stack_size(&mut self, stack_size: u32) {
+        self.stack_size = stack_size;
+    }
+
+    /// Adds a source file to assemble.
+    ///
+    /// The name is only used for error messages.
+    pub fn add_source(&mut self, name: &str, source: &str) {
+        self.sources.push((name.to_string(), source.to_string()));
+    }
+
+    /// Assembles all sources into a QVM.
+    ///
+    /// # Errors
+    /// Returns `Error::Assembler` for the first error in any source.
+    pub fn assemble(&self) -> Result<QVM> {
+        let mut state = State::new();
+
+        for pass in 0..2 {
+            state.begin_pass(pass);
+            for (i, (name, source)) in self.sources.iter().enumerate() {
+                state.file_index = i;
+                state.file_name = name.clone();
+                for (line_number, line) in source.lines().enumerate() {
+                    state.line = line_number + 1;
+                    state.assemble_line(line)?;
+                }
+            }
+            state.end_pass();
+        }
+
+        // reserve the stack in bss
+        state.pass = 2;
+        let stack_start = state.image_used(Segment::BSS);
+        state.define_symbol("_stackStart", stack_start)?;
+        let stack_end = stack_start + self.stack_size as i32;
+        state.define_symbol("_stackEnd", stack_end)?;
+
+        let mut symbols = SymbolMap::new();
+        for symbol in state.symbols.iter().filter(|s| !s.name.starts_with('$')) {
+            symbols.insert(Symbol::new(symbol.segment,
+                                       symbol.value as u32,
+                                       symbol.name.clone()));
+        }
+
+        let data = words(&state.images[Segment::DATA as usize]);
+        let lit = state.images[Segment::LIT as usize].clone();
+        let bss_length = stack_end as u32;
+        let jtrg = words(&state.images[Segment::JTRG as usize]);
+
+        let mut qvm = match self.version {
+            Version::V1 => QVM::new(state.code, data, lit, bss_length)?,
+            Version::V2 => QVM::new_v2(state.code, data, lit, bss_length, jtrg)?,
+        };
+        qvm.set_symbols(symbols);
+        Ok(qvm)
+    }
+}
+
+/// Parses a table of system calls like ioquake3's `g_syscalls.asm`, i.e.
+/// `equ` directives of negative code addresses, into a symbol map.
+///
+/// # Errors
+/// Returns `Error::Assembler` for lines other than `code` and `equ`.
+pub fn parse_syscalls(name: &str, source: &str) -> Result<SymbolMap> {
+    let mut symbols = SymbolMap::new();
+    for (line_number, line) in source.lines().enumerate() {
+        let error = |message: String| {
+            Err(Error::Assembler(name.to_string(), line_number + 1, message))
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens[..] {
+            [] | ["code"] => {}
+            ["equ", symbol, value] => {
+                match parse_number(value) {
+                    Some(value) => {
+                        symbols.insert(Symbol::new(Segment::CODE,
+                                                   value as u32,
+                                                   symbol.to_string()))
+                    }
+                    None => return error(format!("Invalid number: {}", value)),
+                }
+            }
+            _ => return error(format!("Not a system call: {}", line.trim())),
+        }
+    }
+    Ok(symbols)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_syscalls, Assembler};
+    use bytecode::Instruction;
+    use writer::{write_qvm, write_symbol_map};
+    use {Segment, Version};
+
+    fn assemble(sources: &[(&str, &str)]) -> ::QVM {
+        let mut assembler = Assembler::new();
+        assembler.set_version(Version::V1);
+        for &(name, source) in sources {
+            assembler.add_source(name, source);
+        }
+        assembler.assemble().unwrap()
+    }
+
+    fn assert_fixture(qvm: &::QVM, expected_qvm: &[u8], expected_map: &[u8]) {
+        let mut result = vec![];
+        write_qvm(qvm, &mut result).unwrap();
+        assert_eq!(result, expected_qvm);
+
+        let mut result = vec![];
+        write_symbol_map(qvm.symbols().unwrap(), &mut result).unwrap();
+        assert_eq!(String::from_utf8(result).unwrap(),
+                   String::from_utf8(expected_map.to_vec()).unwrap());
+    }
+
+    #[test]
+    fn test_assemble_file_minimal() {
+        let qvm = assemble(&[("mod-minimal.asm", include_str!("../assets/mod-minimal.asm"))]);
+        assert_fixture(&qvm,
+                       include_bytes!("../assets/mod-minimal.qvm"),
+                       include_bytes!("../assets/mod-minimal.map"));
+    }
+
+    #[test]
+    fn test_assemble_file_bss() {
+        let qvm = assemble(&[("mod-bss.asm", include_str!("../assets/mod-bss.asm"))]);
+        assert_fixture(&qvm,
+                       include_bytes!("../assets/mod-bss.qvm"),
+                       include_bytes!("../assets/mod-bss.map"));
+    }
+
+    #[test]
+    fn test_assemble_file_data() {
+        let qvm = assemble(&[("mod-data.asm", include_str!("../assets/mod-data.asm"))]);
+        assert_fixture(&qvm,
+                       include_bytes!("../assets/mod-data.qvm"),
+                       include_bytes!("../assets/mod-data.map"));
+    }
+
+    #[test]
+    fn test_assemble_file_lit() {
+        let qvm = assemble(&[("mod-lit.asm", include_str!("../assets/mod-lit.asm"))]);
+        assert_fixture(&qvm,
+                       include_bytes!("../assets/mod-lit.qvm"),
+                       include_bytes!("../assets/mod-lit.map"));
+    }
+
+    #[test]
+    fn test_assemble_file_syscall() {
+        let qvm = assemble(&[("mod-syscall.asm", include_str!("../assets/mod-syscall.asm")),
+                             ("syscalls.asm", include_str!("../assets/syscalls.asm"))]);
+        assert_fixture(&qvm,
+                       include_bytes!("../assets/mod-syscall.qvm"),
+                       include_bytes!("../assets/mod-syscall.map"));
+    }
+
+    #[test]
+    fn test_parse_syscalls() {
+        let symbols = parse_syscalls("syscalls.asm", include_str!("../assets/syscalls.asm"))
+            .unwrap();
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols.syscall_name(-666), Some("trap_Print"));
+        assert_eq!(parse_syscalls("bad.asm", "code\nequ trap_Print x\n")
+                       .unwrap_err()
+                       .to_string(),
+                   "bad.asm:2: Invalid number: x");
+        assert!(parse_syscalls("bad.asm", "CNSTI4 0\n").is_err());
+    }
+
+    #[test]
+    fn test_assemble_jump_table() {
+        let source = "export vmMain
+code
+proc vmMain 0 0
+ADDRFP4 0
+INDIRI4
+CNSTI4 2
+LSHI4
+ADDRGP4 $3
+ADDP4
+INDIRP4
+JUMPV
+LABELV $1
+CNSTI4 1
+RETI4
+LABELV $2
+CNSTI4 2
+RETI4
+endproc vmMain 0 0
+data
+align 4
+LABELV $3
+address $1
+address $2
+";
+        let mut assembler = Assembler::new();
+        assembler.add_source("switch.asm", source);
+        let qvm = assembler.assemble().unwrap();
+        assert_eq!(qvm.version(), Version::V2);
+        assert_eq!(qvm.jump_targets(), Some(&vec![9, 11]));
+        assert_eq!(qvm.data(), &vec![0, 9, 11]);
+        assert_eq!(qvm.instructions()[1], Instruction::LOCAL(16));
+        assert_eq!(qvm.instructions()[5], Instruction::CONST(4));
+    }
+
+    #[test]
+    fn test_assemble_undefined_symbol() {
+        let mut assembler = Assembler::new();
+        assembler.add_source("undefined.asm", "code\nproc vmMain 0 0\nADDRGP4 missing\n");
+        let error = assembler.assemble().unwrap_err();
+        assert_eq!(error.to_string(), "undefined.asm:3: symbol missing undefined");
+    }
+
+    #[test]
+    fn test_assemble_vmmain_not_first() {
+        let mut assembler = Assembler::new();
+        assembler.add_source("late.asm",
+                             "code\nproc f 0 0\nendproc f 0 0\nproc vmMain 0 0\n");
+        assert!(assembler.assemble().is_err());
+    }
+
+    #[test]
+    fn test_assemble_symbols() {
+        let qvm = assemble(&[("mod-syscall.asm", include_str!("../assets/mod-syscall.asm")),
+                             ("syscalls.asm", include_str!("../assets/syscalls.asm"))]);
+        let symbols = qvm.symbols().unwrap();
+        assert_eq!(symbols.syscall_name(-666), Some("trap_Print"));
+        assert_eq!(symbols.resolve("_stackEnd"), Some((Segment::CODE, 0x10000)));
+    }
+}
@@ -0,0 +1,192 @@
+//! Parameter and return value recovery for procedures.
+//!
+//! QVM procedures do not declare their parameters. Callers pass arguments with
+//! `ARG`, which stores them at offsets from 8 in the frame of the callee, after
+//! the return address. The callee's `ENTER` allocates its frame below them, so
+//! it reads parameter `i` with `LOCAL frame_size + 8 + 4 * i`. Both give a
+//! lower bound of the number of parameters.
+//!
+//! Procedures without a return value leave an unspecified one with `PUSH`
+//! before `LEAVE`, which their callers discard with `POP`.
+
+use super::QVM;
+use bytecode::{Address, FrameOffset, FrameSize, Instruction};
+use cfg::procedures;
+use std::collections::BTreeMap;
+
+/// Frame offset of the first parameter, after the return address.
+const ARGS_OFFSET: FrameOffset = 8;
+
+/// Returns the index of the parameter at `offset` in a frame of the given
+/// size, or `None` for local variables.
+///
+/// `ARG` offsets are the parameter offsets in an empty frame.
+pub fn param_index(frame_size: FrameSize, offset: FrameOffset) -> Option<usize> {
+    match offset.checked_sub(frame_size + ARGS_OFFSET) {
+        Some(arg) if arg % 4 == 0 => Some((arg / 4) as usize),
+        _ => None,
+    }
+}
+
+/// The recovered signature of a procedure.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Signature {
+    /// The number of parameters.
+    pub params: usize,
+    /// Whether the procedure returns a value.
+    pub returns: bool,
+}
+
+/// The signatures of all procedures of a VM.
+#[derive(Debug, Clone, Default)]
+pub struct Signatures {
+    signatures: BTreeMap<Address, Signature>,
+}
+
+impl Signatures {
+    /// Infers the signatures of the procedures of the given code.
+    ///
+    /// Only direct calls are taken into account.
+    pub fn new(code: &[Instruction]) -> Signatures {
+        let procedures = procedures(code);
+        let mut signatures: BTreeMap<Address, Signature> = procedures
+            .iter()
+            .map(|procedure| (procedure.start(), Signature::default()))
+            .collect();
+        for procedure in &procedures {
+            // The number of arguments passed to the next call
+            let mut args = 0;
+            for index in procedure.start() as usize..procedure.end() as usize {
+                match code[index] {
+                    Instruction::LOCAL(offset) => {
+                        if let Some(param) = param_index(procedure.frame_size(), offset) {
+                            let signature = signatures
+                                .get_mut(&procedure.start())
+                                .expect("all procedures have signatures");
+                            signature.params = signature.params.max(param + 1);
+                        }
+                    }
+                    Instruction::ARG(offset) => {
+                        if let Some(param) = param_index(0, offset as FrameOffset) {
+                            args = args.max(param + 1);
+                        }
+                    }
+                    Instruction::LEAVE(_) if code[index - 1] != Instruction::PUSH => {
+                        signatures
+                            .get_mut(&procedure.start())
+                            .expect("all procedures have signatures")
+                            .returns = true;
+                    }
+                    Instruction::CALL => {
+                        let callee = match code[index - 1] {
+                            Instruction::CONST(target) => signatures.get_mut(&target),
+                            _ => None,
+                        };
+                        if let Some(signature) = callee {
+                            signature.params = signature.params.max(args);
+                            if code.get(index + 1) != Some(&Instruction::POP) {
+                                signature.returns = true;
+                            }
+                        }
+                        args = 0;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Signatures { signatures }
+    }
+
+    /// Infers the signatures of the procedures of a VM.
+    pub fn for_qvm(qvm: &QVM) -> Signatures {
+        Signatures::new(qvm.instructions())
+    }
+
+    /// Returns the signature of the procedure at `procedure`, or `None` if
+    /// there is no procedure at that address.
+    pub fn get(&self, procedure: Address) -> Option<Signature> {
+        self.signatures.get(&procedure).cloned()
+    }
+
+    /// Returns the signatures of all procedures, keyed by their address.
+    pub fn signatures(&self) -> &BTreeMap<Address, Signature> {
+        &self.signatures
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{param_index, Signature, Signatures};
+    use bytecode::Instruction;
+    use parser::{parse_qvm, parse_symbol_map};
+
+    #[test]
+    fn test_param_index() {
+        assert_eq!(param_index(4, 12), Some(0));
+        assert_eq!(param_index(4, 16), Some(1));
+        assert_eq!(param_index(4, 8), None);
+        assert_eq!(param_index(0, 10), None);
+    }
+
+    #[test]
+    fn test_signatures() {
+        let code = vec![Instruction::ENTER(8),
+                        Instruction::CONST(1),
+                        Instruction::ARG(8),
+                        Instruction::CONST(2),
+                        Instruction::ARG(16),
+                        Instruction::CONST(11),
+                        Instruction::CALL,
+                        Instruction::POP,
+                        Instruction::CONST(17),
+                        Instruction::CALL,
+                        Instruction::LEAVE(8),
+                        Instruction::ENTER(4),
+                        Instruction::LOCAL(16),
+                        Instruction::LOAD4,
+                        Instruction::POP,
+                        Instruction::PUSH,
+                        Instruction::LEAVE(4),
+                        Instruction::ENTER(8),
+                        Instruction::PUSH,
+                        Instruction::LEAVE(8)];
+        let signatures = Signatures::new(&code);
+        assert_eq!(signatures.signatures().len(), 3);
+        assert_eq!(signatures.get(0),
+                   Some(Signature {
+                            params: 0,
+                            returns: true,
+                        }));
+        // Callers pass more arguments than the callee reads
+        assert_eq!(signatures.get(11),
+                   Some(Signature {
+                            params: 3,
+                            returns: false,
+                        }));
+        // The result is used by a caller
+        assert_eq!(signatures.get(17),
+                   Some(Signature {
+                            params: 0,
+                            returns: true,
+                        }));
+        assert_eq!(signatures.get(5), None);
+    }
+
+    #[test]
+    fn test_signatures_ioq3_qagame() {
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        let symbols = parse_symbol_map(include_bytes!("../assets/ioq3/baseq3/vm/qagame.map"))
+            .unwrap();
+        let signatures = Signatures::for_qvm(&qvm);
+        let signature = |name| {
+            let signature = signatures.get(symbols.resolve(name).unwrap().1).unwrap();
+            (signature.params, signature.returns)
+        };
+        assert_eq!(signature("Q_strncpyz"), (3, false));
+        assert_eq!(signature("VectorNormalize"), (1, true));
+        assert_eq!(signature("G_FindTeams"), (0, false));
+        // Only the command and the first three arguments are used
+        assert_eq!(signature("vmMain"), (4, true));
+    }
+}
@@ -0,0 +1,652 @@
+//! An SSA intermediate representation of procedures.
+//!
+//! Lifting replaces the operand stack with explicit values: every instruction
+//! that pushes onto the stack defines a value, and every instruction that pops
+//! uses the values defined by earlier instructions. Values that are still on
+//! the operand stack when control flow joins become phi nodes.
+//!
+//! Memory, including the stack frame, is not promoted to values; `LOCAL`
+//! defines the address of a frame slot, which is then loaded from and stored
+//! to explicitly.
+//!
+//! QVM code has no instruction to duplicate values, so every value is used at
+//! most once. Lowering relies on this to turn the values back into operand
+//! stack code, and reproduces the lifted instructions exactly.
+
+use bytecode::{Address, ArgOffset, BlockSize, FrameOffset, FrameSize, Instruction, Literal};
+use cfg::{BlockId, Cfg, Edge, EdgeKind};
+use opcodes::{ControlFlow, Opcode};
+use opstack::StackError;
+use std::fmt;
+use super::QVM;
+
+/// Index of a value within its `Function`.
+pub type ValueId = usize;
+
+/// The operation of a value.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Op {
+    /// `CONST`, a literal.
+    Const(Literal),
+    /// `LOCAL`, the address of a slot of the stack frame.
+    Local(FrameOffset),
+    /// `PUSH`, an unspecified value, e.g. for procedures without a return
+    /// value.
+    Undefined,
+    /// The value left on the operand stack by each predecessor block.
+    Phi(Vec<(BlockId, ValueId)>),
+    /// `LOAD1`, `LOAD2` or `LOAD4` of the given number of octets from an
+    /// address.
+    Load(u8, ValueId),
+    /// `STORE1`, `STORE2` or `STORE4` of the given number of octets to an
+    /// address, then the value.
+    Store(u8, ValueId, ValueId),
+    /// `ARG`, passing a value to the next call.
+    Arg(ArgOffset, ValueId),
+    /// `BLOCK_COPY` of the given number of octets to an address, from an
+    /// address.
+    BlockCopy(BlockSize, ValueId, ValueId),
+    /// An operation with one operand, e.g. `NEGI` or `CVIF`.
+    Unary(Opcode, ValueId),
+    /// An operation with two operands, e.g. `ADD` or `LSH`.
+    Binary(Opcode, ValueId, ValueId),
+    /// `CALL` of a procedure or system call address.
+    Call(ValueId),
+    /// `POP`, discarding a value.
+    Pop(ValueId),
+    /// A conditional branch comparing two values, e.g. `LTI`, to an address.
+    Branch(Opcode, ValueId, ValueId, Address),
+    /// `JUMP` to an address.
+    Jump(ValueId),
+    /// `LEAVE` with a return value.
+    Return(ValueId),
+    /// An instruction without operands or effects, e.g. `BREAK`.
+    Nop(Opcode),
+}
+
+impl Op {
+    /// Returns the values used by the operation, in stack order.
+    pub fn operands(&self) -> Vec<ValueId> {
+        match *self {
+            Op::Const(_) | Op::Local(_) | Op::Undefined | Op::Nop(_) => vec![],
+            Op::Phi(ref incoming) => incoming.iter().map(|&(_, value)| value).collect(),
+            Op::Load(_, a) | Op::Arg(_, a) | Op::Unary(_, a) | Op::Call(a) | Op::Pop(a) |
+            Op::Jump(a) | Op::Return(a) => vec![a],
+            Op::Store(_, a, b) |
+            Op::BlockCopy(_, a, b) |
+            Op::Binary(_, a, b) |
+            Op::Branch(_, a, b, _) => vec![a, b],
+        }
+    }
+
+    /// Returns whether the operation defines a value.
+    pub fn has_value(&self) -> bool {
+        match *self {
+            Op::Const(_) | Op::Local(_) | Op::Undefined | Op::Phi(_) | Op::Load(..) |
+            Op::Unary(..) | Op::Binary(..) | Op::Call(_) => true,
+            Op::Store(..) | Op::Arg(..) | Op::BlockCopy(..) | Op::Pop(_) | Op::Branch(..) |
+            Op::Jump(_) | Op::Return(_) | Op::Nop(_) => false,
+        }
+    }
+
+    /// Returns the instruction of the operation, or `None` for phi nodes.
+    fn instruction(&self, frame_size: FrameSize) -> Option<Instruction> {
+        let instruction = match *self {
+            Op::Const(value) => Instruction::CONST(value),
+            Op::Local(offset) => Instruction::LOCAL(offset),
+            Op::Undefined => Instruction::PUSH,
+            Op::Phi(_) => return None,
+            Op::Load(1, _) => Instruction::LOAD1,
+            Op::Load(2, _) => Instruction::LOAD2,
+            Op::Load(..) => Instruction::LOAD4,
+            Op::Store(1, ..) => Instruction::STORE1,
+            Op::Store(2, ..) => Instruction::STORE2,
+            Op::Store(..) => Instruction::STORE4,
+            Op::Arg(offset, _) => Instruction::ARG(offset),
+            Op::BlockCopy(size, ..) => Instruction::BLOCK_COPY(size),
+            Op::Unary(opcode, _) | Op::Binary(opcode, ..) | Op::Nop(opcode) => {
+                Instruction::new(opcode, 0)
+            }
+            Op::Call(_) => Instruction::CALL,
+            Op::Pop(_) => Instruction::POP,
+            Op::Branch(opcode, _, _, target) => Instruction::new(opcode, target),
+            Op::Jump(_) => Instruction::JUMP,
+            Op::Return(_) => Instruction::LEAVE(frame_size),
+        };
+        Some(instruction)
+    }
+}
+
+/// A value, i.e. the result of an operation.
+///
+/// Operations without a result, e.g. stores, are values as well, so that all
+/// instructions of a block are in one list.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Value {
+    /// The operation defining the value.
+    pub op: Op,
+    /// The block that contains the value.
+    pub block: BlockId,
+    /// Address of the lifted instruction, or `None` for phi nodes.
+    pub address: Option<Address>,
+}
+
+/// A basic block of SSA values.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    /// Address of the first lifted instruction.
+    pub start: Address,
+    /// Phi nodes for the values on the operand stack at entry, from bottom to
+    /// top.
+    pub phis: Vec<ValueId>,
+    /// All other values, in execution order.
+    pub values: Vec<ValueId>,
+    /// The outgoing edges, as in the `Cfg`.
+    pub successors: Vec<Edge>,
+    /// The blocks with edges to this one, as in the `Cfg`.
+    pub predecessors: Vec<BlockId>,
+}
+
+/// A procedure in SSA form.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    /// Address of the procedure's `ENTER` instruction.
+    pub start: Address,
+    /// Frame size of the procedure's `ENTER` and `LEAVE` instructions.
+    pub frame_size: FrameSize,
+    /// The basic blocks, with the same ids as in the `Cfg`.
+    pub blocks: Vec<Block>,
+    /// All values of all blocks.
+    pub values: Vec<Value>,
+}
+
+impl Function {
+    fn define(&mut self, block: BlockId, op: Op, address: Option<Address>) -> ValueId {
+        let id = self.values.len();
+        self.values.push(Value { op, block, address });
+        id
+    }
+
+    /// Lifts a procedure into SSA form.
+    ///
+    /// Blocks that are unreachable from the entry block start with an empty
+    /// operand stack. `ENTER` is not lifted, see `Function::frame_size`.
+    ///
+    /// # Errors
+    /// Returns `StackError::Underflow` or `StackError::InconsistentMerge` if
+    /// the operand stack is not balanced, see `opstack::analyze`.
+    pub fn lift(cfg: &Cfg) -> Result<Function, StackError> {
+        let count = cfg.blocks().len();
+        let mut function = Function {
+            start: cfg.procedure().start(),
+            frame_size: cfg.procedure().frame_size(),
+            blocks: (0..count)
+                .map(|id| {
+                         Block {
+                             start: cfg.block(id).start(),
+                             phis: vec![],
+                             values: vec![],
+                             successors: cfg.successors(id).to_vec(),
+                             predecessors: cfg.predecessors(id).to_vec(),
+                         }
+                     })
+                .collect(),
+            values: vec![],
+        };
+
+        // The operand stack at the end of each block
+        let mut exits: Vec<Option<Vec<ValueId>>> = vec![None; count];
+        let mut lifted = vec![false; count];
+        // Whether a block with a single predecessor starts with its exit stack
+        let mut inherited = vec![false; count];
+        for seed in 0..count {
+            if lifted[seed] {
+                continue;
+            }
+            let mut pending = vec![seed];
+            lifted[seed] = true;
+            while let Some(id) = pending.pop() {
+                inherited[id] = match cfg.predecessors(id) {
+                    &[predecessor] => exits[predecessor].is_some(),
+                    _ => false,
+                };
+                let exit = function.lift_block(cfg, id, &exits)?;
+                exits[id] = Some(exit);
+                for edge in cfg.successors(id).iter().rev() {
+                    if !lifted[edge.target] {
+                        lifted[edge.target] = true;
+                        pending.push(edge.target);
+                    }
+                }
+            }
+        }
+
+        // Check the stack depths at joins and at blocks lifted before their
+        // predecessor, and complete phi nodes
+        for (id, &inherited) in inherited.iter().enumerate() {
+            if inherited {
+                continue;
+            }
+            let depth = function.blocks[id].phis.len();
+            for &predecessor in cfg.predecessors(id) {
+                let exit = exits[predecessor].as_ref().expect("all blocks are lifted");
+                if exit.len() != depth {
+                    return Err(StackError::InconsistentMerge {
+                                   index: cfg.block(id).start(),
+                                   expected: depth as u32,
+                                   actual: exit.len() as u32,
+                               });
+                }
+                for (slot, &phi) in function.blocks[id].phis.iter().enumerate() {
+                    if let Op::Phi(ref mut incoming) = function.values[phi].op {
+                        incoming.push((predecessor, exit[slot]));
+                    }
+                }
+            }
+        }
+
+        Ok(function)
+    }
+
+    /// Lifts the instructions of a block, returning the operand stack at its
+    /// end.
+    fn lift_block(&mut self,
+                  cfg: &Cfg,
+                  id: BlockId,
+                  exits: &[Option<Vec<ValueId>>])
+                  -> Result<Vec<ValueId>, StackError> {
+        let predecessors = cfg.predecessors(id);
+        let lifted_exit = predecessors
+            .iter()
+            .filter_map(|&p| exits[p].as_ref())
+            .next();
+        let mut stack = match lifted_exit {
+            Some(exit) if predecessors.len() == 1 => exit.clone(),
+            Some(exit) => {
+                let phis: Vec<ValueId> = (0..exit.len())
+                    .map(|_| self.define(id, Op::Phi(vec![]), None))
+                    .collect();
+                self.blocks[id].phis = phis.clone();
+                phis
+            }
+            None => vec![],
+        };
+
+        let block = cfg.block(id);
+        for (address, &instruction) in (block.start()..).zip(cfg.instructions(id)) {
+            if let Instruction::ENTER(_) = instruction {
+                if address == self.start {
+                    continue;
+                }
+            }
+            let effect = instruction.stack_effect();
+            // The return value is left on the operand stack for the caller
+            let pops = match instruction {
+                Instruction::LEAVE(_) => 1,
+                _ => effect.pops as usize,
+            };
+            if stack.len() < pops {
+                return Err(StackError::Underflow {
+                               index: address,
+                               depth: stack.len() as u32,
+                           });
+            }
+            let b = if pops >= 2 { stack.pop() } else { None };
+            let a = if pops >= 1 { stack.pop() } else { None };
+            let (a, b) = (a.unwrap_or(0), b.unwrap_or(0));
+
+            let opcode = instruction.opcode();
+            let op = match instruction {
+                Instruction::CONST(value) => Op::Const(value),
+                Instruction::LOCAL(offset) => Op::Local(offset),
+                Instruction::PUSH => Op::Undefined,
+                Instruction::POP => Op::Pop(a),
+                Instruction::LOAD1 => Op::Load(1, a),
+                Instruction::LOAD2 => Op::Load(2, a),
+                Instruction::LOAD4 => Op::Load(4, a),
+                Instruction::STORE1 => Op::Store(1, a, b),
+                Instruction::STORE2 => Op::Store(2, a, b),
+                Instruction::STORE4 => Op::Store(4, a, b),
+                Instruction::ARG(offset) => Op::Arg(offset, a),
+                Instruction::BLOCK_COPY(size) => Op::BlockCopy(size, a, b),
+                Instruction::CALL => Op::Call(a),
+                Instruction::JUMP => Op::Jump(a),
+                Instruction::LEAVE(_) => Op::Return(a),
+                _ => {
+                    match (instruction.control_flow(), effect.pops, effect.pushes) {
+                        (ControlFlow::Branch, _, _) => {
+                            let target = instruction
+                                .branch_target()
+                                .expect("branches have targets");
+                            Op::Branch(opcode, a, b, target)
+                        }
+                        (_, 1, 1) => Op::Unary(opcode, a),
+                        (_, 2, 1) => Op::Binary(opcode, a, b),
+                        _ => Op::Nop(opcode),
+                    }
+                }
+            };
+            let has_value = op.has_value();
+            let value = self.define(id, op, Some(address));
+            self.blocks[id].values.push(value);
+            if has_value {
+                stack.push(value);
+            }
+        }
+        Ok(stack)
+    }
+
+    /// Returns the values that use each value.
+    pub fn uses(&self) -> Vec<Vec<ValueId>> {
+        let mut uses = vec![vec![]; self.values.len()];
+        for (id, value) in self.values.iter().enumerate() {
+            for operand in value.op.operands() {
+                uses[operand].push(id);
+            }
+        }
+        uses
+    }
+
+    /// Lowers the procedure back into instructions, starting with `ENTER`.
+    ///
+    /// Values must be used in the order they are defined, as in lifted
+    /// procedures. Lowering keeps the number of instructions of every block,
+    /// so addresses like branch targets remain valid.
+    pub fn lower(&self) -> Vec<Instruction> {
+        let uses = self.uses();
+        let mut code = vec![Instruction::ENTER(self.frame_size)];
+        for (id, block) in self.blocks.iter().enumerate() {
+            for &value in &block.values {
+                // Values used within the block are lowered with their use
+                let is_operand = uses[value]
+                    .iter()
+                    .any(|&user| self.values[user].block == id && !self.is_phi(user));
+                if !is_operand {
+                    self.lower_value(id, value, &mut code);
+                }
+            }
+        }
+        code
+    }
+
+    fn is_phi(&self, value: ValueId) -> bool {
+        matches!(self.values[value].op, Op::Phi(_))
+    }
+
+    fn lower_value(&self, block: BlockId, value: ValueId, code: &mut Vec<Instruction>) {
+        let op = &self.values[value].op;
+        for operand in op.operands() {
+            // Other values are already on the operand stack
+            if self.values[operand].block == block && !self.is_phi(operand) {
+                self.lower_value(block, operand, code);
+            }
+        }
+        code.extend(op.instruction(self.frame_size));
+    }
+}
+
+/// Lifts all procedures of a VM, see `Function::lift`.
+///
+/// # Errors
+/// Returns the first `StackError` of any procedure.
+pub fn lift_qvm(qvm: &QVM) -> Result<Vec<Function>, StackError> {
+    Cfg::for_qvm(qvm).iter().map(Function::lift).collect()
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = match self.instruction(0) {
+            Some(instruction) => instruction,
+            None => {
+                write!(f, "PHI")?;
+                if let Op::Phi(ref incoming) = *self {
+                    for (i, &(block, value)) in incoming.iter().enumerate() {
+                        let separator = if i == 0 { " " } else { ", " };
+                        write!(f, "{}[b{} v{}]", separator, block, value)?;
+                    }
+                }
+                return Ok(());
+            }
+        };
+        write!(f, "{}", instruction.opcode().name())?;
+        let mut separator = " ";
+        match *self {
+            Op::Const(value) => {
+                write!(f, " {}", value as i32)?;
+                separator = ", ";
+            }
+            Op::Local(offset) => {
+                write!(f, " {}", offset)?;
+                separator = ", ";
+            }
+            Op::Arg(offset, _) => {
+                write!(f, " {}", offset)?;
+                separator = ", ";
+            }
+            Op::BlockCopy(size, ..) => {
+                write!(f, " {}", size)?;
+                separator = ", ";
+            }
+            _ => {}
+        }
+        for operand in self.operands() {
+            write!(f, "{}v{}", separator, operand)?;
+            separator = ", ";
+        }
+        if let Op::Branch(_, _, _, target) = *self {
+            write!(f, " -> {}", target)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "procedure {}, frame size {}", self.start, self.frame_size)?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            write!(f, "b{} @{}:", id, block.start)?;
+            for (i, predecessor) in block.predecessors.iter().enumerate() {
+                write!(f, "{}b{}", if i == 0 { "  ; from " } else { ", " }, predecessor)?;
+            }
+            writeln!(f)?;
+            for &value in block.phis.iter().chain(&block.values) {
+                let op = &self.values[value].op;
+                if op.has_value() {
+                    writeln!(f, "    v{} = {}", value, op)?;
+                } else {
+                    writeln!(f, "    {}", op)?;
+                }
+            }
+            let edges: Vec<String> = block
+                .successors
+                .iter()
+                .map(|edge| match edge.kind {
+                         EdgeKind::True => format!("b{} if true", edge.target),
+                         EdgeKind::False => format!("b{} if false", edge.target),
+                         _ => format!("b{}", edge.target),
+                     })
+                .collect();
+            if !edges.is_empty() {
+                writeln!(f, "    ; to {}", edges.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{lift_qvm, Function, Op};
+    use assembler::assemble;
+    use cfg::Cfg;
+    use opcodes::Opcode;
+    use opstack::StackError;
+    use parser::parse_qvm;
+
+    fn lift(source: &str) -> Function {
+        let qvm = assemble("test", source).unwrap();
+        let cfgs = Cfg::for_qvm(&qvm);
+        Function::lift(&cfgs[0]).unwrap()
+    }
+
+    #[test]
+    fn test_lift_statements() {
+        let function = lift("
+            ENTER 16
+            LOCAL 4
+            LOCAL 20
+            LOAD4
+            CONST 1
+            ADD
+            STORE4
+            LOCAL 4
+            LOAD4
+            ARG 8
+            CONST -1
+            CALL
+            POP
+            PUSH
+            LEAVE 16
+        ");
+        let ops: Vec<&Op> = function.values.iter().map(|value| &value.op).collect();
+        assert_eq!(ops,
+                   vec![&Op::Local(4),
+                        &Op::Local(20),
+                        &Op::Load(4, 1),
+                        &Op::Const(1),
+                        &Op::Binary(Opcode::ADD, 2, 3),
+                        &Op::Store(4, 0, 4),
+                        &Op::Local(4),
+                        &Op::Load(4, 6),
+                        &Op::Arg(8, 7),
+                        &Op::Const(-1i32 as u32),
+                        &Op::Call(9),
+                        &Op::Pop(10),
+                        &Op::Undefined,
+                        &Op::Return(12)]);
+        assert_eq!(function.values[4].address, Some(5));
+        assert_eq!(function.uses()[2], vec![4]);
+        let expected = "procedure 0, frame size 16
+b0 @0:
+    v0 = LOCAL 4
+    v1 = LOCAL 20
+    v2 = LOAD4 v1
+    v3 = CONST 1
+    v4 = ADD v2, v3
+    STORE4 v0, v4
+    v6 = LOCAL 4
+    v7 = LOAD4 v6
+    ARG 8, v7
+    v9 = CONST -1
+    v10 = CALL v9
+    POP v10
+    v12 = PUSH
+    LEAVE v12
+";
+        assert_eq!(function.to_string(), expected);
+    }
+
+    #[test]
+    fn test_lift_phi() {
+        // i.e. `return x ? 1 : 2` without a temporary
+        let function = lift("
+                    ENTER 8
+                    LOCAL 16
+                    LOAD4
+                    CONST 0
+                    EQ two
+                    CONST 1
+                    CONST done
+                    JUMP
+            two:    CONST 2
+            done:   LEAVE 8
+        ");
+        assert_eq!(function.blocks.len(), 4);
+        let phis = &function.blocks[3].phis;
+        assert_eq!(phis.len(), 1);
+        let (one, two) = (function.blocks[1].values[0], function.blocks[2].values[0]);
+        assert_eq!(function.values[phis[0]].op, Op::Phi(vec![(1, one), (2, two)]));
+        assert_eq!(function.values[function.blocks[3].values[0]].op, Op::Return(phis[0]));
+        assert!(function.to_string().contains(&format!("v{} = PHI [b1 v{}], [b2 v{}]\n",
+                                                       phis[0],
+                                                       one,
+                                                       two)));
+    }
+
+    #[test]
+    fn test_lift_errors() {
+        let qvm = assemble("test", "ENTER 8\nADD\nLEAVE 8\n").unwrap();
+        assert_eq!(Function::lift(&Cfg::for_qvm(&qvm)[0]),
+                   Err(StackError::Underflow { index: 1, depth: 0 }));
+        let qvm = assemble("test", "
+                    ENTER 8
+                    CONST 0
+                    CONST 0
+                    EQ join
+                    PUSH
+            join:   PUSH
+                    LEAVE 8
+        ")
+                .unwrap();
+        assert_eq!(Function::lift(&Cfg::for_qvm(&qvm)[0]),
+                   Err(StackError::InconsistentMerge {
+                           index: 5,
+                           expected: 0,
+                           actual: 1,
+                       }));
+        // The only predecessor of `target` is unreachable and comes later
+        let qvm = assemble("test", "
+                    ENTER 8
+                    PUSH
+                    LEAVE 8
+            target: PUSH
+                    LEAVE 8
+                    PUSH
+                    CONST target
+                    JUMP
+        ")
+                .unwrap();
+        assert_eq!(Function::lift(&Cfg::for_qvm(&qvm)[0]),
+                   Err(StackError::InconsistentMerge {
+                           index: 3,
+                           expected: 0,
+                           actual: 1,
+                       }));
+    }
+
+    #[test]
+    fn test_lower() {
+        let source = "
+                    ENTER 8
+                    LOCAL 16
+                    LOAD4
+                    CONST 0
+                    EQ two
+                    CONST 1
+                    CONST done
+                    JUMP
+            two:    CONST 2
+            done:   LEAVE 8
+                    PUSH
+                    LEAVE 8
+        ";
+        let qvm = assemble("test", source).unwrap();
+        let function = Function::lift(&Cfg::for_qvm(&qvm)[0]).unwrap();
+        assert_eq!(&function.lower(), qvm.instructions());
+    }
+
+    #[test]
+    fn test_lift_lower_ioq3_qagame() {
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        let functions = lift_qvm(&qvm).unwrap();
+        let code = qvm.instructions();
+        for function in &functions {
+            let lowered = function.lower();
+            let start = function.start as usize;
+            assert_eq!(&lowered[..], &code[start..start + lowered.len()]);
+        }
+        let lowered: usize = functions.iter().map(|function| function.lower().len()).sum();
+        assert_eq!(lowered, code.len());
+    }
+}
@@ -0,0 +1,430 @@
+//! Worst-case program stack usage.
+//!
+//! Every procedure reserves its frame on the program stack with `ENTER`, so
+//! the stack usage of a call chain is the sum of its frame sizes. Calls are
+//! taken from `callgraph::CallGraph`; system calls do not use the program
+//! stack.
+//!
+//! Recursion makes the usage unbounded. Procedures within a recursion cycle
+//! are accounted for one iteration only and flagged as `recursive`, and so are
+//! their callers. Likewise, indirect calls cannot be followed and are flagged.
+
+use super::QVM;
+use bytecode::Address;
+use callgraph::{CallGraph, Callee};
+use interpreter::{MAX_VMMAIN_ARGS, PROGRAM_STACK_SIZE};
+use std::collections::HashMap;
+
+/// Program stack used by the engine to pass arguments to `vmMain`.
+pub const VM_MAIN_OVERHEAD: u32 = 8 + 4 * MAX_VMMAIN_ARGS as u32;
+
+/// Worst-case program stack usage of a procedure.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ProcedureUsage {
+    /// Address of the procedure's `ENTER` instruction.
+    pub procedure: Address,
+    /// Frame size of the procedure's `ENTER` instruction.
+    pub frame_size: u32,
+    /// Octets used by the procedure and the deepest chain of its callees.
+    pub usage: u32,
+    /// The deepest chain of calls, starting with the procedure itself.
+    ///
+    /// Within a recursion cycle, this continues with the deepest callee of
+    /// any procedure of the cycle.
+    pub path: Vec<Address>,
+    /// Whether the procedure reaches a recursion cycle, i.e. `usage` is only
+    /// a lower bound.
+    pub recursive: bool,
+    /// Whether the procedure reaches an indirect call, which is not included
+    /// in `usage`.
+    pub indirect: bool,
+}
+
+/// Program stack usage of all procedures of a VM.
+#[derive(Debug, Clone)]
+pub struct StackUsage {
+    usages: Vec<ProcedureUsage>,
+    cycles: Vec<Vec<Address>>,
+    commands: Vec<Address>,
+    reserved: u32,
+}
+
+/// Returns the strongly connected components of a graph given by its
+/// successors, callees before callers.
+///
+/// This is an iterative version of Tarjan's algorithm, so that long call
+/// chains cannot overflow the native stack.
+fn strongly_connected_components(successors: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let count = successors.len();
+    let mut indices: Vec<Option<usize>> = vec![None; count];
+    let mut low_links = vec![0; count];
+    let mut on_stack = vec![false; count];
+    let mut stack = vec![];
+    let mut components = vec![];
+    let mut next_index = 0;
+
+    for root in 0..count {
+        if indices[root].is_some() {
+            continue;
+        }
+        let mut visits = vec![(root, 0)];
+        indices[root] = Some(next_index);
+        low_links[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some(&mut (node, ref mut next)) = visits.last_mut() {
+            if let Some(&successor) = successors[node].get(*next) {
+                *next += 1;
+                match indices[successor] {
+                    None => {
+                        indices[successor] = Some(next_index);
+                        low_links[successor] = next_index;
+                        next_index += 1;
+                        stack.push(successor);
+                        on_stack[successor] = true;
+                        visits.push((successor, 0));
+                    }
+                    Some(index) if on_stack[successor] => {
+                        low_links[node] = low_links[node].min(index);
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+
+            visits.pop();
+            if let Some(&(parent, _)) = visits.last() {
+                low_links[parent] = low_links[parent].min(low_links[node]);
+            }
+            if Some(low_links[node]) == indices[node] {
+                let mut component = vec![];
+                loop {
+                    let member = stack.pop().expect("node is on the stack");
+                    on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+    components
+}
+
+impl StackUsage {
+    /// Computes the program stack usage of all procedures in a call graph.
+    ///
+    /// `reserved` is the size of the program stack, see `reserved_size`.
+    pub fn new(graph: &CallGraph, reserved: u32) -> StackUsage {
+        let procedures = graph.procedures();
+        let positions: HashMap<Address, usize> = procedures
+            .iter()
+            .enumerate()
+            .map(|(i, procedure)| (procedure.start(), i))
+            .collect();
+        let successors: Vec<Vec<usize>> = procedures
+            .iter()
+            .map(|procedure| {
+                let mut callees: Vec<usize> = graph
+                    .calls(procedure.start())
+                    .iter()
+                    .filter_map(|call| match call.callee {
+                                    Callee::Procedure(callee) => positions.get(&callee).cloned(),
+                                    _ => None,
+                                })
+                    .collect();
+                callees.sort_unstable();
+                callees.dedup();
+                callees
+            })
+            .collect();
+
+        let components = strongly_connected_components(&successors);
+        let mut component_ids = vec![0; procedures.len()];
+        for (id, component) in components.iter().enumerate() {
+            for &member in component {
+                component_ids[member] = id;
+            }
+        }
+
+        let mut usages: Vec<Option<ProcedureUsage>> = vec![None; procedures.len()];
+        let mut cycles = vec![];
+        for (id, component) in components.iter().enumerate() {
+            let recursive = component.len() > 1 ||
+                            successors[component[0]].contains(&component[0]);
+            let external_callees = |member: usize| {
+                successors[member]
+                    .iter()
+                    .filter(|&&callee| component_ids[callee] != id)
+                    .map(|&callee| usages[callee].as_ref().expect("callees come first"))
+            };
+            // The deepest callee outside of the component, per member
+            let deepest = |member: usize| external_callees(member).max_by_key(|usage| usage.usage);
+            let frames: u32 = component
+                .iter()
+                .map(|&member| procedures[member].frame_size())
+                .fold(0, u32::saturating_add);
+            let cycle_callee = component
+                .iter()
+                .filter_map(|&member| deepest(member))
+                .max_by_key(|usage| usage.usage);
+            let reaches = |flag: fn(&ProcedureUsage) -> bool| {
+                component
+                    .iter()
+                    .any(|&member| external_callees(member).any(flag))
+            };
+            let reaches_recursion = recursive || reaches(|usage| usage.recursive);
+            let indirect = component.iter().any(|&member| {
+                graph
+                    .calls(procedures[member].start())
+                    .iter()
+                    .any(|call| call.callee == Callee::Indirect)
+            }) || reaches(|usage| usage.indirect);
+
+            let mut results = vec![];
+            for &member in component {
+                let procedure = &procedures[member];
+                let (base, callee) = if recursive {
+                    (frames, cycle_callee)
+                } else {
+                    (procedure.frame_size(), deepest(member))
+                };
+                let mut path = vec![procedure.start()];
+                let mut usage = base;
+                if let Some(callee) = callee {
+                    usage = usage.saturating_add(callee.usage);
+                    path.extend(callee.path.iter().cloned());
+                }
+                results.push((member,
+                              ProcedureUsage {
+                                  procedure: procedure.start(),
+                                  frame_size: procedure.frame_size(),
+                                  usage,
+                                  path,
+                                  recursive: reaches_recursion,
+                                  indirect,
+                              }));
+            }
+            for (member, usage) in results {
+                usages[member] = Some(usage);
+            }
+            if recursive {
+                let mut cycle: Vec<Address> = component
+                    .iter()
+                    .map(|&member| procedures[member].start())
+                    .collect();
+                cycle.sort_unstable();
+                cycles.push(cycle);
+            }
+        }
+        cycles.sort();
+        let commands = match procedures.first() {
+            Some(vm_main) => {
+                graph
+                    .callees(vm_main.start())
+                    .into_iter()
+                    .filter_map(|callee| match callee {
+                                    Callee::Procedure(callee) => Some(callee),
+                                    _ => None,
+                                })
+                    .filter(|callee| positions.contains_key(callee))
+                    .collect()
+            }
+            None => vec![],
+        };
+
+        StackUsage {
+            usages: usages
+                .into_iter()
+                .map(|usage| usage.expect("all procedures are analyzed"))
+                .collect(),
+            cycles,
+            commands,
+            reserved,
+        }
+    }
+
+    /// Computes the program stack usage of a VM, see `reserved_size`.
+    pub fn for_qvm(qvm: &QVM) -> StackUsage {
+        StackUsage::new(&CallGraph::for_qvm(qvm), reserved_size(qvm))
+    }
+
+    /// Returns the usage of all procedures, ordered by address.
+    pub fn procedures(&self) -> &[ProcedureUsage] {
+        &self.usages
+    }
+
+    /// Returns the usage of the procedure at `procedure`.
+    pub fn procedure(&self, procedure: Address) -> Option<&ProcedureUsage> {
+        self.usages
+            .binary_search_by_key(&procedure, |usage| usage.procedure)
+            .ok()
+            .map(|index| &self.usages[index])
+    }
+
+    /// Returns all recursion cycles, i.e. sets of procedures that can call
+    /// each other.
+    pub fn cycles(&self) -> &[Vec<Address>] {
+        &self.cycles
+    }
+
+    /// Returns the size of the program stack.
+    pub fn reserved(&self) -> u32 {
+        self.reserved
+    }
+
+    /// Returns the worst-case usage of a call from the engine, including
+    /// `VM_MAIN_OVERHEAD`.
+    pub fn max_usage(&self) -> u32 {
+        self.usages
+            .first()
+            .map_or(0, |usage| usage.usage)
+            .saturating_add(VM_MAIN_OVERHEAD)
+    }
+
+    /// Returns the procedures that `vmMain` calls directly, i.e. the paths of
+    /// the different commands, with their worst-case usage from the engine.
+    ///
+    /// The deepest paths come first.
+    pub fn commands(&self) -> Vec<(&ProcedureUsage, u32)> {
+        let vm_main_frame = match self.usages.first() {
+            Some(vm_main) => vm_main.frame_size,
+            None => return vec![],
+        };
+        let mut commands: Vec<_> = self.commands
+            .iter()
+            .filter_map(|&command| self.procedure(command))
+            .map(|usage| {
+                     let total = usage
+                         .usage
+                         .saturating_add(vm_main_frame)
+                         .saturating_add(VM_MAIN_OVERHEAD);
+                     (usage, total)
+                 })
+            .collect();
+        commands.sort_by_key(|&(usage, total)| (!total, usage.procedure));
+        commands
+    }
+
+    /// Returns whether the worst case fits into the reserved program stack.
+    ///
+    /// This is only reliable if `vmMain` is neither `recursive` nor reaches
+    /// `indirect` calls.
+    pub fn is_sufficient(&self) -> bool {
+        self.max_usage() <= self.reserved
+    }
+}
+
+/// Returns the size of the program stack reserved by q3asm.
+///
+/// This is the distance between the `_stackStart` and `_stackEnd` symbols of
+/// the attached symbol map, or `interpreter::PROGRAM_STACK_SIZE`.
+pub fn reserved_size(qvm: &QVM) -> u32 {
+    qvm.symbols()
+        .and_then(|symbols| {
+                      let start = symbols.get("_stackStart")?.address();
+                      let end = symbols.get("_stackEnd")?.address();
+                      end.checked_sub(start)
+                  })
+        .unwrap_or(PROGRAM_STACK_SIZE)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{reserved_size, StackUsage, VM_MAIN_OVERHEAD};
+    use bytecode::Instruction;
+    use callgraph::CallGraph;
+    use interpreter::PROGRAM_STACK_SIZE;
+    use parser::{parse_qvm, parse_symbol_map};
+
+    fn test_usage() -> StackUsage {
+        let code = [Instruction::ENTER(16),
+                    Instruction::CONST(6),
+                    Instruction::CALL,
+                    Instruction::CONST(10),
+                    Instruction::CALL,
+                    Instruction::LEAVE(16),
+                    Instruction::ENTER(100),
+                    Instruction::CONST(17),
+                    Instruction::CALL,
+                    Instruction::LEAVE(100),
+                    Instruction::ENTER(40),
+                    Instruction::CONST(10),
+                    Instruction::CALL,
+                    Instruction::LOCAL(8),
+                    Instruction::LOAD4,
+                    Instruction::CALL,
+                    Instruction::LEAVE(40),
+                    Instruction::ENTER(200),
+                    Instruction::LEAVE(200),
+                    Instruction::ENTER(8),
+                    Instruction::CONST(22),
+                    Instruction::CALL,
+                    Instruction::ENTER(12),
+                    Instruction::CONST(19),
+                    Instruction::CALL,
+                    Instruction::LEAVE(12)];
+        StackUsage::new(&CallGraph::new(&code), 0x200)
+    }
+
+    #[test]
+    fn test_usage_call_chain() {
+        let usage = test_usage();
+        let leaf = usage.procedure(17).unwrap();
+        assert_eq!((leaf.usage, leaf.recursive, leaf.indirect), (200, false, false));
+        let caller = usage.procedure(6).unwrap();
+        assert_eq!((caller.usage, &caller.path), (300, &vec![6, 17]));
+        let vm_main = usage.procedure(0).unwrap();
+        assert_eq!((vm_main.usage, &vm_main.path), (316, &vec![0, 6, 17]));
+        assert!(vm_main.recursive && vm_main.indirect);
+        assert_eq!(usage.max_usage(), 316 + VM_MAIN_OVERHEAD);
+        assert!(usage.is_sufficient());
+        assert!(usage.procedure(1).is_none());
+    }
+
+    #[test]
+    fn test_usage_recursion() {
+        let usage = test_usage();
+        assert_eq!(usage.cycles(), &[vec![10], vec![19, 22]]);
+        let recursive = usage.procedure(10).unwrap();
+        assert_eq!((recursive.usage, recursive.recursive, recursive.indirect),
+                   (40, true, true));
+        assert_eq!(usage.procedure(19).unwrap().usage, 20);
+        assert_eq!(usage.procedure(22).unwrap().usage, 20);
+    }
+
+    #[test]
+    fn test_usage_commands() {
+        let usage = test_usage();
+        let commands: Vec<_> = usage
+            .commands()
+            .into_iter()
+            .map(|(usage, total)| (usage.procedure, total))
+            .collect();
+        assert_eq!(commands,
+                   vec![(6, 316 + VM_MAIN_OVERHEAD), (10, 56 + VM_MAIN_OVERHEAD)]);
+    }
+
+    #[test]
+    fn test_usage_ioq3_qagame() {
+        let mut qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        assert_eq!(reserved_size(&qvm), PROGRAM_STACK_SIZE);
+        qvm.set_symbols(parse_symbol_map(include_bytes!("../assets/ioq3/baseq3/vm/qagame.map"))
+                            .unwrap());
+        assert_eq!(reserved_size(&qvm), 0x10000);
+
+        let usage = StackUsage::for_qvm(&qvm);
+        assert!(usage.is_sufficient());
+        assert!(usage.max_usage() > 0x4000);
+        assert!(usage.procedure(0).unwrap().recursive);
+        assert!(!usage.cycles().is_empty());
+        let commands = usage.commands();
+        assert_eq!(commands[0].1, usage.max_usage());
+        assert!(commands.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+}
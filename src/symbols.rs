@@ -0,0 +1,158 @@
+//! Symbol names of a QVM, as emitted by q3asm in `.map` files.
+
+use super::Segment;
+use bytecode::Address;
+use std::collections::HashMap;
+
+/// A named address within a segment.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    segment: Segment,
+    address: Address,
+    name: String,
+}
+
+impl Symbol {
+    /// Creates a new symbol.
+    pub fn new(segment: Segment, address: Address, name: String) -> Symbol {
+        Symbol {
+            segment,
+            address,
+            name,
+        }
+    }
+
+    /// Returns the segment the symbol is located in.
+    pub fn segment(&self) -> Segment {
+        self.segment
+    }
+
+    /// Returns the address of the symbol within its segment.
+    ///
+    /// For `Segment::CODE` this is an instruction index, for all other
+    /// segments it is a byte offset.
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Returns the name of the symbol.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether the symbol is a system call.
+    ///
+    /// q3asm assigns negative addresses within the code segment to system
+    /// calls, e.g. `equ trap_Print -666`.
+    pub fn is_syscall(&self) -> bool {
+        self.syscall().is_some()
+    }
+
+    /// Returns the system call number, if the symbol is a system call.
+    pub fn syscall(&self) -> Option<i32> {
+        match self.segment {
+            Segment::CODE if (self.address as i32) < 0 => Some(self.address as i32),
+            _ => None,
+        }
+    }
+}
+
+/// A bidirectional mapping of symbol names and addresses.
+///
+/// See ioquake3's `WriteMapFile` in `tools/asm/q3asm.c`
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SymbolMap {
+    symbols: Vec<Symbol>,
+    names: HashMap<String, usize>,
+    addresses: HashMap<(Segment, Address), usize>,
+}
+
+impl SymbolMap {
+    /// Creates a new, empty symbol map.
+    pub fn new() -> SymbolMap {
+        SymbolMap::default()
+    }
+
+    /// Adds a symbol to the map.
+    ///
+    /// If several symbols share the same address, lookups by address return
+    /// the one that was inserted first.
+    /// If a name is inserted twice, lookups by name return the latter.
+    pub fn insert(&mut self, symbol: Symbol) {
+        let index = self.symbols.len();
+        self.names.insert(symbol.name.clone(), index);
+        self.addresses
+            .entry((symbol.segment, symbol.address))
+            .or_insert(index);
+        self.symbols.push(symbol);
+    }
+
+    /// Returns all symbols in the order they were inserted.
+    pub fn symbols(&self) -> &Vec<Symbol> {
+        &self.symbols
+    }
+
+    /// Returns the number of symbols.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Returns whether there are no symbols.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Returns the symbol with the given name.
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.names.get(name).map(|&index| &self.symbols[index])
+    }
+
+    /// Resolves a symbol name to its segment and address.
+    pub fn resolve(&self, name: &str) -> Option<(Segment, Address)> {
+        self.get(name).map(|symbol| (symbol.segment, symbol.address))
+    }
+
+    /// Returns the symbol at the given segment and address.
+    pub fn symbol_at(&self, segment: Segment, address: Address) -> Option<&Symbol> {
+        self.addresses
+            .get(&(segment, address))
+            .map(|&index| &self.symbols[index])
+    }
+
+    /// Returns the name of the symbol at the given segment and address.
+    pub fn name_at(&self, segment: Segment, address: Address) -> Option<&str> {
+        self.symbol_at(segment, address).map(Symbol::name)
+    }
+
+    /// Returns the name of the given system call number.
+    pub fn syscall_name(&self, syscall: i32) -> Option<&str> {
+        self.name_at(Segment::CODE, syscall as Address)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Symbol, SymbolMap};
+    use Segment;
+
+    #[test]
+    fn test_symbol_syscall() {
+        let symbol = Symbol::new(Segment::CODE, 0xfffffd66, "trap_Print".to_string());
+        assert_eq!(symbol.syscall(), Some(-666));
+        let symbol = Symbol::new(Segment::CODE, 0, "vmMain".to_string());
+        assert_eq!(symbol.syscall(), None);
+        let symbol = Symbol::new(Segment::DATA, 0xfffffd66, "huge".to_string());
+        assert!(!symbol.is_syscall());
+    }
+
+    #[test]
+    fn test_symbol_map_first_address_wins() {
+        let mut map = SymbolMap::new();
+        map.insert(Symbol::new(Segment::CODE, 0, "vmMain".to_string()));
+        map.insert(Symbol::new(Segment::CODE, 0, "_stackStart".to_string()));
+        assert_eq!(map.name_at(Segment::CODE, 0), Some("vmMain"));
+        assert_eq!(map.resolve("_stackStart"), Some((Segment::CODE, 0)));
+        assert_eq!(map.len(), 2);
+    }
+}
@@ -0,0 +1,353 @@
+//! System calls from a VM to its host.
+//!
+//! A QVM calls into the engine by `CALL`ing a negative address, e.g.
+//! `trap_Print` is defined as `equ trap_Print -666` in `syscalls.asm`.
+//! The engine sees this as system call number `-1 - address`, i.e. `665`.
+
+use interpreter::Memory;
+use super::errors::*;
+use std::collections::HashMap;
+
+/// Returns the system call number for a negative `CALL` address.
+pub fn syscall_number(address: i32) -> i32 {
+    -1 - address
+}
+
+/// A host that handles system calls of a VM.
+///
+/// This is implemented for closures taking the memory image, the system call
+/// number and the raw arguments.
+pub trait SyscallHandler {
+    /// Handles a system call and returns its result.
+    fn syscall(&mut self, memory: &mut Memory, number: i32, args: &[u32]) -> Result<u32>;
+}
+
+impl<F> SyscallHandler for F
+    where F: FnMut(&mut Memory, i32, &[u32]) -> Result<u32>
+{
+    fn syscall(&mut self, memory: &mut Memory, number: i32, args: &[u32]) -> Result<u32> {
+        self(memory, number, args)
+    }
+}
+
+/// An address within the VM memory image.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Pointer(pub u32);
+
+/// A type that can be decoded from a raw system call argument.
+pub trait FromArg: Sized {
+    /// Decodes the raw argument, which might refer to VM memory.
+    fn from_arg(value: u32, memory: &Memory) -> Result<Self>;
+}
+
+impl FromArg for u32 {
+    fn from_arg(value: u32, _: &Memory) -> Result<u32> {
+        Ok(value)
+    }
+}
+
+impl FromArg for i32 {
+    fn from_arg(value: u32, _: &Memory) -> Result<i32> {
+        Ok(value as i32)
+    }
+}
+
+impl FromArg for f32 {
+    fn from_arg(value: u32, _: &Memory) -> Result<f32> {
+        Ok(f32::from_bits(value))
+    }
+}
+
+impl FromArg for bool {
+    fn from_arg(value: u32, _: &Memory) -> Result<bool> {
+        Ok(value != 0)
+    }
+}
+
+impl FromArg for Pointer {
+    fn from_arg(value: u32, _: &Memory) -> Result<Pointer> {
+        Ok(Pointer(value))
+    }
+}
+
+/// A NUL-terminated string in VM memory, decoded lossily as UTF-8.
+impl FromArg for String {
+    fn from_arg(value: u32, memory: &Memory) -> Result<String> {
+        Ok(String::from_utf8_lossy(memory.read_cstring(value)).into_owned())
+    }
+}
+
+/// A type that can be encoded as a raw system call result.
+pub trait IntoReturn {
+    /// Encodes the result.
+    fn into_return(self) -> Result<u32>;
+}
+
+impl IntoReturn for () {
+    fn into_return(self) -> Result<u32> {
+        Ok(0)
+    }
+}
+
+impl IntoReturn for u32 {
+    fn into_return(self) -> Result<u32> {
+        Ok(self)
+    }
+}
+
+impl IntoReturn for i32 {
+    fn into_return(self) -> Result<u32> {
+        Ok(self as u32)
+    }
+}
+
+impl IntoReturn for f32 {
+    fn into_return(self) -> Result<u32> {
+        Ok(self.to_bits())
+    }
+}
+
+impl IntoReturn for bool {
+    fn into_return(self) -> Result<u32> {
+        Ok(self as u32)
+    }
+}
+
+impl IntoReturn for Pointer {
+    fn into_return(self) -> Result<u32> {
+        Ok(self.0)
+    }
+}
+
+impl<T: IntoReturn> IntoReturn for Result<T> {
+    fn into_return(self) -> Result<u32> {
+        self.and_then(IntoReturn::into_return)
+    }
+}
+
+/// The arguments of a system call, with access to the VM memory.
+pub struct SyscallArgs<'a> {
+    memory: &'a mut Memory,
+    args: &'a [u32],
+}
+
+impl<'a> SyscallArgs<'a> {
+    /// Creates new arguments for the raw values.
+    pub fn new(memory: &'a mut Memory, args: &'a [u32]) -> SyscallArgs<'a> {
+        SyscallArgs { memory, args }
+    }
+
+    /// Returns the raw arguments.
+    pub fn raw(&self) -> &[u32] {
+        self.args
+    }
+
+    /// Decodes the argument at `index`.
+    ///
+    /// # Errors
+    /// Returns `Error::Syscall` if there is no such argument.
+    pub fn get<T: FromArg>(&self, index: usize) -> Result<T> {
+        match self.args.get(index) {
+            Some(&value) => T::from_arg(value, self.memory),
+            None => Err(Error::Syscall(format!("missing argument {}", index))),
+        }
+    }
+
+    /// Decodes the argument at `index` as a signed integer.
+    pub fn int(&self, index: usize) -> Result<i32> {
+        self.get(index)
+    }
+
+    /// Decodes the argument at `index` as a float.
+    pub fn float(&self, index: usize) -> Result<f32> {
+        self.get(index)
+    }
+
+    /// Decodes the argument at `index` as a VM pointer.
+    pub fn pointer(&self, index: usize) -> Result<Pointer> {
+        self.get(index)
+    }
+
+    /// Decodes the argument at `index` as a NUL-terminated string.
+    pub fn string(&self, index: usize) -> Result<String> {
+        self.get(index)
+    }
+
+    /// Returns the VM memory image.
+    pub fn memory(&self) -> &Memory {
+        self.memory
+    }
+
+    /// Returns the VM memory image for modification, e.g. to fill buffers.
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        self.memory
+    }
+}
+
+/// A system call implementation with typed arguments `Args`.
+///
+/// This is implemented for closures of up to 8 arguments that implement
+/// `FromArg` and return something that implements `IntoReturn`.
+pub trait SyscallFn<Args> {
+    /// Decodes the arguments, calls the implementation and encodes its result.
+    fn invoke(&mut self, args: &mut SyscallArgs) -> Result<u32>;
+}
+
+macro_rules! syscall_fn {
+    ($($arg:ident: $index:tt),*) => {
+        impl<F, R, $($arg),*> SyscallFn<($($arg,)*)> for F
+            where F: FnMut($($arg),*) -> R,
+                  R: IntoReturn,
+                  $($arg: FromArg),*
+        {
+            #[allow(unused_variables)]
+            fn invoke(&mut self, args: &mut SyscallArgs) -> Result<u32> {
+                self($(args.get::<$arg>($index)?),*).into_return()
+            }
+        }
+    }
+}
+
+syscall_fn!();
+syscall_fn!(A0: 0);
+syscall_fn!(A0: 0, A1: 1);
+syscall_fn!(A0: 0, A1: 1, A2: 2);
+syscall_fn!(A0: 0, A1: 1, A2: 2, A3: 3);
+syscall_fn!(A0: 0, A1: 1, A2: 2, A3: 3, A4: 4);
+syscall_fn!(A0: 0, A1: 1, A2: 2, A3: 3, A4: 4, A5: 5);
+syscall_fn!(A0: 0, A1: 1, A2: 2, A3: 3, A4: 4, A5: 5, A6: 6);
+syscall_fn!(A0: 0, A1: 1, A2: 2, A3: 3, A4: 4, A5: 5, A6: 6, A7: 7);
+
+type BoxedSyscall<'a> = Box<dyn FnMut(&mut SyscallArgs) -> Result<u32> + 'a>;
+
+/// A registry of system call implementations by number.
+///
+/// System calls without an implementation fail with
+/// `Error::UnknownSyscall`.
+#[derive(Default)]
+pub struct SyscallRegistry<'a> {
+    syscalls: HashMap<i32, BoxedSyscall<'a>>,
+}
+
+impl<'a> SyscallRegistry<'a> {
+    /// Creates a new, empty registry.
+    pub fn new() -> SyscallRegistry<'a> {
+        SyscallRegistry { syscalls: HashMap::new() }
+    }
+
+    /// Registers an implementation with typed arguments and result.
+    ///
+    /// Any previous implementation for `number` is replaced.
+    pub fn register<Args, F>(&mut self, number: i32, mut f: F)
+        where F: SyscallFn<Args> + 'a
+    {
+        self.syscalls
+            .insert(number, Box::new(move |args: &mut SyscallArgs| f.invoke(args)));
+    }
+
+    /// Registers an implementation that decodes its arguments itself.
+    ///
+    /// Any previous implementation for `number` is replaced.
+    pub fn register_raw<F>(&mut self, number: i32, f: F)
+        where F: FnMut(&mut SyscallArgs) -> Result<u32> + 'a
+    {
+        self.syscalls.insert(number, Box::new(f));
+    }
+
+    /// Returns whether there is an implementation for `number`.
+    pub fn contains(&self, number: i32) -> bool {
+        self.syscalls.contains_key(&number)
+    }
+}
+
+impl<'a> SyscallHandler for SyscallRegistry<'a> {
+    fn syscall(&mut self, memory: &mut Memory, number: i32, args: &[u32]) -> Result<u32> {
+        match self.syscalls.get_mut(&number) {
+            Some(syscall) => syscall(&mut SyscallArgs::new(memory, args)),
+            None => Err(Error::UnknownSyscall(number)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{syscall_number, Pointer, SyscallHandler, SyscallRegistry};
+    use bytecode::Instruction;
+    use errors::Error;
+    use interpreter::{Interpreter, Memory};
+    use parser::parse_qvm;
+    use std::cell::RefCell;
+    use QVM;
+
+    fn memory() -> Memory {
+        let code = vec![Instruction::ENTER(8), Instruction::PUSH, Instruction::LEAVE(8)];
+        let qvm = QVM::new(code, vec![0, 0x6c6c6548, 0x0000006f], vec![], 0).unwrap();
+        Memory::new(&qvm)
+    }
+
+    #[test]
+    fn test_syscall_number() {
+        assert_eq!(syscall_number(-666), 665);
+        assert_eq!(syscall_number(-1), 0);
+    }
+
+    #[test]
+    fn test_registry_typed_arguments() {
+        let mut memory = memory();
+        let mut registry = SyscallRegistry::new();
+        registry.register(1, |a: i32, b: f32, s: String, p: Pointer| {
+            assert_eq!(a, -2);
+            assert_eq!(b, 0.5);
+            assert_eq!(s, "Hello");
+            assert_eq!(p, Pointer(4));
+            1.5f32
+        });
+        let args = [-2i32 as u32, 0.5f32.to_bits(), 4, 4];
+        let result = registry.syscall(&mut memory, 1, &args).unwrap();
+        assert_eq!(f32::from_bits(result), 1.5);
+    }
+
+    #[test]
+    fn test_registry_raw_writes_memory() {
+        let mut memory = memory();
+        let mut registry = SyscallRegistry::new();
+        registry.register_raw(2, |args| {
+                                  let Pointer(buffer) = args.pointer(0)?;
+                                  args.memory_mut().write_u8(buffer, b'J');
+                                  Ok(0)
+                              });
+        registry.syscall(&mut memory, 2, &[4]).unwrap();
+        assert_eq!(memory.read_cstring(4), b"Jello");
+    }
+
+    #[test]
+    fn test_registry_error_result() {
+        let mut registry = SyscallRegistry::new();
+        registry.register(3, || -> ::errors::Result<()> {
+            Err(Error::Syscall("failed".to_string()))
+        });
+        assert!(registry.syscall(&mut memory(), 3, &[]).is_err());
+    }
+
+    #[test]
+    fn test_registry_unknown_syscall() {
+        let mut registry = SyscallRegistry::new();
+        match registry.syscall(&mut memory(), 42, &[]) {
+            Err(Error::UnknownSyscall(42)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_registry_hello_world() {
+        let qvm = parse_qvm(include_bytes!("../assets/mod-syscall.qvm")).unwrap();
+        let output = RefCell::new(String::new());
+        let mut registry = SyscallRegistry::new();
+        registry.register(syscall_number(-666),
+                          |message: String| output.borrow_mut().push_str(&message));
+        let result = Interpreter::new(qvm).call(&[0], &mut registry).unwrap();
+        assert_eq!(result as i32, -1);
+        assert_eq!(*output.borrow(), "Hello, world!");
+    }
+}
@@ -0,0 +1,587 @@
+//! Type recovery for frame slots, globals and procedure signatures.
+//!
+//! QVM values are untyped 32-bit words, but the instructions using them
+//! reveal their intent: float arithmetic and `CVIF` produce floats, `LOAD1`
+//! and `SEX8` deal with chars, `LOAD2` and `SEX16` with shorts, and values
+//! that are dereferenced are pointers.
+//! Constant addresses that are loaded from or stored to are globals.
+//!
+//! Evidence for the type of each location is collected from the SSA form of
+//! all procedures. `int` is the type without evidence, and locations with
+//! conflicting evidence, e.g. for a pointer and a float, fall back to it for
+//! good. Types flow between procedures through arguments and return values,
+//! so this repeats until no type changes. The number of parameters and
+//! whether procedures return a value are taken from `signatures`.
+
+use super::{QVM, Segment};
+use bytecode::{Address, ArgOffset, FrameOffset, FrameSize, Literal};
+use cfg::Cfg;
+use disassembler::lit_string;
+use opcodes::Opcode;
+use signatures::{param_index, Signatures};
+use ssa::{Function, Op, ValueId};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// The type of a location.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Type {
+    /// A 32-bit integer, or any word without more specific uses.
+    Int,
+    /// A 16-bit integer, accessed by `LOAD2` and `STORE2`.
+    Short,
+    /// An 8-bit integer, accessed by `LOAD1` and `STORE1`.
+    Char,
+    /// An address that is dereferenced.
+    Pointer,
+    /// A 32-bit float.
+    Float,
+}
+
+impl Type {
+    /// Returns a C declaration of `name` with this type, e.g. `void *p`.
+    pub fn declare(self, name: &str) -> String {
+        match self {
+            Type::Pointer => format!("{}{}", self, name),
+            _ => format!("{} {}", self, name),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Type::Int => "int",
+            Type::Short => "short",
+            Type::Char => "char",
+            Type::Pointer => "void *",
+            Type::Float => "float",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A typed location, identifying procedures by the address of their `ENTER`
+/// instruction.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Location {
+    /// A slot of a procedure's stack frame, by its offset.
+    Local(Address, FrameOffset),
+    /// A parameter of a procedure, by its index.
+    Param(Address, usize),
+    /// The return value of a procedure.
+    Return(Address),
+    /// A global, by its address in the memory image.
+    Global(Address),
+}
+
+impl Location {
+    /// Returns the location of the frame slot at `offset` of a procedure,
+    /// which is a parameter if it is above the frame.
+    pub fn slot(procedure: Address, frame_size: FrameSize, offset: FrameOffset) -> Location {
+        match param_index(frame_size, offset) {
+            Some(param) => Location::Param(procedure, param),
+            None => Location::Local(procedure, offset),
+        }
+    }
+}
+
+/// Returns the type of the octets accessed by a load or store.
+fn access_type(size: u8) -> Type {
+    match size {
+        1 => Type::Char,
+        2 => Type::Short,
+        _ => Type::Int,
+    }
+}
+
+/// Returns the type of the result of an operation.
+fn result_type(opcode: Opcode) -> Type {
+    use opcodes::Opcode::*;
+    match opcode {
+        NEGF | ADDF | SUBF | DIVF | MULF | CVIF => Type::Float,
+        _ => Type::Int,
+    }
+}
+
+/// Returns whether a constant is the address of a string in the LIT segment.
+fn is_string(qvm: &QVM, literal: Literal) -> bool {
+    match qvm.segment_address(literal) {
+        Some((Segment::LIT, address)) => lit_string(qvm.lit(), address).is_some(),
+        _ => false,
+    }
+}
+
+/// Returns the location an address refers to, if it is a frame slot or a
+/// global.
+fn location(qvm: &QVM, function: &Function, address: ValueId) -> Option<Location> {
+    match function.values[address].op {
+        Op::Local(offset) => Some(Location::slot(function.start, function.frame_size, offset)),
+        Op::Const(address) if qvm.segment_address(address).is_some() => {
+            Some(Location::Global(address))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the type of a value from the types of the locations it depends on,
+/// if it is known.
+///
+/// The type of a phi node is the type its incoming values agree on.
+fn value_type(qvm: &QVM,
+              types: &BTreeMap<Location, Type>,
+              function: &Function,
+              value: ValueId)
+              -> Option<Type> {
+    match function.values[value].op {
+        Op::Phi(ref incoming) => {
+            let mut incoming = incoming
+                .iter()
+                .map(|&(_, value)| operation_type(qvm, types, function, value));
+            let first = incoming.next()??;
+            if incoming.all(|ty| ty == Some(first)) {
+                Some(first)
+            } else {
+                None
+            }
+        }
+        _ => operation_type(qvm, types, function, value),
+    }
+}
+
+/// Returns the type of a value like `value_type`, but without looking through
+/// phi nodes, which may depend on themselves.
+fn operation_type(qvm: &QVM,
+                  types: &BTreeMap<Location, Type>,
+                  function: &Function,
+                  value: ValueId)
+                  -> Option<Type> {
+    match function.values[value].op {
+        Op::Const(literal) if is_string(qvm, literal) => Some(Type::Pointer),
+        Op::Local(_) => Some(Type::Pointer),
+        Op::Load(4, address) => {
+            location(qvm, function, address).and_then(|location| types.get(&location).cloned())
+        }
+        Op::Load(size, _) => Some(access_type(size)),
+        Op::Binary(Opcode::ADD, a, b) => {
+            let is_pointer = |value| {
+                operation_type(qvm, types, function, value) == Some(Type::Pointer)
+            };
+            if is_pointer(a) || is_pointer(b) {
+                Some(Type::Pointer)
+            } else {
+                Some(Type::Int)
+            }
+        }
+        Op::Unary(opcode, _) | Op::Binary(opcode, ..) => Some(result_type(opcode)),
+        Op::Call(target) => {
+            match function.values[target].op {
+                Op::Const(address) if (address as i32) >= 0 => {
+                    types.get(&Location::Return(address)).cloned()
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The recovered types of a VM.
+#[derive(Debug, Clone, Default)]
+pub struct Types {
+    types: BTreeMap<Location, Type>,
+    signatures: Signatures,
+}
+
+impl Types {
+    /// Infers the signatures and types of all procedures of a VM.
+    ///
+    /// Procedures that can not be lifted, see `ssa::Function::lift`, are
+    /// skipped.
+    pub fn for_qvm(qvm: &QVM) -> Types {
+        let functions: Vec<Function> = Cfg::for_qvm(qvm)
+            .iter()
+            .filter_map(|cfg| Function::lift(cfg).ok())
+            .collect();
+        let mut inference = Inference {
+            qvm,
+            types: BTreeMap::new(),
+            conflicts: BTreeSet::new(),
+            changed: true,
+        };
+        while inference.changed {
+            inference.changed = false;
+            for function in &functions {
+                inference.function(function);
+            }
+        }
+        Types {
+            types: inference.types,
+            signatures: Signatures::for_qvm(qvm),
+        }
+    }
+
+    /// Returns the type of a location, or `None` if it is never accessed.
+    pub fn get(&self, location: Location) -> Option<Type> {
+        self.types.get(&location).cloned()
+    }
+
+    /// Returns the signatures of all procedures, see `signatures`.
+    pub fn signatures(&self) -> &Signatures {
+        &self.signatures
+    }
+
+    /// Returns the types of the parameters of the procedure at `procedure`,
+    /// with `int` for those without any evidence.
+    pub fn params(&self, procedure: Address) -> Vec<Type> {
+        let count = self.signatures.get(procedure).map_or(0, |signature| signature.params);
+        let mut types = vec![Type::Int; count];
+        let params = Location::Param(procedure, 0)..=Location::Param(procedure, usize::MAX);
+        for (&location, &ty) in self.types.range(params) {
+            if let Location::Param(_, index) = location {
+                if index >= types.len() {
+                    types.resize(index + 1, Type::Int);
+                }
+                types[index] = ty;
+            }
+        }
+        types
+    }
+
+    /// Returns the return type of the procedure at `procedure`, or `None` if
+    /// its signature says that it does not return a value.
+    pub fn returns(&self, procedure: Address) -> Option<Type> {
+        match self.signatures.get(procedure) {
+            Some(signature) if signature.returns => {
+                Some(self.get(Location::Return(procedure)).unwrap_or(Type::Int))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the type of a value of `function`, a procedure of `qvm`, if it
+    /// is known.
+    ///
+    /// The type of a phi node is the type its incoming values agree on.
+    pub fn value_type(&self, qvm: &QVM, function: &Function, value: ValueId) -> Option<Type> {
+        value_type(qvm, &self.types, function, value)
+    }
+
+    /// Returns all typed locations.
+    pub fn locations(&self) -> &BTreeMap<Location, Type> {
+        &self.types
+    }
+}
+
+struct Inference<'a> {
+    qvm: &'a QVM,
+    types: BTreeMap<Location, Type>,
+    /// Locations with conflicting evidence, which stay `int`.
+    conflicts: BTreeSet<Location>,
+    changed: bool,
+}
+
+impl<'a> Inference<'a> {
+    /// Records evidence that a location has type `ty`.
+    ///
+    /// `int` is no evidence for other types, while two different other types
+    /// are a conflict.
+    fn record(&mut self, location: Location, ty: Type) {
+        if self.conflicts.contains(&location) {
+            return;
+        }
+        let ty = match self.types.get(&location) {
+            Some(&current) if current == ty || ty == Type::Int => return,
+            None | Some(&Type::Int) => ty,
+            Some(_) => {
+                self.conflicts.insert(location);
+                Type::Int
+            }
+        };
+        self.types.insert(location, ty);
+        self.changed = true;
+    }
+
+    /// Returns the location an address refers to, if it is a frame slot or a
+    /// global.
+    fn location(&self, function: &Function, address: ValueId) -> Option<Location> {
+        location(self.qvm, function, address)
+    }
+
+    /// Returns the type of a value, if it is known.
+    fn value_type(&self, function: &Function, value: ValueId) -> Option<Type> {
+        value_type(self.qvm, &self.types, function, value)
+    }
+
+    /// Records that a value is used as `ty`, for the location it was loaded
+    /// from or the procedure that returned it.
+    fn expect(&mut self, function: &Function, value: ValueId, ty: Type) {
+        match function.values[value].op {
+            Op::Load(4, address) => {
+                if let Some(location) = self.location(function, address) {
+                    self.record(location, ty);
+                }
+            }
+            Op::Call(target) => {
+                if let Op::Const(address) = function.values[target].op {
+                    if (address as i32) >= 0 {
+                        self.record(Location::Return(address), ty);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Records that a computed address is dereferenced, i.e. the base of
+    /// `p + offset` or `p + (i << 2)` is a pointer.
+    fn dereference(&mut self, function: &Function, address: ValueId) {
+        match function.values[address].op {
+            Op::Binary(Opcode::ADD, a, b) => {
+                for operand in [a, b] {
+                    match function.values[operand].op {
+                        Op::Binary(Opcode::ADD, ..) => self.dereference(function, operand),
+                        Op::Load(4, _) | Op::Call(_) => {
+                            self.expect(function, operand, Type::Pointer)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => self.expect(function, address, Type::Pointer),
+        }
+    }
+
+    /// Records the arguments of a call to the procedure at `procedure`.
+    fn call(&mut self, function: &Function, procedure: Address, args: &[(ArgOffset, ValueId)]) {
+        for &(offset, value) in args {
+            // `ARG` offsets are the frame offsets of a callee with an empty
+            // frame
+            let param = match Location::slot(procedure, 0, offset as FrameOffset) {
+                param @ Location::Param(..) => param,
+                _ => continue,
+            };
+            let ty = self.value_type(function, value).unwrap_or(Type::Int);
+            self.record(param, ty);
+        }
+    }
+
+    /// Collects evidence from all values of a procedure.
+    fn function(&mut self, function: &Function) {
+        // ARG instructions preceding the next call
+        let mut args = vec![];
+        for block in &function.blocks {
+            for &value in &block.values {
+                match function.values[value].op {
+                    Op::Load(size, address) => {
+                        match self.location(function, address) {
+                            Some(location) => self.record(location, access_type(size)),
+                            None => self.dereference(function, address),
+                        }
+                    }
+                    Op::Store(size, address, stored) => {
+                        match self.location(function, address) {
+                            Some(location) => {
+                                let ty = match size {
+                                    4 => self.value_type(function, stored).unwrap_or(Type::Int),
+                                    _ => access_type(size),
+                                };
+                                self.record(location, ty);
+                            }
+                            None => self.dereference(function, address),
+                        }
+                    }
+                    Op::Unary(opcode, a) if opcode.has_float_operands() => {
+                        self.expect(function, a, Type::Float);
+                    }
+                    Op::Unary(Opcode::SEX8, a) => self.expect(function, a, Type::Char),
+                    Op::Unary(Opcode::SEX16, a) => self.expect(function, a, Type::Short),
+                    Op::Binary(opcode, a, b) |
+                    Op::Branch(opcode, a, b, _) if opcode.has_float_operands() => {
+                        self.expect(function, a, Type::Float);
+                        self.expect(function, b, Type::Float);
+                    }
+                    Op::Arg(offset, arg) => args.push((offset, arg)),
+                    Op::Call(target) => {
+                        if let Op::Const(address) = function.values[target].op {
+                            if (address as i32) >= 0 {
+                                self.call(function, address, &args);
+                            }
+                        }
+                        args.clear();
+                    }
+                    // Whether there is a return value at all is up to `signatures`
+                    Op::Return(returned) => {
+                        if let Some(ty) = self.value_type(function, returned) {
+                            self.record(Location::Return(function.start), ty);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Location, Type, Types};
+    use assembler::assemble;
+    use parser::{parse_qvm, parse_symbol_map};
+
+    #[test]
+    fn test_local_types() {
+        let qvm = assemble("test",
+                           "
+            vmMain:
+                    ENTER 40
+                    LOCAL 4
+                    LOCAL 8
+                    LOAD4
+                    CONST 1.0
+                    ADDF
+                    STORE4
+                    LOCAL 12
+                    LOCAL 16
+                    LOAD4
+                    LOAD1
+                    STORE1
+                    LOCAL 20
+                    LOCAL 24
+                    LOAD4
+                    CONST 1
+                    ADD
+                    STORE4
+                    LOCAL 28
+                    LOAD4
+                    SEX8
+                    POP
+                    LOCAL 32
+                    LOAD4
+                    SEX16
+                    POP
+                    PUSH
+                    LEAVE 40
+        ")
+                .unwrap();
+        let types = Types::for_qvm(&qvm);
+        let locals: Vec<(Location, Type)> = types
+            .locations()
+            .iter()
+            .map(|(&location, &ty)| (location, ty))
+            .collect();
+        assert_eq!(locals,
+                   vec![(Location::Local(0, 4), Type::Float),
+                        (Location::Local(0, 8), Type::Float),
+                        (Location::Local(0, 12), Type::Char),
+                        (Location::Local(0, 16), Type::Pointer),
+                        (Location::Local(0, 20), Type::Int),
+                        (Location::Local(0, 24), Type::Int),
+                        (Location::Local(0, 28), Type::Char),
+                        (Location::Local(0, 32), Type::Short)]);
+        assert_eq!(types.get(Location::Return(0)), None);
+    }
+
+    #[test]
+    fn test_signature_and_global_types() {
+        // `g = scale(&x, (float)y)` with `float scale(float *p, float f)`
+        let qvm = assemble("test",
+                           "
+            .data 0 0
+            vmMain:
+                    ENTER 16
+                    CONST 4
+                    LOCAL 4
+                    ARG 8
+                    LOCAL 12
+                    LOAD4
+                    CVIF
+                    ARG 12
+                    CONST scale
+                    CALL
+                    STORE4
+                    PUSH
+                    LEAVE 16
+            scale:
+                    ENTER 8
+                    LOCAL 20
+                    LOAD4
+                    LOCAL 16
+                    LOAD4
+                    LOAD4
+                    MULF
+                    LEAVE 8
+        ")
+                .unwrap();
+        let types = Types::for_qvm(&qvm);
+        assert_eq!(types.params(13), vec![Type::Pointer, Type::Float]);
+        assert_eq!(types.get(Location::Return(13)), Some(Type::Float));
+        assert_eq!(types.get(Location::Global(4)), Some(Type::Float));
+        assert_eq!(types.get(Location::Local(0, 12)), Some(Type::Int));
+        assert_eq!(types.params(0), vec![]);
+        assert_eq!(types.get(Location::Return(0)), None);
+        assert_eq!(types.returns(13), Some(Type::Float));
+        assert_eq!(types.returns(0), None);
+        assert_eq!(types.returns(1), None);
+    }
+
+    #[test]
+    fn test_conflicting_types() {
+        // `f(&x); f((float)i);` and a slot that is both dereferenced and a float
+        let qvm = assemble("test",
+                           "
+            vmMain:
+                    ENTER 16
+                    LOCAL 4
+                    ARG 8
+                    CONST f
+                    CALL
+                    POP
+                    LOCAL 12
+                    LOAD4
+                    CVIF
+                    ARG 8
+                    CONST f
+                    CALL
+                    POP
+                    LOCAL 8
+                    LOAD4
+                    LOAD4
+                    LOCAL 8
+                    LOAD4
+                    ADDF
+                    POP
+                    PUSH
+                    LEAVE 16
+            f:
+                    ENTER 8
+                    PUSH
+                    LEAVE 8
+        ")
+                .unwrap();
+        let types = Types::for_qvm(&qvm);
+        assert_eq!(types.params(22), vec![Type::Int]);
+        assert_eq!(types.get(Location::Local(0, 8)), Some(Type::Int));
+    }
+
+    #[test]
+    fn test_declare() {
+        assert_eq!(Type::Float.declare("x"), "float x");
+        assert_eq!(Type::Pointer.declare("p"), "void *p");
+    }
+
+    #[test]
+    fn test_types_ioq3_qagame() {
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        let symbols = parse_symbol_map(include_bytes!("../assets/ioq3/baseq3/vm/qagame.map"))
+            .unwrap();
+        let address = |name| symbols.resolve(name).unwrap().1;
+        let (normalize, strlen) = (address("VectorNormalize"), address("strlen"));
+        let types = Types::for_qvm(&qvm);
+        assert_eq!(types.params(normalize), vec![Type::Pointer]);
+        assert_eq!(types.get(Location::Return(normalize)), Some(Type::Float));
+        assert_eq!(types.params(strlen), vec![Type::Pointer]);
+        assert_eq!(types.get(Location::Return(strlen)), Some(Type::Int));
+    }
+}
@@ -0,0 +1,318 @@
+//! Semantic validation of QVMs.
+//!
+//! The file format itself does not prevent nonsensical VMs, e.g. branches to
+//! instructions that do not exist. These checks catch the most common issues
+//! of corrupt or malicious VMs before they are executed.
+
+use super::{Instruction, QVM};
+use bytecode::{Address, BlockSize, FrameSize, Literal};
+use std::fmt;
+
+/// A violation of the semantic rules for a QVM.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Violation {
+    /// The code does not start with an `ENTER` instruction.
+    ///
+    /// Execution always starts at instruction 0, i.e. `vmMain`.
+    NoProcedure,
+    /// A conditional branch targets an instruction that does not exist.
+    BranchOutOfBounds {
+        /// Index of the branch instruction.
+        index: usize,
+        /// The invalid target.
+        target: Address,
+    },
+    /// A `CONST` that is `CALL`ed or `JUMP`ed to targets an instruction that
+    /// does not exist.
+    ///
+    /// Negative `CALL` targets are system calls and always valid.
+    CallOutOfBounds {
+        /// Index of the `CONST` instruction.
+        index: usize,
+        /// The invalid target.
+        target: Address,
+    },
+    /// A `LEAVE` does not use the frame size of its procedure's `ENTER`.
+    FrameSizeMismatch {
+        /// Index of the `LEAVE` instruction.
+        index: usize,
+        /// Index of the procedure's `ENTER` instruction.
+        procedure: usize,
+        /// The frame size of `ENTER`.
+        expected: FrameSize,
+        /// The frame size of `LEAVE`.
+        actual: FrameSize,
+    },
+    /// A `BLOCK_COPY` is larger than the memory image.
+    BlockCopyOutOfBounds {
+        /// Index of the `BLOCK_COPY` instruction.
+        index: usize,
+        /// The invalid size.
+        size: BlockSize,
+    },
+    /// A `CONST` that is loaded from, stored to or block copied lies outside
+    /// of the memory image.
+    ///
+    /// Only addresses that are used within the same straight-line code as
+    /// their `CONST` are checked.
+    DataAddressOutOfBounds {
+        /// Index of the `CONST` instruction.
+        index: usize,
+        /// The invalid address.
+        address: Literal,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::NoProcedure => write!(f, "code does not start with a procedure"),
+            Violation::BranchOutOfBounds { index, target } => {
+                write!(f, "instruction {}: branch target {} out of bounds", index, target)
+            }
+            Violation::CallOutOfBounds { index, target } => {
+                write!(f, "instruction {}: call target {} out of bounds", index, target)
+            }
+            Violation::FrameSizeMismatch {
+                index,
+                procedure,
+                expected,
+                actual,
+            } => {
+                write!(f,
+                       "instruction {}: frame size {} does not match {} of procedure at {}",
+                       index,
+                       actual,
+                       expected,
+                       procedure)
+            }
+            Violation::BlockCopyOutOfBounds { index, size } => {
+                write!(f, "instruction {}: block copy size {} out of bounds", index, size)
+            }
+            Violation::DataAddressOutOfBounds { index, address } => {
+                write!(f, "instruction {}: data address {} out of bounds", index, address)
+            }
+        }
+    }
+}
+
+/// Returns the positions of the address operands of an instruction, counted
+/// from the top of the operand stack, and the number of octets it accesses.
+fn address_operands(instruction: &Instruction) -> Option<(&'static [usize], u64)> {
+    match *instruction {
+        Instruction::LOAD1 => Some((&[0], 1)),
+        Instruction::LOAD2 => Some((&[0], 2)),
+        Instruction::LOAD4 => Some((&[0], 4)),
+        Instruction::STORE1 => Some((&[1], 1)),
+        Instruction::STORE2 => Some((&[1], 2)),
+        Instruction::STORE4 => Some((&[1], 4)),
+        Instruction::BLOCK_COPY(size) => Some((&[0, 1], size as u64)),
+        _ => None,
+    }
+}
+
+/// Validates the instructions of a VM against its memory image size.
+pub fn validate_code(code: &[Instruction], memory_size: u64) -> Vec<Violation> {
+    let mut violations = vec![];
+    let code_length = code.len() as u64;
+
+    match code.first() {
+        Some(&Instruction::ENTER(_)) => {}
+        _ => violations.push(Violation::NoProcedure),
+    }
+
+    let mut procedure = None;
+    // The operand stack since the last terminator, with the index and value
+    // of the `CONST`s on it
+    let mut stack: Vec<Option<(usize, Literal)>> = vec![];
+    for (index, instruction) in code.iter().enumerate() {
+        if let Some(target) = instruction.branch_target() {
+            if target as u64 >= code_length {
+                violations.push(Violation::BranchOutOfBounds { index, target });
+            }
+        }
+
+        match *instruction {
+            Instruction::ENTER(frame_size) => procedure = Some((index, frame_size)),
+            Instruction::LEAVE(actual) => {
+                if let Some((procedure, expected)) = procedure {
+                    if actual != expected {
+                        violations.push(Violation::FrameSizeMismatch {
+                                            index,
+                                            procedure,
+                                            expected,
+                                            actual,
+                                        });
+                    }
+                }
+            }
+            Instruction::BLOCK_COPY(size) if size as u64 > memory_size => {
+                violations.push(Violation::BlockCopyOutOfBounds { index, size });
+            }
+            Instruction::CONST(value) => {
+                match code.get(index + 1) {
+                    Some(&Instruction::CALL) if (value as i32) < 0 => {}
+                    Some(&Instruction::CALL) |
+                    Some(&Instruction::JUMP) if value as u64 >= code_length => {
+                        violations.push(Violation::CallOutOfBounds {
+                                            index,
+                                            target: value,
+                                        });
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+
+        if let Some((operands, size)) = address_operands(instruction) {
+            for &operand in operands {
+                let constant = stack.len().checked_sub(operand + 1).and_then(|i| stack[i]);
+                if let Some((index, address)) = constant {
+                    if address as u64 + size > memory_size {
+                        violations.push(Violation::DataAddressOutOfBounds { index, address });
+                    }
+                }
+            }
+        }
+        match *instruction {
+            Instruction::CONST(value) => stack.push(Some((index, value))),
+            Instruction::ENTER(_) => stack.clear(),
+            _ if instruction.opcode().is_terminator() => stack.clear(),
+            _ => {
+                let effect = instruction.stack_effect();
+                let depth = stack.len().saturating_sub(effect.pops as usize);
+                stack.truncate(depth);
+                stack.extend((0..effect.pushes).map(|_| None));
+            }
+        }
+    }
+
+    violations
+}
+
+/// Validates a VM, returning all violations.
+pub fn validate(qvm: &QVM) -> Vec<Violation> {
+    validate_code(qvm.instructions(), qvm.memory_size())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, validate_code, Violation};
+    use bytecode::Instruction;
+    use parser::parse_qvm;
+
+    #[test]
+    fn test_validate_file_syscall() {
+        let qvm = parse_qvm(include_bytes!("../assets/mod-syscall.qvm")).unwrap();
+        assert_eq!(validate(&qvm), vec![]);
+    }
+
+    #[test]
+    fn test_validate_ioq3_qagame() {
+        let qvm = parse_qvm(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm")).unwrap();
+        assert_eq!(validate(&qvm), vec![]);
+    }
+
+    #[test]
+    fn test_validate_no_procedure() {
+        assert_eq!(validate_code(&[], 4), vec![Violation::NoProcedure]);
+        assert_eq!(validate_code(&[Instruction::PUSH], 4),
+                   vec![Violation::NoProcedure]);
+    }
+
+    #[test]
+    fn test_validate_branch_out_of_bounds() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::CONST(0),
+                    Instruction::CONST(0),
+                    Instruction::EQ(5),
+                    Instruction::LEAVE(8)];
+        assert_eq!(validate_code(&code, 4),
+                   vec![Violation::BranchOutOfBounds {
+                            index: 3,
+                            target: 5,
+                        }]);
+    }
+
+    #[test]
+    fn test_validate_call_out_of_bounds() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::const_i32(-666),
+                    Instruction::CALL,
+                    Instruction::CONST(3),
+                    Instruction::CALL,
+                    Instruction::CONST(8),
+                    Instruction::JUMP,
+                    Instruction::LEAVE(8)];
+        assert_eq!(validate_code(&code, 4),
+                   vec![Violation::CallOutOfBounds {
+                            index: 5,
+                            target: 8,
+                        }]);
+    }
+
+    #[test]
+    fn test_validate_frame_size_mismatch() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::LEAVE(8),
+                    Instruction::ENTER(12),
+                    Instruction::LEAVE(8)];
+        assert_eq!(validate_code(&code, 4),
+                   vec![Violation::FrameSizeMismatch {
+                            index: 3,
+                            procedure: 2,
+                            expected: 12,
+                            actual: 8,
+                        }]);
+    }
+
+    #[test]
+    fn test_validate_memory_bounds() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::CONST(0x10),
+                    Instruction::CONST(0xfc),
+                    Instruction::LOAD4,
+                    Instruction::CONST(0xfd),
+                    Instruction::LOAD4,
+                    Instruction::BLOCK_COPY(0x200),
+                    Instruction::LEAVE(8)];
+        assert_eq!(validate_code(&code, 0x100),
+                   vec![Violation::DataAddressOutOfBounds {
+                            index: 4,
+                            address: 0xfd,
+                        },
+                        Violation::BlockCopyOutOfBounds {
+                            index: 6,
+                            size: 0x200,
+                        }]);
+    }
+    #[test]
+    fn test_validate_store_and_block_copy_addresses() {
+        let code = [Instruction::ENTER(8),
+                    Instruction::CONST(0xfe),
+                    Instruction::CONST(1),
+                    Instruction::STORE2,
+                    Instruction::CONST(0x100),
+                    Instruction::LOCAL(0),
+                    Instruction::LOAD4,
+                    Instruction::STORE4,
+                    Instruction::CONST(0xf0),
+                    Instruction::CONST(0xf8),
+                    Instruction::BLOCK_COPY(0x10),
+                    Instruction::CONST(0x10),
+                    Instruction::CONST(0x20),
+                    Instruction::BLOCK_COPY(0x10),
+                    Instruction::LEAVE(8)];
+        assert_eq!(validate_code(&code, 0x100),
+                   vec![Violation::DataAddressOutOfBounds {
+                            index: 4,
+                            address: 0x100,
+                        },
+                        Violation::DataAddressOutOfBounds {
+                            index: 9,
+                            address: 0xf8,
+                        }]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Writers for the QVM file format.
+
+use super::{QVM, Segment, Version, VM_MAGIC, VM_MAGIC_VER2, HEADER_LENGTH_V1, HEADER_LENGTH_V2};
+use bytecode::Instruction;
+use symbols::SymbolMap;
+use super::errors::*;
+use std::io::Write;
+
+/// q3asm aligns all segments to this many bytes.
+const SEGMENT_ALIGNMENT: usize = 4;
+
+/// Pads `bytes` with zeros to the segment alignment.
+fn align(bytes: &mut Vec<u8>) {
+    while bytes.len() % SEGMENT_ALIGNMENT != 0 {
+        bytes.push(0);
+    }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.push(value as u8);
+    bytes.push((value >> 8) as u8);
+    bytes.push((value >> 16) as u8);
+    bytes.push((value >> 24) as u8);
+}
+
+/// Appends the encoded form of an instruction to `bytes`.
+pub fn encode_instruction(instruction: &Instruction, bytes: &mut Vec<u8>) {
+    bytes.push(instruction.opcode() as u8);
+    if let Some(operand) = instruction.operand() {
+        match instruction.opcode().operand().size() {
+            1 => bytes.push(operand as u8),
+            _ => push_u32(bytes, operand),
+        }
+    }
+}
+
+/// Serializes a QVM into its binary file format.
+///
+/// The layout matches the one of ioquake3's q3asm, i.e. all segments are
+/// aligned to 4 bytes and there is no padding between segments.
+/// A `Version::V2` VM is written with the `VM_MAGIC_VER2` header and its
+/// JTRG segment.
+pub fn write_qvm<W: Write>(qvm: &QVM, writer: &mut W) -> Result<()> {
+    let mut code = vec![];
+    for instruction in qvm.instructions() {
+        encode_instruction(instruction, &mut code);
+    }
+    align(&mut code);
+
+    let mut data = vec![];
+    for word in qvm.data() {
+        push_u32(&mut data, *word);
+    }
+
+    let mut lit = qvm.lit().clone();
+    align(&mut lit);
+
+    let mut jtrg = vec![];
+    if let Some(targets) = qvm.jump_targets() {
+        for target in targets {
+            push_u32(&mut jtrg, *target);
+        }
+    }
+
+    let (magic, header_length) = match qvm.version() {
+        Version::V1 => (VM_MAGIC, HEADER_LENGTH_V1),
+        Version::V2 => (VM_MAGIC_VER2, HEADER_LENGTH_V2),
+    };
+    let code_offset = header_length;
+    let data_offset = code_offset + code.len() as u32;
+
+    let mut header = Vec::with_capacity(header_length as usize);
+    header.extend_from_slice(&magic);
+    push_u32(&mut header, qvm.instructions().len() as u32);
+    push_u32(&mut header, code_offset);
+    push_u32(&mut header, code.len() as u32);
+    push_u32(&mut header, data_offset);
+    push_u32(&mut header, data.len() as u32);
+    push_u32(&mut header, lit.len() as u32);
+    push_u32(&mut header, qvm.bss_length());
+    if qvm.version() == Version::V2 {
+        push_u32(&mut header, jtrg.len() as u32);
+    }
+
+    writer.write_all(&header)?;
+    writer.write_all(&code)?;
+    writer.write_all(&data)?;
+    writer.write_all(&lit)?;
+    writer.write_all(&jtrg)?;
+    Ok(())
+}
+
+
+/// Serializes a symbol map into q3asm's `.map` format.
+///
+/// Symbols are grouped by segment, but otherwise kept in order.
+/// Symbols of the JTRG segment are omitted, just like q3asm does.
+pub fn write_symbol_map<W: Write>(symbols: &SymbolMap, writer: &mut W) -> Result<()> {
+    for segment in &[Segment::CODE, Segment::DATA, Segment::LIT, Segment::BSS] {
+        for symbol in symbols.symbols().iter().filter(|s| s.segment() == *segment) {
+            writeln!(writer,
+                     "{} {:8x} {}",
+                     *segment as u8,
+                     symbol.address(),
+                     symbol.name())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_instruction, write_qvm, write_symbol_map};
+    use bytecode::Instruction;
+    use parser::{parse_qvm, parse_symbol_map};
+    use QVM;
+
+    fn assert_round_trip(data: &[u8]) {
+        let qvm = parse_qvm(data).unwrap();
+        let mut result = vec![];
+        write_qvm(&qvm, &mut result).unwrap();
+        assert_eq!(result, data);
+    }
+
+    #[test]
+    fn test_encode_instruction_enter() {
+        let mut result = vec![];
+        encode_instruction(&Instruction::ENTER(0x42), &mut result);
+        assert_eq!(result, vec![0x3, 0x42, 0x0, 0x0, 0x0]);
+    }
+
+    #[test]
+    fn test_encode_instruction_arg() {
+        let mut result = vec![];
+        encode_instruction(&Instruction::ARG(0x42), &mut result);
+        assert_eq!(result, vec![0x21, 0x42]);
+    }
+
+    #[test]
+    fn test_write_qvm_pads_lit() {
+        let code = vec![Instruction::ENTER(8), Instruction::PUSH, Instruction::LEAVE(8)];
+        let qvm = QVM::new(code, vec![], vec![b'!'], 0).unwrap();
+        let mut result = vec![];
+        write_qvm(&qvm, &mut result).unwrap();
+        let expected = parse_qvm(&result).unwrap();
+        assert_eq!(expected.lit(), &vec![b'!', 0, 0, 0]);
+        assert_eq!(result.len(), 32 + 12 + 4);
+    }
+
+    #[test]
+    fn test_write_qvm_v2() {
+        let qvm = QVM::new_v2(vec![Instruction::ENTER(8), Instruction::PUSH, Instruction::LEAVE(8)],
+                              vec![0],
+                              vec![],
+                              0,
+                              vec![0, 1])
+                .unwrap();
+        let mut result = vec![];
+        write_qvm(&qvm, &mut result).unwrap();
+        assert_eq!(parse_qvm(&result).unwrap(), qvm);
+        assert_round_trip(&result);
+    }
+
+    #[test]
+    fn test_write_qvm_file_minimal() {
+        assert_round_trip(include_bytes!("../assets/mod-minimal.qvm"));
+    }
+
+    #[test]
+    fn test_write_qvm_file_bss() {
+        assert_round_trip(include_bytes!("../assets/mod-bss.qvm"));
+    }
+
+    #[test]
+    fn test_write_qvm_file_data() {
+        assert_round_trip(include_bytes!("../assets/mod-data.qvm"));
+    }
+
+    #[test]
+    fn test_write_qvm_file_lit() {
+        assert_round_trip(include_bytes!("../assets/mod-lit.qvm"));
+    }
+
+    #[test]
+    fn test_write_qvm_file_syscall() {
+        assert_round_trip(include_bytes!("../assets/mod-syscall.qvm"));
+    }
+
+    #[test]
+    fn test_write_qvm_ioq3_qagame() {
+        assert_round_trip(include_bytes!("../assets/ioq3/baseq3/vm/qagame.qvm"));
+    }
+
+    #[test]
+    fn test_write_symbol_map_ioq3_qagame() {
+        let data = include_bytes!("../assets/ioq3/baseq3/vm/qagame.map");
+        let symbols = parse_symbol_map(data).unwrap();
+        let mut result = vec![];
+        write_symbol_map(&symbols, &mut result).unwrap();
+        assert_eq!(&result[..], &data[..]);
+    }
+}